@@ -1,12 +1,21 @@
 // 10.0: account and collateral management. isolated margin means each position has its own collateral.
-// 10.1 has deposit/withdraw/fee logic. withdrawals blocked with open positions.
-
+// 10.1 has deposit/withdraw/fee logic. withdrawals limited to free collateral, see 10.2.
+// portfolio accounts (3.5) hold margin at the account level instead, see MarginMode.
+// balance is the quote asset everything settles in. other collateral sits in collateral_assets
+// and counts toward margin at oracle price x haircut weight, see 10.3.
+// a master account can own numbered subaccounts, each with its own positions and balance (10.5).
+// resting orders hold margin out of free collateral until they fill or cancel (10.6).
+// traded volume is kept per day over a rolling window for fee tiers (10.7).
+
+use crate::adl::LossHaircut;
+use crate::config::FeeOverride;
+use crate::custody::CollateralType;
 use crate::margin::{calculate_margin_requirement, MarginParams};
 use crate::position::Position;
-use crate::types::{AccountId, MarketId, Price, Quote, Timestamp};
-use rust_decimal::Decimal;
+use crate::types::{AccountId, Leverage, MarketId, Price, Quote, Timestamp};
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
@@ -18,8 +27,27 @@ pub struct Account {
     pub realized_pnl: Quote,
     pub created_at: Timestamp,
     pub referrer: Option<AccountId>,        // earns a cut of this account's fees
-    pub trading_volume_30d: Decimal,         // for fee tier calculation
+    pub trading_volume_30d: Decimal,         // for fee tier calculation, as of the last fill
+    pub volume_history: VecDeque<(i64, Decimal)>, // (day, notional) inside the fee window
+    pub fee_override: Option<FeeOverride>,   // VIP schedule set by an admin
     pub total_fees_paid: Quote,
+    pub leverage_settings: HashMap<MarketId, Leverage>, // user-selected, falls back to market max
+    pub margin_mode: MarginMode,
+    pub collateral_assets: BTreeMap<CollateralType, Decimal>, // non quote, in asset units
+    pub master: Option<AccountId>, // set on subaccounts
+    pub subaccount_number: u32,    // 0 for masters, 1.. for subaccounts
+    pub subaccounts: Vec<AccountId>,
+    pub order_margin: HashMap<MarketId, Quote>, // held for resting orders, see 3.6
+    pub loss_haircuts: Vec<LossHaircut>, // profit taken to cover others' bad debt, see 6.2.3
+}
+
+// isolated: each position reserves its own IM. portfolio: nothing reserved per position,
+// the whole book is margined by scenario analysis against account equity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MarginMode {
+    #[default]
+    Isolated,
+    Portfolio,
 }
 
 impl Account {
@@ -34,22 +62,40 @@ impl Account {
             created_at: timestamp,
             referrer: None,
             trading_volume_30d: Decimal::ZERO,
+            volume_history: VecDeque::new(),
+            fee_override: None,
             total_fees_paid: Quote::zero(),
+            leverage_settings: HashMap::new(),
+            margin_mode: MarginMode::default(),
+            collateral_assets: BTreeMap::new(),
+            master: None,
+            subaccount_number: 0,
+            subaccounts: Vec::new(),
+            order_margin: HashMap::new(),
+            loss_haircuts: Vec::new(),
+        }
+    }
+
+    pub fn new_subaccount(id: AccountId, master: AccountId, number: u32, timestamp: Timestamp) -> Self {
+        Self {
+            master: Some(master),
+            subaccount_number: number,
+            ..Self::new(id, timestamp)
         }
     }
 
+    // master for subaccounts, self otherwise
+    pub fn root(&self) -> AccountId {
+        self.master.unwrap_or(self.id)
+    }
+
     pub fn deposit(&mut self, amount: Quote) {
         self.balance = self.balance.add(amount);
         self.total_deposited = self.total_deposited.add(amount);
     }
 
-    // 10.1: withdraw. blocked if positions are open
+    // 10.1: withdraw from balance. caller checks free collateral first (see available_balance)
     pub fn withdraw(&mut self, amount: Quote) -> Result<(), AccountError> {
-        if self.has_open_positions() {
-            return Err(AccountError::WithdrawalLocked {
-                reason: "close all positions before withdrawing".to_string(),
-            });
-        }
         if amount.value() > self.balance.value() {
             return Err(AccountError::InsufficientBalance {
                 requested: amount,
@@ -61,22 +107,155 @@ impl Account {
         Ok(())
     }
 
-    pub fn has_open_positions(&self) -> bool {
-        !self.positions.is_empty()
+    pub fn asset_balance(&self, asset: CollateralType) -> Decimal {
+        match asset {
+            CollateralType::Usd => self.balance.value(),
+            _ => self.collateral_assets.get(&asset).copied().unwrap_or(Decimal::ZERO),
+        }
     }
 
-    // zero if positions open, must close everything to withdraw
-    pub fn available_balance(&self) -> Quote {
-        if self.has_open_positions() {
-            Quote::zero()
+    // USD is the quote asset and lands in balance
+    pub fn deposit_asset(&mut self, asset: CollateralType, amount: Decimal) {
+        match asset {
+            CollateralType::Usd => self.deposit(Quote::new(amount)),
+            _ => *self.collateral_assets.entry(asset).or_insert(Decimal::ZERO) += amount,
+        }
+    }
+
+    pub fn withdraw_asset(&mut self, asset: CollateralType, amount: Decimal) -> Result<(), AccountError> {
+        if asset == CollateralType::Usd {
+            return self.withdraw(Quote::new(amount));
+        }
+        let available = self.asset_balance(asset);
+        if amount > available {
+            return Err(AccountError::InsufficientAsset {
+                asset,
+                requested: amount,
+                available,
+            });
+        }
+        self.set_asset_balance(asset, available - amount);
+        Ok(())
+    }
+
+    fn set_asset_balance(&mut self, asset: CollateralType, amount: Decimal) {
+        if amount.is_zero() {
+            self.collateral_assets.remove(&asset);
         } else {
-            self.balance
+            self.collateral_assets.insert(asset, amount);
         }
     }
 
-    // free collateral for new positions
+    // 10.3: margin value of non quote collateral: sum of amount x oracle price x weight.
+    // assets the valuation can't price count for nothing
+    pub fn collateral_value<F>(&self, valuation: F) -> Quote
+    where
+        F: Fn(CollateralType) -> Option<(Price, Decimal)>,
+    {
+        let value = self
+            .collateral_assets
+            .iter()
+            .filter_map(|(asset, amount)| {
+                valuation(*asset).map(|(price, weight)| amount * price.value() * weight)
+            })
+            .sum();
+        Quote::new(value)
+    }
+
+    // 10.4: sell non quote collateral until balance reaches target. proceeds carry the same
+    // haircut as margin (oracle price x weight) so conversion never yields more than the
+    // collateral was counted for. stables go first (CollateralType order), assets without a
+    // price or weight are left alone. stops early if everything is sold
+    pub fn convert_to_quote<F>(&mut self, target: Quote, valuation: F) -> Vec<CollateralConversion>
+    where
+        F: Fn(CollateralType) -> Option<(Price, Decimal)>,
+    {
+        let mut conversions = Vec::new();
+        let assets: Vec<(CollateralType, Decimal)> =
+            self.collateral_assets.iter().map(|(a, amt)| (*a, *amt)).collect();
+
+        for (asset, held) in assets {
+            let shortfall = target.value() - self.balance.value();
+            if shortfall <= Decimal::ZERO {
+                break;
+            }
+            let Some((price, weight)) = valuation(asset).filter(|(_, weight)| *weight > Decimal::ZERO) else {
+                continue;
+            };
+
+            // round up at the asset's precision so the proceeds never fall short
+            let amount = (shortfall / (price.value() * weight))
+                .round_dp_with_strategy(asset.decimals(), RoundingStrategy::AwayFromZero)
+                .min(held);
+            let proceeds = Quote::new(amount * price.value() * weight);
+            self.set_asset_balance(asset, held - amount);
+            self.balance = self.balance.add(proceeds);
+
+            conversions.push(CollateralConversion {
+                asset,
+                amount,
+                price,
+                proceeds,
+            });
+        }
+
+        conversions
+    }
+
+    // negative quote balance. counts against equity until repaid
+    pub fn debt(&self) -> Quote {
+        Quote::new((-self.balance.value()).max(Decimal::ZERO))
+    }
+
+    // 10.4: pull isolated position collateral into balance to cover debt, lowest market id
+    // first so the order is deterministic. returns the amount taken
+    pub fn cover_debt_from_positions(&mut self) -> Quote {
+        let mut market_ids: Vec<MarketId> = self.positions.keys().copied().collect();
+        market_ids.sort_by_key(|id| id.0);
+
+        let mut taken = Quote::zero();
+        for market_id in market_ids {
+            let debt = self.debt();
+            if debt.value().is_zero() {
+                break;
+            }
+            let position = self.positions.get_mut(&market_id).unwrap();
+            let amount = Quote::new(debt.value().min(position.collateral.value()));
+            position.collateral = position.collateral.sub(amount);
+            self.balance = self.balance.add(amount);
+            taken = taken.add(amount);
+        }
+        taken
+    }
+
+    pub fn has_open_positions(&self) -> bool {
+        !self.positions.is_empty()
+    }
+
+    // 10.2: withdrawable amount. free margin (equity - IM - order margin) less a buffer for
+    // funding about to be owed, never more than the free collateral itself
+    pub fn available_balance(&self, metrics: &AccountMetrics, funding_buffer: Quote) -> Quote {
+        let free = metrics.free_margin.value() - funding_buffer.value();
+        Quote::new(free.min(self.free_collateral().value()).max(Decimal::ZERO))
+    }
+
+    // free collateral for new positions: balance not held by resting orders
     pub fn free_collateral(&self) -> Quote {
-        self.balance
+        self.balance.sub(self.reserved_order_margin())
+    }
+
+    // 10.6: margin held across all markets for resting orders
+    pub fn reserved_order_margin(&self) -> Quote {
+        self.order_margin.values().copied().sum()
+    }
+
+    // replace the hold for one market. zero drops the entry
+    pub fn set_order_margin(&mut self, market_id: MarketId, amount: Quote) {
+        if amount.value().is_zero() {
+            self.order_margin.remove(&market_id);
+        } else {
+            self.order_margin.insert(market_id, amount);
+        }
     }
 
     pub fn deduct_fee(&mut self, fee: Quote) {
@@ -84,10 +263,60 @@ impl Account {
         self.total_fees_paid = self.total_fees_paid.add(fee);
     }
 
+    // 10.7: traded notional inside the window ending at `now`. bucketed by day, so a
+    // day drops out whole once it is entirely older than the window
+    pub fn volume_in_window(&self, now: Timestamp, window_ms: i64) -> Decimal {
+        let first_day = Self::volume_day(now.as_millis() - window_ms);
+        self.volume_history
+            .iter()
+            .filter(|(day, _)| *day > first_day)
+            .map(|(_, volume)| *volume)
+            .sum()
+    }
+
+    pub fn record_volume(&mut self, now: Timestamp, notional: Decimal, window_ms: i64) {
+        let day = Self::volume_day(now.as_millis());
+        match self.volume_history.back_mut() {
+            Some((last, volume)) if *last == day => *volume += notional,
+            _ => self.volume_history.push_back((day, notional)),
+        }
+
+        let first_day = Self::volume_day(now.as_millis() - window_ms);
+        while self.volume_history.front().is_some_and(|(d, _)| *d <= first_day) {
+            self.volume_history.pop_front();
+        }
+        self.trading_volume_30d = self.volume_in_window(now, window_ms);
+    }
+
+    fn volume_day(millis: i64) -> i64 {
+        millis.div_euclid(24 * 60 * 60 * 1000)
+    }
+
     pub fn set_referrer(&mut self, referrer_id: AccountId) {
         self.referrer = Some(referrer_id);
     }
 
+    // leverage used to size initial margin on new fills in this market
+    pub fn leverage_for(&self, market_id: MarketId, default: Leverage) -> Leverage {
+        self.leverage_settings.get(&market_id).copied().unwrap_or(default)
+    }
+
+    pub fn set_leverage(&mut self, market_id: MarketId, leverage: Leverage) {
+        self.leverage_settings.insert(market_id, leverage);
+    }
+
+    pub fn is_portfolio_margin(&self) -> bool {
+        self.margin_mode == MarginMode::Portfolio
+    }
+
+    // collateral a fill moves into the position. portfolio accounts keep it in balance
+    pub fn collateral_to_reserve(&self, initial_margin: Quote) -> Quote {
+        match self.margin_mode {
+            MarginMode::Isolated => initial_margin,
+            MarginMode::Portfolio => Quote::zero(),
+        }
+    }
+
     pub fn get_position(&self, market_id: MarketId) -> Option<&Position> {
         self.positions.get(&market_id)
     }
@@ -113,11 +342,13 @@ impl Account {
         self.balance = self.balance.add(amount);
     }
 
+    // 10.6: margin held for resting orders can't back a new position
     pub fn reserve_collateral(&mut self, amount: Quote) -> Result<(), AccountError> {
-        if amount.value() > self.balance.value() {
-            return Err(AccountError::InsufficientBalance {
+        let available = self.free_collateral();
+        if amount.value() > available.value() {
+            return Err(AccountError::InsufficientFreeCollateral {
                 requested: amount,
-                available: self.balance,
+                available: Quote::new(available.value().max(Decimal::ZERO)),
             });
         }
         self.balance = self.balance.sub(amount);
@@ -125,6 +356,15 @@ impl Account {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct CollateralConversion {
+    pub asset: CollateralType,
+    pub amount: Decimal,
+    pub price: Price,
+    pub proceeds: Quote,
+}
+
+#[derive(Debug, Clone)]
 pub struct AccountMetrics {
     pub total_equity: Quote,
     pub unrealized_pnl: Quote,
@@ -139,24 +379,37 @@ pub fn calculate_account_metrics(
     market_prices: &HashMap<MarketId, (Price, Decimal)>,
     margin_params: &MarginParams,
 ) -> AccountMetrics {
+    calculate_account_metrics_with(account, |market_id| {
+        market_prices
+            .get(&market_id)
+            .map(|(mark_price, funding_index)| (*mark_price, *funding_index, margin_params))
+    })
+}
+
+// same as above, but mark price, funding index and margin params are looked up per market.
+// positions whose market returns None are left out.
+pub fn calculate_account_metrics_with<'a, F>(account: &Account, market_lookup: F) -> AccountMetrics
+where
+    F: Fn(MarketId) -> Option<(Price, Decimal, &'a MarginParams)>,
+{
     let mut unrealized_pnl = Quote::zero();
     let mut pending_funding = Quote::zero();
     let mut margin_used = Quote::zero();
     let mut total_notional = Quote::zero();
 
     for (market_id, position) in &account.positions {
-        if let Some((mark_price, funding_index)) = market_prices.get(market_id) {
-            let pnl = position.unrealized_pnl(*mark_price);
+        if let Some((mark_price, funding_index, margin_params)) = market_lookup(*market_id) {
+            let pnl = position.unrealized_pnl(mark_price);
             unrealized_pnl = unrealized_pnl.add(pnl);
 
-            let funding = position.pending_funding(*funding_index);
+            let funding = position.pending_funding(funding_index);
             pending_funding = pending_funding.add(funding);
 
-            let notional = position.notional_value(*mark_price);
+            let notional = position.notional_value(mark_price);
             total_notional = total_notional.add(notional);
 
             let margin_req =
-                calculate_margin_requirement(position.size, *mark_price, position.leverage, margin_params);
+                calculate_margin_requirement(position.size, mark_price, position.leverage, margin_params);
             margin_used = margin_used.add(margin_req.initial);
         }
     }
@@ -168,6 +421,8 @@ pub fn calculate_account_metrics(
             - pending_funding.value(),
     );
 
+    // resting orders count as used margin so they can't back a withdrawal
+    let margin_used = margin_used.add(account.reserved_order_margin());
     let free_margin = Quote::new(total_equity.value() - margin_used.value());
 
     let margin_ratio = if total_notional.value().is_zero() {
@@ -186,6 +441,33 @@ pub fn calculate_account_metrics(
     }
 }
 
+// 10.5: master plus subaccounts summed. risk stays per account, this is only a view
+#[derive(Debug, Clone)]
+pub struct ConsolidatedMetrics {
+    pub master: AccountId,
+    pub accounts: Vec<(AccountId, AccountMetrics)>,
+    pub total_balance: Quote,
+    pub total_equity: Quote,
+    pub unrealized_pnl: Quote,
+    pub margin_used: Quote,
+    pub free_margin: Quote,
+}
+
+impl ConsolidatedMetrics {
+    pub fn new(master: AccountId, accounts: Vec<(AccountId, AccountMetrics)>, total_balance: Quote) -> Self {
+        let sum = |f: fn(&AccountMetrics) -> Quote| -> Quote { accounts.iter().map(|(_, m)| f(m)).sum() };
+        Self {
+            master,
+            total_balance,
+            total_equity: sum(|m| m.total_equity),
+            unrealized_pnl: sum(|m| m.unrealized_pnl),
+            margin_used: sum(|m| m.margin_used),
+            free_margin: sum(|m| m.free_margin),
+            accounts,
+        }
+    }
+}
+
 pub fn can_open_position(
     account: &Account,
     collateral_required: Quote,
@@ -213,6 +495,16 @@ pub enum AccountError {
     #[error("Account is liquidatable")]
     Liquidatable,
 
+    #[error("Insufficient free collateral: requested {requested}, available {available}")]
+    InsufficientFreeCollateral { requested: Quote, available: Quote },
+
+    #[error("Insufficient {asset:?}: requested {requested}, available {available}")]
+    InsufficientAsset {
+        asset: CollateralType,
+        requested: Decimal,
+        available: Decimal,
+    },
+
     #[error("Withdrawal locked: {reason}")]
     WithdrawalLocked { reason: String },
 }
@@ -220,7 +512,7 @@ pub enum AccountError {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{Leverage, SignedSize};
+    use crate::types::SignedSize;
     use rust_decimal_macros::dec;
 
     fn test_account() -> Account {
@@ -260,6 +552,23 @@ mod tests {
         assert!(matches!(result, Err(AccountError::InsufficientBalance { .. })));
     }
 
+    #[test]
+    fn available_balance_capped_by_free_margin_and_balance() {
+        let account = test_account();
+        let mut metrics = calculate_account_metrics(&account, &HashMap::new(), &MarginParams::default());
+
+        // no positions: whole balance, less any funding buffer
+        assert_eq!(account.available_balance(&metrics, Quote::zero()).value(), dec!(10000));
+        assert_eq!(account.available_balance(&metrics, Quote::new(dec!(50))).value(), dec!(9950));
+
+        // unrealized profit raises free margin but can't be withdrawn
+        metrics.free_margin = Quote::new(dec!(15000));
+        assert_eq!(account.available_balance(&metrics, Quote::zero()).value(), dec!(10000));
+
+        metrics.free_margin = Quote::new(dec!(-100));
+        assert_eq!(account.available_balance(&metrics, Quote::zero()).value(), dec!(0));
+    }
+
     #[test]
     fn position_management() {
         let mut account = test_account();
@@ -375,4 +684,98 @@ mod tests {
         account.return_collateral(Quote::new(dec!(3000)));
         assert_eq!(account.balance.value(), dec!(10000));
     }
+
+    #[test]
+    fn collateral_value_applies_weights() {
+        let mut account = test_account();
+        account.deposit_asset(CollateralType::Btc, dec!(0.5));
+        account.deposit_asset(CollateralType::Usdc, dec!(1000));
+        account.deposit_asset(CollateralType::Usd, dec!(500)); // quote, lands in balance
+
+        let value = account.collateral_value(|asset| match asset {
+            CollateralType::Btc => Some((Price::new_unchecked(dec!(50000)), dec!(0.9))),
+            CollateralType::Usdc => Some((Price::new_unchecked(dec!(1)), dec!(0.999))),
+            _ => None,
+        });
+
+        // 0.5 * 50000 * 0.9 + 1000 * 0.999
+        assert_eq!(value.value(), dec!(23499));
+        assert_eq!(account.balance.value(), dec!(10500));
+    }
+
+    #[test]
+    fn convert_to_quote_sells_stables_first() {
+        let mut account = Account::new(AccountId(1), Timestamp::from_millis(0));
+        account.deposit_asset(CollateralType::Eth, dec!(1));
+        account.deposit_asset(CollateralType::Usdc, dec!(100));
+        account.balance = Quote::new(dec!(-400));
+
+        let conversions = account.convert_to_quote(Quote::zero(), |asset| match asset {
+            CollateralType::Eth => Some((Price::new_unchecked(dec!(3000)), dec!(1))),
+            _ => Some((Price::new_unchecked(dec!(1)), dec!(1))),
+        });
+
+        assert_eq!(conversions.len(), 2);
+        assert_eq!(conversions[0].asset, CollateralType::Usdc);
+        assert_eq!(conversions[1].amount, dec!(0.1));
+        assert_eq!(account.balance.value(), dec!(0));
+        assert_eq!(account.asset_balance(CollateralType::Usdc), dec!(0));
+        assert_eq!(account.asset_balance(CollateralType::Eth), dec!(0.9));
+    }
+
+    #[test]
+    fn convert_to_quote_applies_the_collateral_haircut() {
+        let mut account = Account::new(AccountId(1), Timestamp::from_millis(0));
+        account.deposit_asset(CollateralType::Eth, dec!(1));
+        account.deposit_asset(CollateralType::Btc, dec!(1));
+        account.balance = Quote::new(dec!(-1200));
+
+        // BTC has no weight and stays put. 1200 of debt at 3000 x 0.8 takes 0.5 ETH
+        let conversions = account.convert_to_quote(Quote::zero(), |asset| match asset {
+            CollateralType::Eth => Some((Price::new_unchecked(dec!(3000)), dec!(0.8))),
+            CollateralType::Btc => Some((Price::new_unchecked(dec!(50000)), dec!(0))),
+            _ => None,
+        });
+
+        assert_eq!(conversions.len(), 1);
+        assert_eq!(conversions[0].amount, dec!(0.5));
+        assert_eq!(conversions[0].proceeds.value(), dec!(1200));
+        assert_eq!(account.balance.value(), dec!(0));
+        assert_eq!(account.asset_balance(CollateralType::Eth), dec!(0.5));
+        assert_eq!(account.asset_balance(CollateralType::Btc), dec!(1));
+    }
+
+    #[test]
+    fn debt_covered_from_position_collateral() {
+        let mut account = Account::new(AccountId(1), Timestamp::from_millis(0));
+        account.set_position(test_position(MarketId(2)));
+        account.set_position(test_position(MarketId(1)));
+        account.balance = Quote::new(dec!(-6000));
+
+        // market 1 drained first, then 1000 from market 2
+        let taken = account.cover_debt_from_positions();
+        assert_eq!(taken.value(), dec!(6000));
+        assert!(account.debt().value().is_zero());
+        assert!(account.get_position(MarketId(1)).unwrap().collateral.value().is_zero());
+        assert_eq!(account.get_position(MarketId(2)).unwrap().collateral.value(), dec!(4000));
+    }
+
+    #[test]
+    fn volume_window_drops_old_days() {
+        let mut account = test_account();
+        let day = 24 * 60 * 60 * 1000;
+        let window = 30 * day;
+
+        account.record_volume(Timestamp::from_millis(0), dec!(600000), window);
+        account.record_volume(Timestamp::from_millis(day / 2), dec!(400000), window);
+        account.record_volume(Timestamp::from_millis(10 * day), dec!(250000), window);
+        assert_eq!(account.volume_history.len(), 2);
+        assert_eq!(account.trading_volume_30d, dec!(1250000));
+
+        // day 0 is fully outside the window on day 30, day 10 is not
+        assert_eq!(account.volume_in_window(Timestamp::from_millis(30 * day), window), dec!(250000));
+        account.record_volume(Timestamp::from_millis(41 * day), dec!(1), window);
+        assert_eq!(account.volume_history.len(), 1);
+        assert_eq!(account.trading_volume_30d, dec!(1));
+    }
 }
@@ -2,7 +2,7 @@
 // ranked by pnl * leverage score: highest score gets deleveraged first.
 
 use crate::position::Position;
-use crate::types::{AccountId, MarketId, Price, Quote, Side};
+use crate::types::{AccountId, MarketId, Price, Quote, Side, Timestamp};
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use serde::{Deserialize, Serialize};
@@ -12,6 +12,7 @@ use std::cmp::Ordering;
 pub struct AdlParams {
     pub min_trigger_amount: Quote,      // min bad debt to trigger ADL
     pub max_accounts_per_round: usize,  // cap per ADL round
+    pub indicator_buckets: u8,          // lights on the ADL indicator
 }
 
 impl Default for AdlParams {
@@ -19,6 +20,7 @@ impl Default for AdlParams {
         Self {
             min_trigger_amount: Quote::new(dec!(100)),
             max_accounts_per_round: 50,
+            indicator_buckets: 5,
         }
     }
 }
@@ -163,6 +165,116 @@ pub fn should_trigger_adl(uncovered_debt: Quote, params: &AdlParams) -> bool {
     uncovered_debt.value() >= params.min_trigger_amount.value()
 }
 
+// 6.2.3: a profitable account's share of a socialized loss, taken out of unrealized
+// profit first and realized-but-unwithdrawn profit after. kept on the account for audit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LossHaircut {
+    pub market_id: MarketId,
+    pub bankrupt_account: AccountId,
+    pub unrealized: Quote,
+    pub realized: Quote,
+    pub timestamp: Timestamp,
+}
+
+impl LossHaircut {
+    pub fn total(&self) -> Quote {
+        self.unrealized.add(self.realized)
+    }
+}
+
+// splits `loss` pro rata over (account, unrealized profit, realized profit). nobody loses
+// more than their profit, so the haircuts sum to less than `loss` when profits run short
+pub fn socialize_haircuts(profits: &[(AccountId, Quote, Quote)], loss: Quote) -> Vec<(AccountId, Quote, Quote)> {
+    let total: Decimal = profits.iter().map(|(_, u, r)| u.value() + r.value()).sum();
+    if total <= Decimal::ZERO || loss.value() <= Decimal::ZERO {
+        return Vec::new();
+    }
+    let ratio = (loss.value() / total).min(Decimal::ONE);
+
+    profits
+        .iter()
+        .filter_map(|(id, unrealized, realized)| {
+            let take = (unrealized.value() + realized.value()) * ratio;
+            let from_unrealized = take.min(unrealized.value());
+            (take > Decimal::ZERO).then(|| (*id, Quote::new(from_unrealized), Quote::new(take - from_unrealized)))
+        })
+        .collect()
+}
+
+// 6.2.2: bucket for the position at `rank` (0 = next in line) of `total`. the top bucket is
+// `buckets`, the back of the queue 1
+pub fn adl_quantile(rank: usize, total: usize, buckets: u8) -> u8 {
+    if total == 0 || rank >= total {
+        return 0;
+    }
+    buckets - (rank * buckets as usize / total) as u8
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdlRank {
+    pub account_id: AccountId,
+    pub score: Decimal,
+    pub unrealized_pnl: Quote,
+    pub quantile: u8,
+}
+
+// each side's ADL queue at the last mark. losing positions can't be deleveraged and aren't
+// listed, their indicator reads 0
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdlRanking {
+    pub updated_at: Timestamp,
+    pub longs: Vec<AdlRank>, // next in line first
+    pub shorts: Vec<AdlRank>,
+}
+
+impl AdlRanking {
+    pub fn new(timestamp: Timestamp) -> Self {
+        Self {
+            updated_at: timestamp,
+            longs: Vec::new(),
+            shorts: Vec::new(),
+        }
+    }
+
+    pub fn build(positions: Vec<(AccountId, Position)>, mark_price: Price, buckets: u8, timestamp: Timestamp) -> Self {
+        let side_ranks = |side| {
+            let candidates = rank_adl_candidates(positions.clone(), side, mark_price);
+            let total = candidates.len();
+            candidates
+                .into_iter()
+                .enumerate()
+                .map(|(rank, c)| AdlRank {
+                    account_id: c.account_id,
+                    score: c.score,
+                    unrealized_pnl: c.unrealized_pnl,
+                    quantile: adl_quantile(rank, total, buckets),
+                })
+                .collect()
+        };
+
+        Self {
+            updated_at: timestamp,
+            longs: side_ranks(Side::Long),
+            shorts: side_ranks(Side::Short),
+        }
+    }
+
+    pub fn side(&self, side: Side) -> &[AdlRank] {
+        match side {
+            Side::Long => &self.longs,
+            Side::Short => &self.shorts,
+        }
+    }
+
+    pub fn quantile_of(&self, account_id: AccountId) -> u8 {
+        self.longs
+            .iter()
+            .chain(&self.shorts)
+            .find(|rank| rank.account_id == account_id)
+            .map_or(0, |rank| rank.quantile)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -264,6 +376,49 @@ mod tests {
         assert!(should_trigger_adl(Quote::new(dec!(500)), &params));
     }
 
+    #[test]
+    fn adl_indicator_buckets_by_rank() {
+        let quantiles: Vec<u8> = (0..10).map(|rank| adl_quantile(rank, 10, 5)).collect();
+        assert_eq!(quantiles, vec![5, 5, 4, 4, 3, 3, 2, 2, 1, 1]);
+        assert_eq!(adl_quantile(0, 1, 5), 5);
+        assert_eq!(adl_quantile(3, 3, 5), 0);
+
+        let positions = vec![
+            (AccountId(1), create_position(dec!(1), dec!(50000), dec!(25000), dec!(2))),
+            (AccountId(2), create_position(dec!(1), dec!(50000), dec!(5000), dec!(10))),
+            (AccountId(3), create_position(dec!(-1), dec!(50000), dec!(5000), dec!(10))),
+        ];
+        let ranking = AdlRanking::build(positions, Price::new_unchecked(dec!(55000)), 5, Timestamp::from_millis(0));
+
+        assert_eq!(ranking.side(Side::Long)[0].account_id, AccountId(2));
+        assert_eq!(ranking.quantile_of(AccountId(2)), 5);
+        assert_eq!(ranking.quantile_of(AccountId(1)), 3);
+        // the short is losing, nothing to deleverage
+        assert!(ranking.side(Side::Short).is_empty());
+        assert_eq!(ranking.quantile_of(AccountId(3)), 0);
+    }
+
+    #[test]
+    fn loss_is_shared_pro_rata_to_profit() {
+        let profits = vec![
+            (AccountId(1), Quote::new(dec!(3000)), Quote::new(dec!(1000))),
+            (AccountId(2), Quote::new(dec!(500)), Quote::new(dec!(500))),
+        ];
+
+        // a fifth of every profit, unrealized first
+        let haircuts = socialize_haircuts(&profits, Quote::new(dec!(1000)));
+        assert_eq!(haircuts[0], (AccountId(1), Quote::new(dec!(800)), Quote::new(dec!(0))));
+        assert_eq!(haircuts[1], (AccountId(2), Quote::new(dec!(200)), Quote::new(dec!(0))));
+
+        // more loss than profit: everything goes and the rest stays uncovered
+        let haircuts = socialize_haircuts(&profits, Quote::new(dec!(9000)));
+        assert_eq!(haircuts[1], (AccountId(2), Quote::new(dec!(500)), Quote::new(dec!(500))));
+        let taken: Decimal = haircuts.iter().map(|(_, u, r)| u.value() + r.value()).sum();
+        assert_eq!(taken, dec!(5000));
+
+        assert!(socialize_haircuts(&[], Quote::new(dec!(100))).is_empty());
+    }
+
     #[test]
     fn adl_respects_max_accounts() {
         let mut positions = Vec::new();
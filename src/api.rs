@@ -8,11 +8,20 @@
 // The actual transport layer (HTTP server, blockchain runtime, etc) lives outside
 // the core engine. This module just defines the contracts and request/response types.
 
+use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
+use crate::account::MarginMode;
+use crate::adl::AdlRank;
+use crate::config::{FeeOverride, FeeTier};
+use crate::custody::CollateralType;
 use crate::types::{AccountId, OrderId, Side};
 use crate::events::Event;
+use crate::funding::{annualized_funding_rate, FundingParams, FundingRecord, FundingState, PredictedFunding};
+use crate::ledger::ConservationReport;
+use crate::liquidation::InsuranceFlow;
+use crate::referral::ReferrerStats;
 use crate::position::Position;
 use crate::order::Order;
 
@@ -26,18 +35,44 @@ pub enum EngineCommand {
         account_id: AccountId,
     },
 
+    // Create a numbered subaccount under a master account
+    CreateSubaccount {
+        master_id: AccountId,
+    },
+
+    // Move quote collateral between a master and its subaccounts (limited to free collateral)
+    Transfer {
+        from: AccountId,
+        to: AccountId,
+        amount: Decimal,
+    },
+
     // Deposit collateral into an account
     Deposit {
         account_id: AccountId,
         amount: Decimal,
     },
 
-    // Withdraw collateral from an account (must have no open positions)
+    // Withdraw collateral from an account (limited to free collateral)
     Withdraw {
         account_id: AccountId,
         amount: Decimal,
     },
 
+    // Deposit non quote collateral (counts toward margin after haircut)
+    DepositCollateral {
+        account_id: AccountId,
+        asset: CollateralType,
+        amount: Decimal,
+    },
+
+    // Withdraw non quote collateral (haircut value limited to free collateral)
+    WithdrawCollateral {
+        account_id: AccountId,
+        asset: CollateralType,
+        amount: Decimal,
+    },
+
     // Place a new order
     PlaceOrder {
         account_id: AccountId,
@@ -64,6 +99,48 @@ pub enum EngineCommand {
         account_id: AccountId,
     },
 
+    // Select leverage used to size initial margin on subsequent fills
+    SetLeverage {
+        account_id: AccountId,
+        leverage: Decimal,
+    },
+
+    // Move collateral from the account balance into the open position
+    AddMargin {
+        account_id: AccountId,
+        amount: Decimal,
+    },
+
+    // Move excess collateral from the open position back to the balance
+    RemoveMargin {
+        account_id: AccountId,
+        amount: Decimal,
+    },
+
+    // Switch between isolated and portfolio margin (only with no open positions)
+    SetMarginMode {
+        account_id: AccountId,
+        mode: MarginMode,
+    },
+
+    // Pin an account to a VIP fee schedule, None returns it to the volume tiers (admin operation)
+    SetFeeOverride {
+        account_id: AccountId,
+        fee_override: Option<FeeOverride>,
+    },
+
+    // Register the account's referral code (one per account, unique ignoring case)
+    CreateReferralCode {
+        account_id: AccountId,
+        code: String,
+    },
+
+    // Sign the account up under another account's referral code
+    ApplyReferralCode {
+        account_id: AccountId,
+        code: String,
+    },
+
     // Update the oracle price (admin/keeper operation)
     UpdatePrice {
         price: Decimal,
@@ -78,9 +155,42 @@ pub enum EngineCommand {
         timestamp: u64,
     },
 
-    // Attempt to liquidate an account (keeper/anyone operation)
+    // Attempt to liquidate an account, the liquidator collects the reward (keeper/anyone operation)
     Liquidate {
         account_id: AccountId,
+        liquidator: AccountId,
+    },
+
+    // Buy backstop vault shares with free balance
+    DepositToBackstop {
+        account_id: AccountId,
+        amount: Decimal,
+    },
+
+    // Redeem backstop vault shares at the current share value
+    WithdrawFromBackstop {
+        account_id: AccountId,
+        shares: Decimal,
+    },
+
+    // Work a slice of the backstop vault's position back into the book (keeper operation)
+    UnwindBackstop,
+
+    // Stake free balance into the insurance fund for shares
+    StakeInsurance {
+        account_id: AccountId,
+        amount: Decimal,
+    },
+
+    // Start the cooldown on insurance shares
+    RequestUnstake {
+        account_id: AccountId,
+        shares: Decimal,
+    },
+
+    // Withdraw requested insurance shares once the cooldown has passed
+    UnstakeInsurance {
+        account_id: AccountId,
     },
 
     // Process auto deleveraging for a given bad debt amount (admin operation)
@@ -139,11 +249,47 @@ pub enum EngineQuery {
     // Get funding rate information
     GetFundingInfo,
 
+    // Get past funding settlements, most recent first
+    GetFundingHistory {
+        /// Max number of settlements to return
+        limit: Option<usize>,
+    },
+
+    // Get the market's insurance fund, the global fund behind it and recent flows
+    GetInsuranceFund {
+        /// Max number of flows to return
+        limit: Option<usize>,
+    },
+
+    // Get the positions next in line for auto-deleveraging on each side
+    GetAdlRanking {
+        /// Max number of positions per side
+        limit: Option<usize>,
+    },
+
     // Get recent events
     GetRecentEvents {
         /// Max number of events to return
         limit: Option<usize>,
     },
+
+    // Get a master account and its subaccounts summed
+    GetConsolidatedAccount {
+        master_id: AccountId,
+    },
+
+    // Get the fee rates an account currently pays (volume tier or VIP override)
+    GetFeeTier {
+        account_id: AccountId,
+    },
+
+    // Get a referrer's code, referees, referred volume and earnings history
+    GetReferralStats {
+        account_id: AccountId,
+    },
+
+    // Reconcile the fund ledger against state and show where all deposits are held
+    GetConservationReport,
 }
 
 // Unified response wrapper for all API operations
@@ -218,6 +364,9 @@ pub enum ErrorCode {
     AccountAlreadyExists,
     InsufficientBalance,
     InsufficientMargin,
+    InvalidTransfer,
+    InvalidFeeSchedule,
+    InvalidReferral,
 
     // Order errors
     OrderNotFound,
@@ -229,6 +378,8 @@ pub enum ErrorCode {
     // Position errors
     PositionNotFound,
     CannotWithdrawWithPosition,
+    InvalidLeverage,
+    MarginModeLocked,
 
     // Market errors
     MarketClosed,
@@ -255,6 +406,39 @@ pub struct AccountInfo {
     pub available_margin: Decimal,
     pub position: Option<PositionInfo>,
     pub open_orders_count: usize,
+    /// Held for resting orders, not available to withdraw
+    pub order_margin: Decimal,
+    pub collateral: Vec<CollateralBalance>,
+}
+
+// Master level view over all subaccounts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsolidatedAccountInfo {
+    pub master_id: AccountId,
+    pub total_balance: Decimal,
+    pub total_equity: Decimal,
+    pub total_unrealized_pnl: Decimal,
+    pub total_available_margin: Decimal,
+    pub accounts: Vec<SubaccountSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubaccountSummary {
+    pub account_id: AccountId,
+    /// 0 for the master itself
+    pub subaccount_number: u32,
+    pub balance: Decimal,
+    pub equity: Decimal,
+    pub available_margin: Decimal,
+}
+
+// Non quote collateral held by an account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollateralBalance {
+    pub asset: CollateralType,
+    pub amount: Decimal,
+    /// Amount x oracle price x collateral weight
+    pub margin_value: Decimal,
 }
 
 // Position information response
@@ -267,6 +451,8 @@ pub struct PositionInfo {
     pub unrealized_pnl: Decimal,
     pub liquidation_price: Option<Decimal>,
     pub leverage: Decimal,
+    /// ADL indicator, 1-5 with 5 next in line, 0 when not in the queue
+    pub adl_quantile: u8,
 }
 
 impl From<&Position> for PositionInfo {
@@ -280,6 +466,7 @@ impl From<&Position> for PositionInfo {
             unrealized_pnl: Decimal::ZERO, // caller should calculate
             liquidation_price: None, // caller should calculate
             leverage: Decimal::ZERO, // caller should calculate
+            adl_quantile: 0, // Engine::position_info looks it up
         }
     }
 }
@@ -371,11 +558,40 @@ pub struct MarginInfo {
 pub struct FundingInfo {
     pub current_rate: Decimal,
     pub predicted_rate: Decimal,
+    pub annualized_current_rate: Decimal,
+    pub annualized_predicted_rate: Decimal,
+    pub premium_twap: Decimal,
     pub last_settlement: u64,
     pub next_settlement: u64,
     pub funding_interval_hours: u32,
 }
 
+// Insurance fund information
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsuranceFundInfo {
+    pub balance: Decimal,
+    /// Above this the market's penalties go to the global fund
+    pub target: Decimal,
+    /// Draws only once the market's fund is empty
+    pub global_balance: Decimal,
+    pub history: Vec<InsuranceFlow>,
+}
+
+impl FundingInfo {
+    pub fn new(state: &FundingState, predicted: &PredictedFunding, params: &FundingParams) -> Self {
+        Self {
+            current_rate: state.current_rate,
+            predicted_rate: predicted.rate,
+            annualized_current_rate: annualized_funding_rate(state.current_rate, params.period_hours),
+            annualized_predicted_rate: predicted.annualized_rate,
+            premium_twap: predicted.premium_twap,
+            last_settlement: state.last_update.as_millis() as u64,
+            next_settlement: predicted.next_funding.as_millis() as u64,
+            funding_interval_hours: params.period_hours.to_u32().unwrap_or(8),
+        }
+    }
+}
+
 // Result of placing an order
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlaceOrderResult {
@@ -418,6 +634,9 @@ pub struct LiquidationResult {
     pub liquidation_price: Decimal,
     pub bad_debt: Decimal,
     pub insurance_payout: Decimal,
+    pub remaining_size: Decimal,
+    pub backstop_size: Decimal, // left to the backstop because the book couldn't take it
+    pub adl_size: Decimal,      // closed against auto-deleveraged positions
 }
 
 // The main API service trait that defines the engine interface.
@@ -435,14 +654,29 @@ pub trait EngineApi {
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum CommandResult {
     AccountCreated { account_id: AccountId },
+    SubaccountCreated { account_id: AccountId, subaccount_number: u32 },
+    Transferred { from_balance: Decimal, to_balance: Decimal },
     Deposited(DepositResult),
     Withdrawn(WithdrawResult),
+    CollateralUpdated { asset: CollateralType, amount: Decimal },
     OrderPlaced(PlaceOrderResult),
     OrderCancelled { order_id: OrderId },
     AllOrdersCancelled { count: usize },
+    LeverageSet { leverage: Decimal },
+    MarginUpdated { collateral: Decimal, liquidation_price: Option<Decimal> },
+    MarginModeSet { mode: MarginMode },
+    FeeOverrideSet { fee_override: Option<FeeOverride> },
+    ReferralCodeCreated { code: String },
+    ReferralCodeApplied { referrer: AccountId },
     PriceUpdated { price: Decimal },
     FundingSettled { accounts_affected: usize },
     Liquidated(LiquidationResult),
+    BackstopDeposited { shares: Decimal },
+    BackstopWithdrawn { amount: Decimal },
+    BackstopUnwound { filled_size: Decimal },
+    InsuranceStaked { shares: Decimal },
+    UnstakeRequested { available_at: u64 },
+    InsuranceUnstaked { amount: Decimal },
     AdlProcessed { accounts_affected: usize },
 }
 
@@ -460,27 +694,51 @@ pub enum QueryResult {
     MarginInfo(MarginInfo),
     Liquidatable { is_liquidatable: bool },
     FundingInfo(FundingInfo),
+    FundingHistory(Vec<FundingRecord>),
+    InsuranceFund(InsuranceFundInfo),
+    AdlRanking { longs: Vec<AdlRank>, shorts: Vec<AdlRank> },
     RecentEvents(Vec<Event>),
+    ConsolidatedAccount(ConsolidatedAccountInfo),
+    FeeTier(FeeTier),
+    ReferralStats(ReferrerStats),
+    ConservationReport(ConservationReport),
 }
 
 // Validates an incoming command before execution
 pub fn validate_command(cmd: &EngineCommand) -> Result<(), ApiError> {
     match cmd {
-        EngineCommand::Deposit { amount, .. } => {
-            if *amount <= Decimal::ZERO {
-                return Err(ApiError::new(
-                    ErrorCode::InvalidOrderSize,
-                    "Deposit amount must be positive",
-                ));
-            }
+        EngineCommand::Deposit { amount, .. } if *amount <= Decimal::ZERO => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Deposit amount must be positive",
+            ));
         }
-        EngineCommand::Withdraw { amount, .. } => {
-            if *amount <= Decimal::ZERO {
-                return Err(ApiError::new(
-                    ErrorCode::InvalidOrderSize,
-                    "Withdrawal amount must be positive",
-                ));
-            }
+        EngineCommand::Withdraw { amount, .. } if *amount <= Decimal::ZERO => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Withdrawal amount must be positive",
+            ));
+        }
+        EngineCommand::Transfer { amount, .. } if *amount <= Decimal::ZERO => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Transfer amount must be positive",
+            ));
+        }
+        EngineCommand::Transfer { from, to, .. } if from == to => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidTransfer,
+                "Cannot transfer to the same account",
+            ));
+        }
+        EngineCommand::DepositCollateral { amount, .. }
+        | EngineCommand::WithdrawCollateral { amount, .. }
+            if *amount <= Decimal::ZERO =>
+        {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Collateral amount must be positive",
+            ));
         }
         EngineCommand::PlaceOrder { size, limit_price, .. } => {
             if *size <= Decimal::ZERO {
@@ -498,13 +756,71 @@ pub fn validate_command(cmd: &EngineCommand) -> Result<(), ApiError> {
                 }
             }
         }
-        EngineCommand::UpdatePrice { price, .. } => {
-            if *price <= Decimal::ZERO {
-                return Err(ApiError::new(
-                    ErrorCode::InvalidPrice,
-                    "Price must be positive",
-                ));
-            }
+        EngineCommand::DepositToBackstop { amount, .. } if *amount <= Decimal::ZERO => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Backstop deposit must be positive",
+            ));
+        }
+        EngineCommand::WithdrawFromBackstop { shares, .. } if *shares <= Decimal::ZERO => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Backstop shares must be positive",
+            ));
+        }
+        EngineCommand::StakeInsurance { amount, .. } if *amount <= Decimal::ZERO => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Stake must be positive",
+            ));
+        }
+        EngineCommand::RequestUnstake { shares, .. } if *shares <= Decimal::ZERO => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Unstake shares must be positive",
+            ));
+        }
+        EngineCommand::AddMargin { amount, .. } | EngineCommand::RemoveMargin { amount, .. }
+            if *amount <= Decimal::ZERO =>
+        {
+            return Err(ApiError::new(
+                ErrorCode::InvalidOrderSize,
+                "Margin amount must be positive",
+            ));
+        }
+        EngineCommand::SetLeverage { leverage, .. } if *leverage < Decimal::ONE => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidLeverage,
+                "Leverage must be at least 1x",
+            ));
+        }
+        EngineCommand::SetFeeOverride { fee_override: Some(vip), .. }
+            if vip.maker_fee_bps < 0 && vip.maker_fee_bps.unsigned_abs() > vip.taker_fee_bps =>
+        {
+            return Err(ApiError::new(
+                ErrorCode::InvalidFeeSchedule,
+                "Maker rebate cannot exceed the taker fee",
+            ));
+        }
+        EngineCommand::CreateReferralCode { code, .. } | EngineCommand::ApplyReferralCode { code, .. }
+            if code.trim().is_empty() =>
+        {
+            return Err(ApiError::new(
+                ErrorCode::InvalidReferral,
+                "Referral code cannot be empty",
+            ));
+        }
+        EngineCommand::Liquidate { account_id, liquidator } if account_id == liquidator => {
+            return Err(ApiError::new(
+                ErrorCode::Unauthorized,
+                "Cannot liquidate your own account",
+            ));
+        }
+        EngineCommand::UpdatePrice { price, .. } if *price <= Decimal::ZERO => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidPrice,
+                "Price must be positive",
+            ));
         }
         _ => {}
     }
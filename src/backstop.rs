@@ -0,0 +1,139 @@
+// 16.0: backstop vault. takes over liquidated positions the book couldn't absorb, at the
+// bankruptcy price, and works them back out through the book (16.1). depositors hold
+// shares of the vault's equity, so its trading pnl and the liquidation discount are theirs.
+
+use crate::types::{AccountId, MarketId, Quote, Timestamp};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackstopParams {
+    pub unwind_interval_ms: i64,    // min time between unwind steps in one market
+    pub unwind_fraction: Decimal,   // of the vault's position per step
+    pub unwind_slippage: Decimal,   // IOC limit this far through mark, 0.005 = 0.5%
+}
+
+impl Default for BackstopParams {
+    fn default() -> Self {
+        Self {
+            unwind_interval_ms: 60_000,
+            unwind_fraction: dec!(0.1),
+            unwind_slippage: dec!(0.005),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackstopVault {
+    pub account_id: AccountId, // engine account holding the vault's balance and positions
+    shares: HashMap<AccountId, Decimal>,
+    total_shares: Decimal,
+    last_unwind: HashMap<MarketId, Timestamp>,
+}
+
+impl BackstopVault {
+    pub fn new(account_id: AccountId) -> Self {
+        Self {
+            account_id,
+            shares: HashMap::new(),
+            total_shares: Decimal::ZERO,
+            last_unwind: HashMap::new(),
+        }
+    }
+
+    pub fn shares_of(&self, depositor: AccountId) -> Decimal {
+        self.shares.get(&depositor).copied().unwrap_or(Decimal::ZERO)
+    }
+
+    pub fn total_shares(&self) -> Decimal {
+        self.total_shares
+    }
+
+    // 16.2: shares minted for a deposit at the vault's current equity, 1:1 into an empty
+    // vault. None when outstanding shares are worth nothing
+    pub fn shares_for_deposit(&self, amount: Quote, equity: Quote) -> Option<Decimal> {
+        if self.total_shares.is_zero() {
+            return Some(amount.value());
+        }
+        if equity.value() <= Decimal::ZERO {
+            return None;
+        }
+        Some(amount.value() * self.total_shares / equity.value())
+    }
+
+    pub fn value_of(&self, shares: Decimal, equity: Quote) -> Quote {
+        if self.total_shares.is_zero() {
+            return Quote::zero();
+        }
+        Quote::new((shares * equity.value() / self.total_shares).max(Decimal::ZERO))
+    }
+
+    pub fn mint(&mut self, depositor: AccountId, shares: Decimal) {
+        *self.shares.entry(depositor).or_insert(Decimal::ZERO) += shares;
+        self.total_shares += shares;
+    }
+
+    pub fn burn(&mut self, depositor: AccountId, shares: Decimal) {
+        let held = self.shares.entry(depositor).or_insert(Decimal::ZERO);
+        *held -= shares;
+        if held.is_zero() {
+            self.shares.remove(&depositor);
+        }
+        self.total_shares -= shares;
+    }
+
+    // 16.1: one unwind step per interval per market
+    pub fn unwind_due(&self, market_id: MarketId, now: Timestamp, params: &BackstopParams) -> bool {
+        self.last_unwind
+            .get(&market_id)
+            .is_none_or(|last| now.as_millis() - last.as_millis() >= params.unwind_interval_ms)
+    }
+
+    pub fn record_unwind(&mut self, market_id: MarketId, now: Timestamp) {
+        self.last_unwind.insert(market_id, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_track_vault_equity() {
+        let mut vault = BackstopVault::new(AccountId(0));
+        let alice = AccountId(1);
+        let bob = AccountId(2);
+
+        let minted = vault.shares_for_deposit(Quote::new(dec!(1000)), Quote::zero()).unwrap();
+        vault.mint(alice, minted);
+        assert_eq!(minted, dec!(1000));
+
+        // vault made 500, bob's 1500 buys in at 1.5 per share
+        let minted = vault.shares_for_deposit(Quote::new(dec!(1500)), Quote::new(dec!(1500))).unwrap();
+        vault.mint(bob, minted);
+        assert_eq!(minted, dec!(1000));
+
+        // a loss is shared pro rata
+        let equity = Quote::new(dec!(2000));
+        assert_eq!(vault.value_of(vault.shares_of(alice), equity).value(), dec!(1000));
+
+        vault.burn(alice, dec!(1000));
+        assert_eq!(vault.total_shares(), dec!(1000));
+        assert!(vault.shares_for_deposit(Quote::new(dec!(1)), Quote::new(dec!(-5))).is_none());
+    }
+
+    #[test]
+    fn unwind_steps_are_rate_limited() {
+        let mut vault = BackstopVault::new(AccountId(0));
+        let params = BackstopParams::default();
+        let market = MarketId(1);
+
+        assert!(vault.unwind_due(market, Timestamp::from_millis(0), &params));
+        vault.record_unwind(market, Timestamp::from_millis(0));
+        assert!(!vault.unwind_due(market, Timestamp::from_millis(59_999), &params));
+        assert!(vault.unwind_due(market, Timestamp::from_millis(60_000), &params));
+        assert!(vault.unwind_due(MarketId(2), Timestamp::from_millis(1), &params));
+    }
+}
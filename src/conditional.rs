@@ -112,6 +112,7 @@ impl ConditionalOrder {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_trailing_stop(
         id: ConditionalOrderId,
         account_id: AccountId,
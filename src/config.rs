@@ -1,5 +1,6 @@
 // 7.0 config.rs: all settings in one place. fees, margins, risk params.
 // 7.1 FeeConfig has maker/taker fees. no builder fees or referrals yet.
+// 7.3 volume tiers and VIP overrides resolve to the FeeTier an account pays.
 
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
@@ -62,6 +63,8 @@ pub struct FeeConfig {
     pub referral_fee_pct: Decimal,
     // Fee discount tiers based on volume
     pub volume_discounts: Vec<VolumeDiscount>,
+    // Length of the rolling volume window in milliseconds
+    pub volume_window_ms: i64,
 }
 
 impl Default for FeeConfig {
@@ -76,10 +79,104 @@ impl Default for FeeConfig {
                 VolumeDiscount { min_volume: Decimal::new(10_000_000, 0), discount_pct: 20 },
                 VolumeDiscount { min_volume: Decimal::new(100_000_000, 0), discount_pct: 30 },
             ],
+            volume_window_ms: 30 * 24 * 60 * 60 * 1000, // 30 days
         }
     }
 }
 
+impl FeeConfig {
+    // 7.3: discount of the highest tier the volume reaches, 0 below the first
+    pub fn discount_for(&self, volume: Decimal) -> u32 {
+        self.volume_discounts
+            .iter()
+            .filter(|tier| volume >= tier.min_volume)
+            .map(|tier| tier.discount_pct)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // 7.3: rates an account pays. a VIP override replaces the schedule outright,
+    // otherwise the volume discount comes off positive fees. rebates are left whole
+    pub fn tier_for(&self, volume: Decimal, fee_override: Option<&FeeOverride>) -> FeeTier {
+        if let Some(vip) = fee_override {
+            return FeeTier {
+                volume_30d: volume,
+                discount_pct: 0,
+                maker_fee_bps: Decimal::from(vip.maker_fee_bps),
+                taker_fee_bps: Decimal::from(vip.taker_fee_bps),
+                vip: true,
+                referral_discount_pct: Decimal::ZERO,
+            };
+        }
+
+        let discount_pct = self.discount_for(volume);
+        let discounted = |bps: Decimal| {
+            if bps > Decimal::ZERO {
+                bps * Decimal::from(100 - discount_pct.min(100)) / Decimal::ONE_HUNDRED
+            } else {
+                bps
+            }
+        };
+        FeeTier {
+            volume_30d: volume,
+            discount_pct,
+            maker_fee_bps: discounted(Decimal::from(self.maker_fee_bps)),
+            taker_fee_bps: discounted(Decimal::from(self.taker_fee_bps)),
+            vip: false,
+            referral_discount_pct: Decimal::ZERO,
+        }
+    }
+}
+
+// Per account fee schedule set by an admin, replaces the volume tiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeOverride {
+    pub maker_fee_bps: i32,
+    pub taker_fee_bps: u32,
+}
+
+// Fee rates in effect for one account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeeTier {
+    // Volume inside the rolling window
+    pub volume_30d: Decimal,
+    pub discount_pct: u32,
+    // Effective rates after discount, fractional bps possible
+    pub maker_fee_bps: Decimal,
+    pub taker_fee_bps: Decimal,
+    pub vip: bool,
+    // Referee discount from the referrer's tier, already in the rates above
+    pub referral_discount_pct: Decimal,
+}
+
+impl FeeTier {
+    // take the referee discount off positive rates. VIP schedules are left as set
+    pub fn with_referral_discount(mut self, discount_pct: Decimal) -> Self {
+        if self.vip || discount_pct.is_zero() {
+            return self;
+        }
+        let discounted = |bps: Decimal| {
+            if bps > Decimal::ZERO {
+                bps * (Decimal::ONE - discount_pct)
+            } else {
+                bps
+            }
+        };
+        self.maker_fee_bps = discounted(self.maker_fee_bps);
+        self.taker_fee_bps = discounted(self.taker_fee_bps);
+        self.referral_discount_pct = discount_pct;
+        self
+    }
+
+    pub fn maker_fee(&self, notional: Decimal) -> Decimal {
+        notional * self.maker_fee_bps / Decimal::from(10_000)
+    }
+
+    pub fn taker_fee(&self, notional: Decimal) -> Decimal {
+        notional * self.taker_fee_bps / Decimal::from(10_000)
+    }
+}
+
 // Volume based fee discount tier
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VolumeDiscount {
@@ -355,6 +452,24 @@ mod tests {
         assert_eq!(config.collateral_weight(CollateralType::Btc), Decimal::ZERO); // not configured
     }
 
+    #[test]
+    fn test_fee_tiers() {
+        let fees = FeeConfig::default();
+        assert_eq!(fees.discount_for(Decimal::new(999_999, 0)), 0);
+        assert_eq!(fees.discount_for(Decimal::new(10_000_000, 0)), 20);
+
+        // 20% off 5 bps taker and 2 bps maker
+        let tier = fees.tier_for(Decimal::new(10_000_000, 0), None);
+        assert_eq!(tier.taker_fee_bps, Decimal::new(4, 0));
+        assert_eq!(tier.maker_fee_bps, Decimal::new(16, 1));
+
+        let vip = FeeOverride { maker_fee_bps: -1, taker_fee_bps: 2 };
+        let tier = fees.tier_for(Decimal::ZERO, Some(&vip));
+        assert!(tier.vip);
+        assert_eq!(tier.maker_fee_bps, Decimal::new(-1, 0));
+        assert_eq!(tier.taker_fee(Decimal::new(100_000, 0)), Decimal::new(20, 0));
+    }
+
     #[test]
     fn test_environment_presets() {
         assert!(Environment::Development.config().validate().is_ok());
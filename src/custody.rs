@@ -9,8 +9,9 @@ use crate::types::AccountId;
 // Unique identifier for a deposit/withdrawal transaction
 pub type TxId = String;
 
-// Supported collateral types
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+// Supported collateral types. Declaration order is also the order non quote
+// collateral is sold in when a quote balance needs covering (stables first)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum CollateralType {
     Usd,
@@ -519,8 +520,10 @@ mod tests {
 
     #[test]
     fn test_withdrawal_cooldown() {
-        let mut config = CustodyConfig::default();
-        config.withdrawal_cooldown = 3600;
+        let config = CustodyConfig {
+            withdrawal_cooldown: 3600,
+            ..Default::default()
+        };
         let mut custody = CustodyManager::new(config);
 
         let deposit = DepositRequest::new(
@@ -0,0 +1,480 @@
+// 8.11: auto-deleveraging (6.2). when a liquidation would leave debt the insurance fund
+// can't cover, the bankrupt position closes against ranked profitable positions on the
+// other side at its bankruptcy price. their forgone profit pays for the shortfall.
+// markets in socializing mode haircut that profit instead and leave the positions open.
+
+use super::core::Engine;
+use super::results::EngineError;
+use crate::adl::{rank_adl_candidates, socialize_haircuts, AdlRank, AdlRanking, LossHaircut};
+use crate::events::{AutoDeleverageEvent, EventPayload, LossHaircutEvent};
+use crate::ledger::{EntryKind, LedgerAccount};
+use crate::position::Position;
+use crate::types::{AccountId, MarketId, Price, Quote, Side, SignedSize};
+use rust_decimal::Decimal;
+
+impl Engine {
+    // 6.2.2: rebuild the market's ADL queue at the current mark. runs on every mark update
+    pub fn refresh_adl_ranking(&mut self, market_id: MarketId) -> Result<(), EngineError> {
+        let market = self
+            .markets
+            .get(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        let mark_price = market.mark_price.ok_or(EngineError::NoMarkPrice(market_id))?;
+
+        let positions: Vec<(AccountId, Position)> = self
+            .position_holders(market_id)
+            .map(|(id, _, position)| (id, position.clone()))
+            .collect();
+        let ranking = AdlRanking::build(positions, mark_price, self.config.adl.indicator_buckets, self.current_time);
+        self.markets.get_mut(&market_id).unwrap().adl_ranking = ranking;
+        Ok(())
+    }
+
+    // indicator lights for the account's position, 0 when it isn't in the queue
+    pub fn adl_quantile(&self, account_id: AccountId, market_id: MarketId) -> u8 {
+        self.markets
+            .get(&market_id)
+            .map_or(0, |m| m.adl_ranking.quantile_of(account_id))
+    }
+
+    // next `limit` positions in line on one side, for the risk desk
+    pub fn top_adl_candidates(&self, market_id: MarketId, side: Side, limit: usize) -> Result<Vec<AdlRank>, EngineError> {
+        let market = self
+            .markets
+            .get(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        Ok(market.adl_ranking.side(side).iter().take(limit).cloned().collect())
+    }
+
+    // closes up to `size` of the opposite side at `price`, highest score first. returns how
+    // much of the bankrupt position found a counterparty
+    pub(super) fn auto_deleverage(
+        &mut self,
+        bankrupt: AccountId,
+        market_id: MarketId,
+        bankrupt_side: Side,
+        size: Decimal,
+        price: Price,
+    ) -> Decimal {
+        let Some(mark_price) = self.markets.get(&market_id).and_then(|m| m.mark_price) else {
+            return Decimal::ZERO;
+        };
+        let positions: Vec<(AccountId, Position)> = self
+            .position_holders(market_id)
+            .filter(|(id, _, _)| *id != bankrupt)
+            .map(|(id, _, position)| (id, position.clone()))
+            .collect();
+        let candidates = rank_adl_candidates(positions, bankrupt_side.opposite(), mark_price);
+        let config = self.markets[&market_id].config.clone();
+
+        let mut left = size;
+        // only accounts actually closed count toward the round's cap
+        let mut closed_accounts = 0;
+        for candidate in &candidates {
+            if left <= Decimal::ZERO || closed_accounts >= self.config.adl.max_accounts_per_round {
+                break;
+            }
+            let position = &candidate.position;
+            let close_size = position.size.abs().min(left);
+
+            // the same as a fill against the bankrupt position: a reduce, never a flip
+            if self
+                .update_position_for_fill(candidate.account_id, &config, bankrupt_side, close_size, price)
+                .is_err()
+            {
+                continue;
+            }
+            self.settle_debt(candidate.account_id);
+            self.refresh_order_margin(candidate.account_id, market_id);
+            left -= close_size;
+            closed_accounts += 1;
+
+            let closed = SignedSize::new(if position.size.is_long() { close_size } else { -close_size });
+            self.emit_event(EventPayload::AutoDeleverage(AutoDeleverageEvent {
+                market_id,
+                account_id: candidate.account_id,
+                bankrupt_account: bankrupt,
+                size: closed,
+                price,
+                realized_pnl: Quote::new(closed.value() * (price.value() - position.entry_price.value())),
+            }));
+        }
+
+        size - left
+    }
+
+    // 6.2.3: takes `loss` out of the profit of every position on the other side of this market,
+    // pro rata. returns what was collected, anything beyond their profit stays uncovered
+    pub(super) fn socialize_loss(
+        &mut self,
+        bankrupt: AccountId,
+        market_id: MarketId,
+        bankrupt_side: Side,
+        loss: Quote,
+    ) -> Quote {
+        let Some(mark_price) = self.markets.get(&market_id).and_then(|m| m.mark_price) else {
+            return Quote::zero();
+        };
+        let profits: Vec<(AccountId, Quote, Quote)> = self
+            .position_holders(market_id)
+            .filter(|(id, _, _)| *id != bankrupt)
+            .filter_map(|(id, account, position)| {
+                if position.side() != Some(bankrupt_side.opposite()) {
+                    return None;
+                }
+                let unrealized = position.unrealized_pnl(mark_price).value().max(Decimal::ZERO);
+                // what the position already realized in this market and is still in the balance
+                let realized = position.realized_pnl.value().min(account.balance.value()).max(Decimal::ZERO);
+                Some((id, Quote::new(unrealized), Quote::new(realized)))
+            })
+            .collect();
+
+        let mut collected = Quote::zero();
+        let now = self.current_time;
+        for (account_id, unrealized, realized) in socialize_haircuts(&profits, loss) {
+            let account = self.accounts.get_mut(&account_id).unwrap();
+            // open profit comes off the entry price and settlement pays it over now, so the
+            // position realizes that much less when it closes
+            if let Some(position) = account.get_position_mut(market_id) {
+                let shift = unrealized.value() / position.size.abs();
+                let entry = if position.size.is_long() {
+                    position.entry_price.value() + shift
+                } else {
+                    position.entry_price.value() - shift
+                };
+                position.entry_price = Price::new_unchecked(entry);
+                position.realized_pnl = position.realized_pnl.sub(realized);
+            }
+            account.balance = account.balance.sub(realized);
+            account.realized_pnl = account.realized_pnl.sub(realized);
+            account.loss_haircuts.push(LossHaircut {
+                market_id,
+                bankrupt_account: bankrupt,
+                unrealized,
+                realized,
+                timestamp: now,
+            });
+
+            let to = LedgerAccount::Trader(bankrupt);
+            self.post(LedgerAccount::Settlement(market_id), to, unrealized, EntryKind::ProfitHaircut);
+            self.post(LedgerAccount::Trader(account_id), to, realized, EntryKind::ProfitHaircut);
+            self.refresh_order_margin(account_id, market_id);
+            collected = collected.add(unrealized).add(realized);
+
+            self.emit_event(EventPayload::LossHaircut(LossHaircutEvent {
+                market_id,
+                account_id,
+                bankrupt_account: bankrupt,
+                unrealized,
+                realized,
+            }));
+        }
+        collected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, EngineConfig};
+    use crate::events::EventPayload;
+    use crate::liquidation::BadDebtMode;
+    use crate::market::MarketConfig;
+    use crate::order::TimeInForce;
+    use crate::types::{AccountId, Leverage, MarketId, Price, Quote, Side};
+    use rust_decimal_macros::dec;
+
+    // buyer long 1 BTC at 10x against two shorts, the 10x one ranked first
+    fn setup_two_shorts(config: EngineConfig) -> (Engine, [AccountId; 3]) {
+        let mut engine = Engine::new(config);
+        engine.add_market(MarketConfig::btc_perp());
+        let market = MarketId(1);
+
+        let buyer = engine.create_account();
+        let big_short = engine.create_account();
+        let small_short = engine.create_account();
+        for (account, amount) in [(buyer, dec!(10000)), (big_short, dec!(100000)), (small_short, dec!(100000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine.set_leverage(big_short, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine.set_leverage(small_short, market, Leverage::new(dec!(2)).unwrap()).unwrap();
+        for (short, size) in [(big_short, dec!(0.6)), (small_short, dec!(0.4))] {
+            engine
+                .place_limit_order(short, market, Side::Short, size, Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+                .unwrap();
+        }
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+        (engine, [buyer, big_short, small_short])
+    }
+
+    #[test]
+    fn uncovered_debt_deleverages_profitable_shorts_at_bankruptcy() {
+        let (mut engine, [_, big_short, small_short]) = setup_two_shorts(EngineConfig::default());
+        let market = MarketId(1);
+
+        // gap through bankruptcy (45000) with no book, vault or insurance behind it
+        engine.update_index_price(market, Price::new_unchecked(dec!(40000))).unwrap();
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+        assert_eq!(result.adl_size, dec!(1));
+        assert_eq!(result.backstop_size, dec!(0));
+        assert_eq!(result.bad_debt.value(), dec!(0));
+
+        // the 10x short ranks first and goes whole, the 2x short covers the rest
+        let events: Vec<_> = engine
+            .events()
+            .iter()
+            .filter_map(|e| match &e.payload {
+                EventPayload::AutoDeleverage(adl) => Some(adl.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].account_id, events[0].size.value()), (big_short, dec!(-0.6)));
+        assert_eq!((events[1].account_id, events[1].size.value()), (small_short, dec!(-0.4)));
+        assert_eq!(events[0].price.value(), dec!(45000));
+        assert_eq!(events[0].realized_pnl.value(), dec!(3000));
+
+        let state = engine.get_market(market).unwrap();
+        assert_eq!(state.open_interest_long, dec!(0));
+        assert_eq!(state.open_interest_short, dec!(0));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn round_cap_leaves_the_rest_for_the_next_check() {
+        let mut config = EngineConfig::default();
+        config.adl.max_accounts_per_round = 1;
+        let (mut engine, [buyer, big_short, small_short]) = setup_two_shorts(config);
+        let market = MarketId(1);
+
+        // one account per round: the 10x short goes whole and the rest stays with the buyer
+        engine.update_index_price(market, Price::new_unchecked(dec!(40000))).unwrap();
+        let first = engine.check_liquidations(market).unwrap().remove(0);
+        assert_eq!(first.adl_size, dec!(0.6));
+        assert_eq!(first.remaining_size.value(), dec!(0.4));
+        assert!(engine.get_account(big_short).unwrap().get_position(market).is_none());
+        let state = engine.get_market(market).unwrap();
+        assert_eq!(state.open_interest_long, dec!(0.4));
+        assert_eq!(state.open_interest_short, dec!(0.4));
+
+        let second = engine.check_liquidations(market).unwrap().remove(0);
+        assert_eq!(second.adl_size, dec!(0.4));
+        assert!(second.remaining_size.is_zero());
+        assert!(engine.get_account(buyer).unwrap().get_position(market).is_none());
+        assert!(engine.get_account(small_short).unwrap().get_position(market).is_none());
+        let state = engine.get_market(market).unwrap();
+        assert_eq!(state.open_interest_long, state.open_interest_short);
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn shortfall_under_trigger_goes_to_the_book_before_deleveraging() {
+        let mut config = EngineConfig::default();
+        config.adl.min_trigger_amount = Quote::new(dec!(10000));
+        let (mut engine, [buyer, big_short, small_short]) = setup_two_shorts(config);
+        let market = MarketId(1);
+
+        let bidder = engine.create_account();
+        engine.deposit(bidder, Quote::new(dec!(100000))).unwrap();
+        engine
+            .place_limit_order(bidder, market, Side::Long, dec!(1), Price::new_unchecked(dec!(40000)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(40000))).unwrap();
+
+        // the losing long isn't in line, the profitable shorts are
+        assert!(engine.top_adl_candidates(market, Side::Long, 10).unwrap().is_empty());
+        assert_eq!(engine.adl_quantile(buyer, market), 0);
+        assert_eq!(engine.top_adl_candidates(market, Side::Short, 1).unwrap()[0].account_id, big_short);
+        assert!(matches!(
+            engine.top_adl_candidates(MarketId(9), Side::Short, 1),
+            Err(crate::engine::EngineError::MarketNotFound(_))
+        ));
+
+        // 5000 short against a 10000 trigger: the bid under bankruptcy takes it, not the shorts
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+        assert!(result.adl_size.is_zero());
+        assert_eq!(result.liquidation_price.value(), dec!(40000));
+        assert_eq!(result.bad_debt.value(), dec!(5000));
+        assert!(!engine.events().iter().any(|e| matches!(e.payload, EventPayload::AutoDeleverage(_))));
+        for short in [big_short, small_short] {
+            assert!(engine.get_account(short).unwrap().get_position(market).is_some());
+        }
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn socializing_market_haircuts_profits_instead_of_closing() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let mut config = MarketConfig::btc_perp();
+        config.liquidation_params.bad_debt_mode = BadDebtMode::Socialize;
+        engine.add_market(config);
+        let market = MarketId(1);
+
+        let buyer = engine.create_account();
+        let big_short = engine.create_account();
+        let small_short = engine.create_account();
+        for (account, amount) in [(buyer, dec!(10000)), (big_short, dec!(100000)), (small_short, dec!(100000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        for (short, size) in [(big_short, dec!(0.6)), (small_short, dec!(0.4))] {
+            engine
+                .place_limit_order(short, market, Side::Short, size, Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+                .unwrap();
+        }
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+
+        // closed into a bid at 40000, 5000 under the bankruptcy price
+        let bidder = engine.create_account();
+        engine.deposit(bidder, Quote::new(dec!(100000))).unwrap();
+        engine
+            .place_limit_order(bidder, market, Side::Long, dec!(1), Price::new_unchecked(dec!(40000)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(40000))).unwrap();
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+        assert_eq!(result.adl_size, dec!(0));
+        assert!(result.bad_debt.value() > dec!(0));
+
+        // both shorts stay open and give up the same share of their profit
+        let big = engine.get_account(big_short).unwrap();
+        let small = engine.get_account(small_short).unwrap();
+        assert_eq!(big.get_position(market).unwrap().size.value(), dec!(-0.6));
+        assert_eq!(small.get_position(market).unwrap().size.value(), dec!(-0.4));
+        let big_cut = big.loss_haircuts[0].total().value();
+        let small_cut = small.loss_haircuts[0].total().value();
+        assert_eq!(big_cut + small_cut, result.bad_debt.value());
+        assert_eq!(big_cut * dec!(4), small_cut * dec!(6));
+        assert_eq!(big.loss_haircuts[0].bankrupt_account, buyer);
+
+        let mark = engine.get_market(market).unwrap().mark_price.unwrap();
+        let profit = big.get_position(market).unwrap().unrealized_pnl(mark).value();
+        assert_eq!((profit + big_cut).round_dp(8), dec!(0.6) * (dec!(50000) - mark.value()));
+        assert_eq!(
+            engine
+                .events()
+                .iter()
+                .filter(|e| matches!(e.payload, EventPayload::LossHaircut(_)))
+                .count(),
+            2
+        );
+
+        let report = engine.conservation_report();
+        assert_eq!(report.socialized_loss.value(), dec!(0));
+        assert!(report.is_conserved());
+
+        // closing later realizes only what the haircut left
+        let seller = engine.create_account();
+        engine.deposit(seller, Quote::new(dec!(100000))).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(0.6), mark, TimeInForce::GTC)
+            .unwrap();
+        let before = engine.get_account(big_short).unwrap().realized_pnl.value();
+        engine.place_market_order(big_short, market, Side::Long, dec!(0.6)).unwrap();
+        let realized = engine.get_account(big_short).unwrap().realized_pnl.value() - before;
+        assert_eq!(realized.round_dp(8), profit.round_dp(8));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn indicator_follows_the_mark() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        let market = MarketId(1);
+
+        let buyer = engine.create_account();
+        let big_short = engine.create_account();
+        let small_short = engine.create_account();
+        for account in [buyer, big_short, small_short] {
+            engine.deposit(account, Quote::new(dec!(100000))).unwrap();
+        }
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(big_short, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine.set_leverage(small_short, market, Leverage::new(dec!(2)).unwrap()).unwrap();
+        for short in [big_short, small_short] {
+            engine
+                .place_limit_order(short, market, Side::Short, dec!(0.5), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+                .unwrap();
+        }
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+
+        // price falls: the shorts are in profit and queue up, the long is out
+        engine.update_index_price(market, Price::new_unchecked(dec!(48000))).unwrap();
+        assert_eq!(engine.adl_quantile(big_short, market), 5);
+        assert_eq!(engine.adl_quantile(small_short, market), 3);
+        assert_eq!(engine.adl_quantile(buyer, market), 0);
+        let top = engine.top_adl_candidates(market, Side::Short, 1).unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].account_id, big_short);
+
+        // and the other way round once it rallies
+        engine.update_index_price(market, Price::new_unchecked(dec!(52000))).unwrap();
+        assert_eq!(engine.adl_quantile(buyer, market), 5);
+        assert_eq!(engine.adl_quantile(big_short, market), 0);
+        assert!(engine.top_adl_candidates(market, Side::Short, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn socialized_share_counts_only_profit_in_the_bankrupt_market() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let mut config = MarketConfig::btc_perp();
+        config.liquidation_params.bad_debt_mode = BadDebtMode::Socialize;
+        engine.add_market(config);
+        engine.add_market(MarketConfig {
+            id: MarketId(2),
+            name: "ETH-PERP".to_string(),
+            base_asset: "ETH".to_string(),
+            ..MarketConfig::btc_perp()
+        });
+        let (btc, eth) = (MarketId(1), MarketId(2));
+
+        let buyer = engine.create_account();
+        let fresh_short = engine.create_account();
+        let eth_winner = engine.create_account();
+        let maker = engine.create_account();
+        for (account, amount) in [(buyer, dec!(10000)), (fresh_short, dec!(100000)), (eth_winner, dec!(100000)), (maker, dec!(1000000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+
+        // eth_winner banks 5000 in ETH before going short BTC
+        engine.update_index_price(eth, Price::new_unchecked(dec!(3000))).unwrap();
+        engine
+            .place_limit_order(maker, eth, Side::Short, dec!(10), Price::new_unchecked(dec!(3000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(eth_winner, eth, Side::Long, dec!(10)).unwrap();
+        engine.update_index_price(eth, Price::new_unchecked(dec!(3500))).unwrap();
+        engine
+            .place_limit_order(maker, eth, Side::Long, dec!(10), Price::new_unchecked(dec!(3500)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(eth_winner, eth, Side::Short, dec!(10)).unwrap();
+        let eth_profit = engine.get_account(eth_winner).unwrap().realized_pnl.value();
+        assert!(eth_profit > dec!(4000));
+
+        engine.update_index_price(btc, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, btc, Leverage::new(dec!(10)).unwrap()).unwrap();
+        for short in [fresh_short, eth_winner] {
+            engine
+                .place_limit_order(short, btc, Side::Short, dec!(0.5), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+                .unwrap();
+        }
+        engine.place_market_order(buyer, btc, Side::Long, dec!(1)).unwrap();
+
+        // 5000 of bad debt against 5000 of open profit on each short
+        engine
+            .place_limit_order(maker, btc, Side::Long, dec!(1), Price::new_unchecked(dec!(40000)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(btc, Price::new_unchecked(dec!(40000))).unwrap();
+        let result = engine.check_liquidations(btc).unwrap().remove(0);
+        assert_eq!(result.bad_debt.value(), dec!(5000));
+
+        // the ETH profit isn't on the line: both shorts give up the same half of their BTC profit
+        let fresh = engine.get_account(fresh_short).unwrap().loss_haircuts[0].clone();
+        let winner = engine.get_account(eth_winner).unwrap().loss_haircuts[0].clone();
+        assert_eq!(fresh.unrealized.value(), dec!(2500));
+        assert_eq!(winner.unrealized.value(), dec!(2500));
+        assert!(winner.realized.value().is_zero());
+        assert_eq!(engine.get_account(eth_winner).unwrap().realized_pnl.value(), eth_profit);
+        assert!(engine.conservation_report().is_conserved());
+    }
+}
@@ -0,0 +1,239 @@
+// 8.10: backstop vault (16.0). depositors buy in and redeem at share value, the vault takes
+// over liquidations the book couldn't fill and unwinds them through the book a step at a time.
+
+use super::core::Engine;
+use super::results::{EngineError, OrderResult};
+use crate::account::AccountError;
+use crate::events::{BackstopDepositEvent, BackstopTakeoverEvent, BackstopWithdrawalEvent, EventPayload};
+use crate::ledger::{EntryKind, LedgerAccount};
+use crate::order::TimeInForce;
+use crate::types::{AccountId, MarketId, Price, Quote, Side, SignedSize};
+use rust_decimal::Decimal;
+
+impl Engine {
+    pub fn backstop_account(&self) -> AccountId {
+        self.backstop.account_id
+    }
+
+    // balance plus the vault's positions at mark. errors while any of its markets has no mark
+    pub fn backstop_equity(&self) -> Result<Quote, EngineError> {
+        Ok(self.account_metrics(self.backstop.account_id)?.total_equity)
+    }
+
+    pub fn backstop_shares(&self, depositor: AccountId) -> Decimal {
+        self.backstop.shares_of(depositor)
+    }
+
+    // 16.2: move free balance into the vault for shares at the current share value
+    pub fn deposit_to_backstop(&mut self, depositor: AccountId, amount: Quote) -> Result<Decimal, EngineError> {
+        if amount.value() <= Decimal::ZERO {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        let vault = self.backstop.account_id;
+        if depositor == vault {
+            return Err(EngineError::AccountNotFound(depositor));
+        }
+
+        let available = self.withdrawable_balance(depositor)?;
+        if amount.value() > available.value() {
+            return Err(EngineError::Account(AccountError::InsufficientFreeCollateral {
+                requested: amount,
+                available,
+            }));
+        }
+        let shares = self
+            .backstop
+            .shares_for_deposit(amount, self.backstop_equity()?)
+            .ok_or(EngineError::BackstopInsolvent)?;
+
+        let account = self.accounts.get_mut(&depositor).unwrap();
+        account.balance = account.balance.sub(amount);
+        let vault_account = self.accounts.get_mut(&vault).unwrap();
+        vault_account.balance = vault_account.balance.add(amount);
+        self.post(LedgerAccount::Trader(depositor), LedgerAccount::Trader(vault), amount, EntryKind::BackstopDeposit);
+        self.backstop.mint(depositor, shares);
+
+        self.emit_event(EventPayload::BackstopDeposit(BackstopDepositEvent {
+            account_id: depositor,
+            amount,
+            shares,
+        }));
+        Ok(shares)
+    }
+
+    // redeem shares at the current share value, out of whatever the vault isn't using as margin
+    pub fn withdraw_from_backstop(&mut self, depositor: AccountId, shares: Decimal) -> Result<Quote, EngineError> {
+        if !self.accounts.contains_key(&depositor) {
+            return Err(EngineError::AccountNotFound(depositor));
+        }
+        let held = self.backstop.shares_of(depositor);
+        if shares <= Decimal::ZERO || shares > held {
+            return Err(EngineError::InsufficientShares { requested: shares, available: held });
+        }
+
+        let vault = self.backstop.account_id;
+        let amount = self.backstop.value_of(shares, self.backstop_equity()?);
+        let available = self.withdrawable_balance(vault)?;
+        if amount.value() > available.value() {
+            return Err(EngineError::Account(AccountError::InsufficientFreeCollateral {
+                requested: amount,
+                available,
+            }));
+        }
+
+        let vault_account = self.accounts.get_mut(&vault).unwrap();
+        vault_account.balance = vault_account.balance.sub(amount);
+        let account = self.accounts.get_mut(&depositor).unwrap();
+        account.balance = account.balance.add(amount);
+        self.post(LedgerAccount::Trader(vault), LedgerAccount::Trader(depositor), amount, EntryKind::BackstopWithdrawal);
+        self.backstop.burn(depositor, shares);
+
+        self.emit_event(EventPayload::BackstopWithdrawal(BackstopWithdrawalEvent {
+            account_id: depositor,
+            amount,
+            shares,
+        }));
+        Ok(amount)
+    }
+
+    // 16.3: the vault steps into `size` of the liquidated position at its bankruptcy price, as
+    // a fill against it would. false when the vault can't margin it
+    pub(super) fn backstop_takeover(
+        &mut self,
+        account_id: AccountId,
+        market_id: MarketId,
+        side: Side,
+        size: Decimal,
+        price: Price,
+    ) -> bool {
+        let vault = self.backstop.account_id;
+        // an unfunded vault has nothing to margin with, whatever the position's requirement
+        let funded = self.accounts.get(&vault).is_some_and(|a| a.free_collateral().value() > Decimal::ZERO);
+        if account_id == vault || !funded {
+            return false;
+        }
+        let config = self.markets[&market_id].config.clone();
+        if self.update_position_for_fill(vault, &config, side, size, price).is_err() {
+            return false;
+        }
+        self.settle_debt(vault);
+        self.refresh_order_margin(vault, market_id);
+
+        self.emit_event(EventPayload::BackstopTakeover(BackstopTakeoverEvent {
+            market_id,
+            account_id,
+            size: SignedSize::new(size * side.sign()),
+            price,
+        }));
+        true
+    }
+
+    // 16.1: work a slice of the vault's position back into the book as an IOC no more than
+    // unwind_slippage through mark. one step per interval, None when nothing is due
+    pub fn unwind_backstop(&mut self, market_id: MarketId) -> Result<Option<OrderResult>, EngineError> {
+        let market = self
+            .markets
+            .get(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        let mark_price = market.mark_price.ok_or(EngineError::NoMarkPrice(market_id))?;
+        let params = &self.config.backstop;
+
+        let vault = self.backstop.account_id;
+        let Some(position) = self.accounts[&vault].get_position(market_id) else {
+            return Ok(None);
+        };
+        if !self.backstop.unwind_due(market_id, self.current_time, params) {
+            return Ok(None);
+        }
+
+        let config = &market.config;
+        let size = position.size.abs();
+        let step = (size * params.unwind_fraction / config.lot_size).ceil() * config.lot_size;
+        let step = step.max(config.min_order_size).min(size);
+
+        let tick = config.tick_size;
+        let (side, limit) = if position.size.is_long() {
+            let floor = mark_price.value() * (Decimal::ONE - params.unwind_slippage);
+            (Side::Short, (floor / tick).ceil() * tick)
+        } else {
+            let cap = mark_price.value() * (Decimal::ONE + params.unwind_slippage);
+            (Side::Long, (cap / tick).floor() * tick)
+        };
+
+        self.backstop.record_unwind(market_id, self.current_time);
+        let result = self.place_limit_order(vault, market_id, side, step, Price::new_unchecked(limit), TimeInForce::IOC)?;
+        Ok(Some(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, EngineConfig};
+    use crate::events::EventPayload;
+    use crate::market::MarketConfig;
+    use crate::order::TimeInForce;
+    use crate::types::{Leverage, MarketId, Price, Quote, Side};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn vault_takes_over_unfilled_liquidation_and_unwinds_it() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        let market = MarketId(1);
+
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        let depositor = engine.create_account();
+        for (account, amount) in [(buyer, dec!(10000)), (seller, dec!(100000)), (depositor, dec!(50000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        assert_eq!(engine.deposit_to_backstop(depositor, Quote::new(dec!(50000))).unwrap(), dec!(50000));
+
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+
+        // empty book: the vault takes the 0.5709 partial close at the 45000 bankruptcy price and
+        // the rest stays with the account
+        engine.update_index_price(market, Price::new_unchecked(dec!(47300))).unwrap();
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+        assert_eq!(result.backstop_size, dec!(0.5709));
+        assert_eq!(result.liquidation_price.value(), dec!(45000));
+        assert_eq!(result.remaining_size.value(), dec!(0.4291));
+        assert_eq!(engine.get_account(buyer).unwrap().get_position(market).unwrap().size.value(), dec!(0.4291));
+
+        let vault = engine.backstop_account();
+        let taken = engine.get_account(vault).unwrap().get_position(market).unwrap().clone();
+        assert_eq!(taken.size.value(), dec!(0.5709));
+        assert_eq!(taken.entry_price.value(), dec!(45000));
+        let state = engine.get_market(market).unwrap();
+        assert_eq!(state.open_interest_long, state.open_interest_short);
+        assert!(engine
+            .events()
+            .iter()
+            .any(|e| matches!(e.payload, EventPayload::BackstopTakeover(_))));
+
+        // bought 2300 a unit under mark, which belongs to the depositor
+        assert_eq!(engine.backstop_equity().unwrap().value(), dec!(51313.07));
+
+        // a tenth of what's left goes back into the book per interval, within slippage of mark
+        engine
+            .place_limit_order(seller, market, Side::Long, dec!(5), Price::new_unchecked(dec!(47200)), TimeInForce::GTC)
+            .unwrap();
+        let step = engine.unwind_backstop(market).unwrap().unwrap();
+        assert_eq!(step.filled_size, dec!(0.0571));
+        assert!(engine.unwind_backstop(market).unwrap().is_none());
+        engine.advance_time(60_000);
+        assert!(engine.unwind_backstop(market).unwrap().is_some());
+        assert_eq!(engine.get_account(vault).unwrap().get_position(market).unwrap().size.value(), dec!(0.4624));
+
+        let before = engine.get_account(depositor).unwrap().balance.value();
+        let paid = engine.withdraw_from_backstop(depositor, dec!(10000)).unwrap();
+        assert_eq!(engine.get_account(depositor).unwrap().balance.value(), before + paid.value());
+        assert_eq!(engine.backstop_shares(depositor), dec!(40000));
+        assert!(engine.conservation_report().is_conserved());
+    }
+}
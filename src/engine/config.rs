@@ -1,12 +1,28 @@
-// 8.0.1: engine config. max events, verbose logging, fee schedule.
+// 8.0.1: engine config. max events, verbose logging, fee schedule, referral tiers,
+// portfolio margin, collateral haircuts, backstop vault, ADL.
 
-use crate::config::FeeConfig;
+use crate::adl::AdlParams;
+use crate::backstop::BackstopParams;
+use crate::config::{FeeConfig, IntegrationConfig};
+use crate::custody::CollateralType;
+use crate::liquidation::InsuranceParams;
+use crate::portfolio::PortfolioMarginParams;
+use crate::referral::ReferralConfig;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub struct EngineConfig {
     pub max_events: usize,
     pub verbose: bool,
     pub fees: FeeConfig,
+    pub referrals: ReferralConfig,
+    pub portfolio_margin: PortfolioMarginParams, // cross-market, so engine-wide
+    pub collateral_weights: HashMap<CollateralType, Decimal>, // haircut, 0.9 = 90% counts. unlisted = 0
+    pub auto_convert_collateral: bool, // sell collateral to cover a negative quote balance
+    pub backstop: BackstopParams,
+    pub adl: AdlParams,
+    pub insurance: InsuranceParams,
 }
 
 impl Default for EngineConfig {
@@ -15,6 +31,13 @@ impl Default for EngineConfig {
             max_events: 100_000,
             verbose: false,
             fees: FeeConfig::default(),
+            referrals: ReferralConfig::default(),
+            portfolio_margin: PortfolioMarginParams::default(),
+            collateral_weights: IntegrationConfig::default().collateral_weights,
+            auto_convert_collateral: true,
+            backstop: BackstopParams::default(),
+            adl: AdlParams::default(),
+            insurance: InsuranceParams::default(),
         }
     }
 }
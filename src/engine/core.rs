@@ -1,13 +1,30 @@
-// 8.0 engine/core.rs: main engine. holds all markets, accounts, insurance fund.
+// 8.0 engine/core.rs: main engine. holds all markets, accounts, insurance fund, treasury and
+// the fund ledger (14.0).
 
 use super::config::EngineConfig;
 use super::results::EngineError;
-use crate::account::Account;
-use crate::events::{DepositEvent, Event, EventId, EventPayload, WithdrawalEvent, WithdrawalRejectedEvent};
-use crate::liquidation::InsuranceFund;
+use crate::account::{
+    calculate_account_metrics_with, Account, AccountError, AccountMetrics, ConsolidatedMetrics,
+    MarginMode,
+};
+use crate::config::{FeeOverride, FeeTier};
+use crate::backstop::BackstopVault;
+use crate::custody::CollateralType;
+use crate::events::{
+    AccountDebtEvent, CollateralConvertedEvent, CollateralDepositEvent, CollateralWithdrawalEvent,
+    DebtWrittenOffEvent, DepositEvent, Event, EventId, EventPayload, FeeOverrideUpdatedEvent, InternalTransferEvent,
+    NegativeQuoteBalanceEvent, ReferralCodeCreatedEvent, ReferrerSetEvent, SubaccountCreatedEvent, WithdrawalEvent, WithdrawalRejectReason, WithdrawalRejectedEvent,
+};
+use crate::ledger::{ConservationReport, EntryKind, Ledger, LedgerAccount, LedgerMismatch};
+use crate::liquidation::{InsuranceFlowKind, InsuranceFund, LiquidationUnwind};
+use crate::margin::MarginParams;
 use crate::market::{MarketConfig, MarketState, MarketStatus};
-use crate::types::{AccountId, MarketId, Quote, Timestamp};
-use std::collections::HashMap;
+use crate::position::Position;
+use crate::portfolio::{calculate_portfolio_margin, PortfolioLeg, PortfolioMarginRequirement};
+use crate::referral::{ReferralProgram, ReferrerStats};
+use crate::types::{AccountId, Leverage, MarketId, Price, Quote, SignedSize, Timestamp};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
 
 /** 8.1: main engine struct. all state lives here */
 #[derive(Debug)]
@@ -16,22 +33,45 @@ pub struct Engine {
     pub(super) markets: HashMap<MarketId, MarketState>,
     pub(super) accounts: HashMap<AccountId, Account>,
     pub(super) insurance_fund: InsuranceFund,
+    pub(super) collateral_prices: HashMap<CollateralType, Price>, // oracle, non quote collateral
+    pub(super) margin_calls: HashSet<(AccountId, MarketId)>, // positions with an active call
+    pub(super) liquidation_unwinds: HashMap<(AccountId, MarketId), LiquidationUnwind>, // see 8.9.5
+    pub(super) referrals: ReferralProgram,
+    pub(super) backstop: BackstopVault,
+    pub(super) treasury: Quote, // net trading fees after rebates and referral cuts
+    pub(super) ledger: Ledger,
     pub(super) events: Vec<Event>,
     pub(super) next_event_id: u64,
     pub(super) next_order_id: u64,
+    pub(super) next_account_id: u64,
     pub(super) current_time: Timestamp,
 }
 
 impl Engine {
     pub fn new(config: EngineConfig) -> Self {
+        // the backstop vault trades from an account of its own, outside the user id range
+        let backstop = BackstopVault::new(AccountId(0));
+        let backstop_account = Account::new(backstop.account_id, Timestamp::from_millis(0));
         Self {
+            ledger: Ledger::new(config.max_events),
             config,
             markets: HashMap::new(),
-            accounts: HashMap::new(),
+            accounts: HashMap::from([(backstop.account_id, backstop_account)]),
             insurance_fund: InsuranceFund::new(Quote::zero()),
+            // stables start at par until an oracle update says otherwise
+            collateral_prices: HashMap::from([
+                (CollateralType::Usdc, Price::new_unchecked(Decimal::ONE)),
+                (CollateralType::Usdt, Price::new_unchecked(Decimal::ONE)),
+            ]),
+            margin_calls: HashSet::new(),
+            liquidation_unwinds: HashMap::new(),
+            referrals: ReferralProgram::default(),
+            backstop,
+            treasury: Quote::zero(),
             events: Vec::new(),
             next_event_id: 1,
             next_order_id: 1,
+            next_account_id: 1,
             current_time: Timestamp::from_millis(0),
         }
     }
@@ -81,13 +121,94 @@ impl Engine {
         Ok(())
     }
 
+    fn next_account_id(&mut self) -> AccountId {
+        let id = AccountId(self.next_account_id);
+        self.next_account_id += 1;
+        id
+    }
+
     pub fn create_account(&mut self) -> AccountId {
-        let id = AccountId(self.accounts.len() as u64 + 1);
+        let id = self.next_account_id();
         let account = Account::new(id, self.current_time);
         self.accounts.insert(id, account);
         id
     }
 
+    // 10.5: numbered subaccount under a master. one level deep
+    pub fn create_subaccount(&mut self, master_id: AccountId) -> Result<AccountId, EngineError> {
+        let master = self
+            .accounts
+            .get(&master_id)
+            .ok_or(EngineError::AccountNotFound(master_id))?;
+        if master.master.is_some() {
+            return Err(EngineError::NestedSubaccount(master_id));
+        }
+
+        let number = master.subaccounts.len() as u32 + 1;
+        let id = self.next_account_id();
+        let account = Account::new_subaccount(id, master_id, number, self.current_time);
+        self.accounts.insert(id, account);
+        self.accounts.get_mut(&master_id).unwrap().subaccounts.push(id);
+
+        self.emit_event(EventPayload::SubaccountCreated(SubaccountCreatedEvent {
+            master: master_id,
+            account_id: id,
+            subaccount_number: number,
+        }));
+        Ok(id)
+    }
+
+    // instant quote transfer within one master's group, limited to the sender's free collateral
+    pub fn transfer(&mut self, from: AccountId, to: AccountId, amount: Quote) -> Result<(), EngineError> {
+        if amount.value() <= Decimal::ZERO {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        let from_root = self
+            .accounts
+            .get(&from)
+            .ok_or(EngineError::AccountNotFound(from))?
+            .root();
+        let to_root = self.accounts.get(&to).ok_or(EngineError::AccountNotFound(to))?.root();
+        if from == to || from_root != to_root {
+            return Err(EngineError::TransferOutsideGroup { from, to });
+        }
+
+        let available = self.withdrawable_balance(from)?;
+        if amount.value() > available.value() {
+            return Err(EngineError::Account(AccountError::InsufficientFreeCollateral {
+                requested: amount,
+                available,
+            }));
+        }
+
+        let sender = self.accounts.get_mut(&from).unwrap();
+        sender.balance = sender.balance.sub(amount);
+        let receiver = self.accounts.get_mut(&to).unwrap();
+        receiver.balance = receiver.balance.add(amount);
+        self.post(LedgerAccount::Trader(from), LedgerAccount::Trader(to), amount, EntryKind::Transfer);
+
+        self.emit_event(EventPayload::InternalTransfer(InternalTransferEvent { from, to, amount }));
+        Ok(())
+    }
+
+    // master and all its subaccounts. a subaccount id resolves to its master
+    pub fn consolidated_metrics(&self, account_id: AccountId) -> Result<ConsolidatedMetrics, EngineError> {
+        let master_id = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?
+            .root();
+        let master = &self.accounts[&master_id];
+
+        let mut accounts = Vec::new();
+        let mut total_balance = Quote::zero();
+        for id in std::iter::once(master_id).chain(master.subaccounts.iter().copied()) {
+            accounts.push((id, self.account_metrics(id)?));
+            total_balance = total_balance.add(self.accounts[&id].balance);
+        }
+        Ok(ConsolidatedMetrics::new(master_id, accounts, total_balance))
+    }
+
     pub fn get_account(&self, account_id: AccountId) -> Option<&Account> {
         self.accounts.get(&account_id)
     }
@@ -100,6 +221,22 @@ impl Engine {
         self.accounts.iter()
     }
 
+    // accounts holding a position in the market. per-market scans (margin calls,
+    // liquidations, ADL) walk this instead of every account
+    pub(super) fn position_holders(
+        &self,
+        market_id: MarketId,
+    ) -> impl Iterator<Item = (AccountId, &Account, &Position)> + '_ {
+        self.markets
+            .get(&market_id)
+            .into_iter()
+            .flat_map(|market| market.position_holders.iter())
+            .filter_map(move |id| {
+                let account = self.accounts.get(id)?;
+                Some((*id, account, account.get_position(market_id)?))
+            })
+    }
+
     pub fn deposit(&mut self, account_id: AccountId, amount: Quote) -> Result<(), EngineError> {
         let account = self
             .accounts
@@ -108,6 +245,7 @@ impl Engine {
 
         account.deposit(amount);
         let new_balance = account.balance;
+        self.post(LedgerAccount::External, LedgerAccount::Trader(account_id), amount, EntryKind::Deposit);
 
         self.emit_event(EventPayload::Deposit(DepositEvent {
             account_id,
@@ -118,23 +256,360 @@ impl Engine {
         Ok(())
     }
 
-    // blocked if positions are open
-    pub fn withdraw(&mut self, account_id: AccountId, amount: Quote) -> Result<(), EngineError> {
+    // account equity and margin at current mark prices, using each market's own params.
+    // portfolio accounts report the scenario requirement (3.5) as margin used. both modes
+    // add what resting orders hold (10.6)
+    pub fn account_metrics(&self, account_id: AccountId) -> Result<AccountMetrics, EngineError> {
+        let account = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+        self.require_mark_prices(account)?;
+        Ok(self.metrics_for(account))
+    }
+
+    // metrics with non quote collateral folded into equity. markets without a mark are left out
+    pub(super) fn metrics_for(&self, account: &Account) -> AccountMetrics {
+        let mut metrics = calculate_account_metrics_with(account, |market_id| self.market_lookup(market_id));
+
+        let collateral_value = self.collateral_value(account);
+        metrics.total_equity = metrics.total_equity.add(collateral_value);
+        if account.is_portfolio_margin() {
+            metrics.margin_used = self
+                .portfolio_requirement(account, None)
+                .initial
+                .add(account.reserved_order_margin());
+        }
+        metrics.free_margin = Quote::new(metrics.total_equity.value() - metrics.margin_used.value());
+        metrics
+    }
+
+    pub fn portfolio_margin(&self, account_id: AccountId) -> Result<PortfolioMarginRequirement, EngineError> {
+        let account = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+        self.require_mark_prices(account)?;
+        Ok(self.portfolio_requirement(account, None))
+    }
+
+    // only with a flat book: the two modes keep collateral in different places
+    pub fn set_margin_mode(&mut self, account_id: AccountId, mode: MarginMode) -> Result<(), EngineError> {
+        let account = self
+            .accounts
+            .get_mut(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+
+        if account.margin_mode != mode && account.has_open_positions() {
+            return Err(EngineError::MarginModeLocked(account_id));
+        }
+        account.margin_mode = mode;
+        Ok(())
+    }
+
+    fn require_mark_prices(&self, account: &Account) -> Result<(), EngineError> {
+        for market_id in account.positions.keys() {
+            let market = self
+                .markets
+                .get(market_id)
+                .ok_or(EngineError::MarketNotFound(*market_id))?;
+            if market.effective_mark_price().is_none() {
+                return Err(EngineError::NoMarkPrice(*market_id));
+            }
+        }
+        Ok(())
+    }
+
+    // per-market inputs for calculate_account_metrics_with
+    pub(super) fn market_lookup(&self, market_id: MarketId) -> Option<(Price, Decimal, &MarginParams)> {
+        let market = self.markets.get(&market_id)?;
+        let mark_price = market.effective_mark_price()?;
+        Some((mark_price, market.funding_state.cumulative_funding, &market.config.margin_params))
+    }
+
+    // 3.5: scenario requirement for the account's book. `pending` layers a hypothetical
+    // fill on top for pre-trade checks. markets without a mark price are left out
+    pub(super) fn portfolio_requirement(
+        &self,
+        account: &Account,
+        pending: Option<(MarketId, SignedSize, Price)>,
+    ) -> PortfolioMarginRequirement {
+        let mut book: HashMap<MarketId, (Decimal, Leverage)> = account
+            .positions
+            .iter()
+            .map(|(market_id, position)| (*market_id, (position.size.value(), position.leverage)))
+            .collect();
+
+        if let Some((market_id, size, _)) = pending {
+            if let Some(market) = self.markets.get(&market_id) {
+                let leverage = account.leverage_for(market_id, market.config.margin_params.max_leverage);
+                book.entry(market_id).or_insert((Decimal::ZERO, leverage)).0 += size.value();
+            }
+        }
+
+        let legs: Vec<PortfolioLeg> = book
+            .into_iter()
+            .filter_map(|(market_id, (size, leverage))| {
+                let market = self.markets.get(&market_id)?;
+                let mark_price = market.effective_mark_price().or(match pending {
+                    Some((pending_market, _, price)) if pending_market == market_id => Some(price),
+                    _ => None,
+                })?;
+                Some(PortfolioLeg::new(
+                    market_id,
+                    SignedSize::new(size),
+                    mark_price,
+                    leverage,
+                    &market.config.margin_params,
+                ))
+            })
+            .collect();
+
+        calculate_portfolio_margin(&legs, &self.config.portfolio_margin)
+    }
+
+    pub fn update_collateral_price(&mut self, asset: CollateralType, price: Price) {
+        self.collateral_prices.insert(asset, price);
+    }
+
+    pub fn collateral_price(&self, asset: CollateralType) -> Option<Price> {
+        match asset {
+            CollateralType::Usd => Some(Price::new_unchecked(Decimal::ONE)),
+            _ => self.collateral_prices.get(&asset).copied(),
+        }
+    }
+
+    // 10.3: haircut value of the account's non quote collateral
+    pub fn collateral_value(&self, account: &Account) -> Quote {
+        account.collateral_value(|asset| {
+            let weight = self.config.collateral_weights.get(&asset).copied()?;
+            Some((self.collateral_price(asset)?, weight))
+        })
+    }
+
+    pub fn deposit_collateral(
+        &mut self,
+        account_id: AccountId,
+        asset: CollateralType,
+        amount: Decimal,
+    ) -> Result<(), EngineError> {
+        if asset == CollateralType::Usd {
+            return self.deposit(account_id, Quote::new(amount));
+        }
+        if amount <= Decimal::ZERO {
+            return Err(EngineError::InvalidAmount(Quote::new(amount)));
+        }
         let account = self
             .accounts
             .get_mut(&account_id)
             .ok_or(EngineError::AccountNotFound(account_id))?;
 
-        if let Err(e) = account.withdraw(amount) {
+        account.deposit_asset(asset, amount);
+        let new_amount = account.asset_balance(asset);
+
+        self.emit_event(EventPayload::CollateralDeposit(CollateralDepositEvent {
+            account_id,
+            asset,
+            amount,
+            new_amount,
+        }));
+        Ok(())
+    }
+
+    // haircut value of the withdrawal must fit in free margin, same as quote withdrawals
+    pub fn withdraw_collateral(
+        &mut self,
+        account_id: AccountId,
+        asset: CollateralType,
+        amount: Decimal,
+    ) -> Result<(), EngineError> {
+        if asset == CollateralType::Usd {
+            return self.withdraw(account_id, Quote::new(amount));
+        }
+        if amount <= Decimal::ZERO {
+            return Err(EngineError::InvalidAmount(Quote::new(amount)));
+        }
+        self.realize_account_funding(account_id);
+        let metrics = self.account_metrics(account_id)?;
+        let account = &self.accounts[&account_id];
+
+        let weight = self.config.collateral_weights.get(&asset).copied().unwrap_or(Decimal::ZERO);
+        let value = self
+            .collateral_price(asset)
+            .map_or(Decimal::ZERO, |price| amount * price.value() * weight);
+        // checked with or without open positions: debt is only collectable while the collateral
+        // backing it stays on the account
+        let withdrawable = metrics.free_margin.value() - self.funding_buffer(account).value();
+        if value > withdrawable {
+            return Err(EngineError::Account(AccountError::InsufficientFreeCollateral {
+                requested: Quote::new(value),
+                available: Quote::new(withdrawable.max(Decimal::ZERO)),
+            }));
+        }
+
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        account.withdraw_asset(asset, amount)?;
+        let new_amount = account.asset_balance(asset);
+
+        self.emit_event(EventPayload::CollateralWithdrawal(CollateralWithdrawalEvent {
+            account_id,
+            asset,
+            amount,
+            new_amount,
+        }));
+        Ok(())
+    }
+
+    // 10.4: funding, fees and losses settle in quote and can push balance below zero. that
+    // shortfall is account debt. cover it from other collateral (when auto-convert is on), then
+    // from isolated position collateral. what's left stays on the account and counts against
+    // equity until repaid, or is written off as bad debt once there is nothing left to seize
+    pub(super) fn settle_debt(&mut self, account_id: AccountId) {
+        let Some(account) = self.accounts.get(&account_id) else {
+            return;
+        };
+        if account.balance.value() >= Decimal::ZERO {
+            return;
+        }
+        let shortfall = account.debt();
+
+        let conversions = if self.config.auto_convert_collateral {
+            let valuations: HashMap<CollateralType, (Price, Decimal)> = account
+                .collateral_assets
+                .keys()
+                .filter_map(|asset| {
+                    let weight = self.config.collateral_weights.get(asset).copied()?;
+                    Some((*asset, (self.collateral_price(*asset)?, weight)))
+                })
+                .collect();
+            let account = self.accounts.get_mut(&account_id).unwrap();
+            account.convert_to_quote(Quote::zero(), |asset| valuations.get(&asset).copied())
+        } else {
+            Vec::new()
+        };
+
+        for conversion in conversions {
+            self.post(
+                LedgerAccount::External,
+                LedgerAccount::Trader(account_id),
+                conversion.proceeds,
+                EntryKind::CollateralConversion,
+            );
+            self.emit_event(EventPayload::CollateralConverted(CollateralConvertedEvent {
+                account_id,
+                asset: conversion.asset,
+                amount: conversion.amount,
+                price: conversion.price,
+                proceeds: conversion.proceeds,
+            }));
+        }
+
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        let from_position_collateral = account.cover_debt_from_positions();
+        let remaining = account.debt();
+        let uncollectable = !account.has_open_positions() && account.collateral_assets.is_empty();
+
+        if from_position_collateral.value() > Decimal::ZERO || remaining.value() > Decimal::ZERO {
+            self.emit_event(EventPayload::AccountDebt(AccountDebtEvent {
+                account_id,
+                shortfall,
+                from_position_collateral,
+                remaining,
+            }));
+        }
+        if remaining.value().is_zero() {
+            return;
+        }
+
+        if uncollectable {
+            self.accounts.get_mut(&account_id).unwrap().balance = Quote::zero();
+            let trader = LedgerAccount::Trader(account_id);
+            let covered = self.cover_from_insurance(trader, None, remaining);
+            self.post(LedgerAccount::SocializedLoss, trader, remaining.sub(covered), EntryKind::DebtSocialized);
+            self.emit_event(EventPayload::DebtWrittenOff(DebtWrittenOffEvent {
+                account_id,
+                debt_amount: remaining,
+                covered_by_insurance: covered,
+                socialized_loss: remaining.sub(covered),
+            }));
+        } else {
+            let account = &self.accounts[&account_id];
+            let collateral_value = self.collateral_value(account);
+            self.emit_event(EventPayload::NegativeQuoteBalance(NegativeQuoteBalanceEvent {
+                account_id,
+                balance: account.balance,
+                collateral_value,
+            }));
+        }
+    }
+
+    // free margin less a funding buffer, capped at the idle balance
+    pub fn withdrawable_balance(&self, account_id: AccountId) -> Result<Quote, EngineError> {
+        let metrics = self.account_metrics(account_id)?;
+        let account = &self.accounts[&account_id];
+        Ok(account.available_balance(&metrics, self.funding_buffer(account)))
+    }
+
+    // limited to free collateral when positions are open
+    pub fn withdraw(&mut self, account_id: AccountId, amount: Quote) -> Result<(), EngineError> {
+        // funding owed is realized first so it can't leave with the withdrawal
+        self.realize_account_funding(account_id);
+        let account = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+
+        let rejection = match self.account_metrics(account_id) {
+            Err(EngineError::NoMarkPrice(market_id)) => Some((
+                WithdrawalRejectReason::NoMarkPrice { market_id },
+                EngineError::NoMarkPrice(market_id),
+            )),
+            Err(e) => return Err(e),
+            Ok(_) if amount.value() > account.balance.value() => Some((
+                WithdrawalRejectReason::InsufficientBalance {
+                    balance: account.balance,
+                },
+                EngineError::Account(AccountError::InsufficientBalance {
+                    requested: amount,
+                    available: account.balance,
+                }),
+            )),
+            Ok(metrics) => {
+                let funding_buffer = self.funding_buffer(account);
+                let withdrawable = account.available_balance(&metrics, funding_buffer);
+                if amount.value() > withdrawable.value() {
+                    Some((
+                        WithdrawalRejectReason::InsufficientFreeCollateral {
+                            equity: metrics.total_equity,
+                            initial_margin: metrics.margin_used,
+                            funding_buffer,
+                            withdrawable,
+                        },
+                        EngineError::Account(AccountError::InsufficientFreeCollateral {
+                            requested: amount,
+                            available: withdrawable,
+                        }),
+                    ))
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some((reason, error)) = rejection {
             // Emit rejection event for audit
             self.emit_event(EventPayload::WithdrawalRejected(WithdrawalRejectedEvent {
                 account_id,
                 amount,
-                reason: e.to_string(),
+                reason,
             }));
-            return Err(EngineError::Account(e));
+            return Err(error);
         }
+
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        account.withdraw(amount)?;
         let new_balance = account.balance;
+        self.post(LedgerAccount::Trader(account_id), LedgerAccount::External, amount, EntryKind::Withdrawal);
 
         self.emit_event(EventPayload::Withdrawal(WithdrawalEvent {
             account_id,
@@ -163,18 +638,128 @@ impl Engine {
         // In production this would create a SharedPool and deposit into it.
         let market = self.markets.get_mut(&market_id).unwrap();
         market.pool_funding_fees += initial_pool_deposit.value();
+        self.post(
+            LedgerAccount::External,
+            LedgerAccount::LpPool(market_id),
+            initial_pool_deposit,
+            EntryKind::PoolDeposit,
+        );
         Ok(market_id)
     }
 
+    // 15.3: link an account to its referrer. no self referral (including within one
+    // master's group) and no cycles anywhere up the chain
     pub fn set_referrer(&mut self, account_id: AccountId, referrer_id: AccountId) -> Result<(), EngineError> {
-        if !self.accounts.contains_key(&referrer_id) {
-            return Err(EngineError::AccountNotFound(referrer_id));
+        let referrer_root = self
+            .accounts
+            .get(&referrer_id)
+            .ok_or(EngineError::AccountNotFound(referrer_id))?
+            .root();
+        let account = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+        if account.root() == referrer_root {
+            return Err(EngineError::SelfReferral(account_id));
         }
+
+        let mut next = Some(referrer_id);
+        while let Some(id) = next {
+            if id == account_id {
+                return Err(EngineError::ReferralCycle {
+                    account: account_id,
+                    referrer: referrer_id,
+                });
+            }
+            next = self.accounts.get(&id).and_then(|a| a.referrer);
+        }
+
+        if let Some(previous) = account.referrer {
+            self.referrals.remove_referee(previous, account_id);
+        }
+        self.accounts.get_mut(&account_id).unwrap().set_referrer(referrer_id);
+        self.referrals.add_referee(referrer_id, account_id);
+
+        self.emit_event(EventPayload::ReferrerSet(ReferrerSetEvent {
+            account_id,
+            referrer: referrer_id,
+        }));
+        Ok(())
+    }
+
+    // one code per account, unique ignoring case
+    pub fn create_referral_code(&mut self, account_id: AccountId, code: &str) -> Result<(), EngineError> {
+        if !self.accounts.contains_key(&account_id) {
+            return Err(EngineError::AccountNotFound(account_id));
+        }
+        if !self.config.referrals.is_valid_code(code) {
+            return Err(EngineError::InvalidReferralCode(code.to_string()));
+        }
+        if self.referrals.owner_of(code).is_some() {
+            return Err(EngineError::ReferralCodeTaken(code.to_string()));
+        }
+        if self.referrals.stats(account_id).is_some_and(|s| s.code.is_some()) {
+            return Err(EngineError::ReferralCodeExists(account_id));
+        }
+
+        self.referrals.register_code(account_id, code);
+        self.emit_event(EventPayload::ReferralCodeCreated(ReferralCodeCreatedEvent {
+            account_id,
+            code: code.to_string(),
+        }));
+        Ok(())
+    }
+
+    // sign up under a code. returns the referrer
+    pub fn apply_referral_code(&mut self, account_id: AccountId, code: &str) -> Result<AccountId, EngineError> {
+        let referrer = self
+            .referrals
+            .owner_of(code)
+            .ok_or_else(|| EngineError::InvalidReferralCode(code.to_string()))?;
+        self.set_referrer(account_id, referrer)?;
+        Ok(referrer)
+    }
+
+    pub fn referral_stats(&self, account_id: AccountId) -> Option<&ReferrerStats> {
+        self.referrals.stats(account_id)
+    }
+
+    // (rebate, referee discount) at the referrer's current tier
+    pub(super) fn referral_rates(&self, referrer: AccountId) -> (Decimal, Decimal) {
+        self.config
+            .referrals
+            .rates_for(self.referrals.referred_volume(referrer), self.config.fees.referral_fee_pct)
+    }
+
+    // 7.3: fee rates the account pays on its next fill, volume as of engine time
+    pub fn fee_tier(&self, account_id: AccountId) -> Result<FeeTier, EngineError> {
+        let account = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+        let fees = &self.config.fees;
+        let volume = account.volume_in_window(self.current_time, fees.volume_window_ms);
+        let tier = fees.tier_for(volume, account.fee_override.as_ref());
+        let discount = account.referrer.map_or(Decimal::ZERO, |referrer| self.referral_rates(referrer).1);
+        Ok(tier.with_referral_discount(discount))
+    }
+
+    // admin: pin an account to a VIP schedule, or None to return it to the volume tiers
+    pub fn set_fee_override(
+        &mut self,
+        account_id: AccountId,
+        fee_override: Option<FeeOverride>,
+    ) -> Result<(), EngineError> {
         let account = self
             .accounts
             .get_mut(&account_id)
             .ok_or(EngineError::AccountNotFound(account_id))?;
-        account.set_referrer(referrer_id);
+        account.fee_override = fee_override;
+
+        self.emit_event(EventPayload::FeeOverrideUpdated(FeeOverrideUpdatedEvent {
+            account_id,
+            fee_override,
+        }));
         Ok(())
     }
 
@@ -191,8 +776,74 @@ impl Engine {
         self.insurance_fund.balance
     }
 
+    // the global fund, behind every market's own
     pub fn fund_insurance(&mut self, amount: Quote) {
-        self.insurance_fund.deposit(amount);
+        self.insurance_fund.fund(amount);
+        self.insurance_fund
+            .record(InsuranceFlowKind::Deposit, None, amount, self.current_time);
+        self.post(LedgerAccount::External, LedgerAccount::InsuranceFund, amount, EntryKind::InsuranceDeposit);
+    }
+
+    pub fn treasury_balance(&self) -> Quote {
+        self.treasury
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub(super) fn post(&mut self, from: LedgerAccount, to: LedgerAccount, amount: Quote, kind: EntryKind) {
+        self.ledger.post(from, to, amount, kind, self.current_time);
+    }
+
+    // 14.3: check every holder's ledger balance against engine state and account for all
+    // deposited funds. settlement is left over from open positions, so it nets out as they close
+    pub fn conservation_report(&self) -> ConservationReport {
+        let mut mismatches = Vec::new();
+        let mut check = |account: LedgerAccount, actual: Quote| {
+            let ledger = self.ledger.balance(account);
+            if ledger.value() != actual.value() {
+                mismatches.push(LedgerMismatch { account, ledger, actual });
+            }
+        };
+
+        let mut trader_funds = Quote::zero();
+        for (account_id, account) in &self.accounts {
+            let collateral: Quote = account.positions.values().map(|p| p.collateral).sum();
+            let funds = account.balance.add(collateral);
+            check(LedgerAccount::Trader(*account_id), funds);
+            trader_funds = trader_funds.add(funds);
+        }
+
+        let mut lp_pool_fees = Quote::zero();
+        let mut insurance_fund = self.insurance_fund.balance;
+        for (market_id, market) in &self.markets {
+            let pool = Quote::new(market.pool_funding_fees);
+            check(LedgerAccount::LpPool(*market_id), pool);
+            lp_pool_fees = lp_pool_fees.add(pool);
+            check(LedgerAccount::MarketInsurance(*market_id), market.insurance_fund.balance);
+            insurance_fund = insurance_fund.add(market.insurance_fund.balance);
+        }
+        check(LedgerAccount::Treasury, self.treasury);
+        check(LedgerAccount::InsuranceFund, self.insurance_fund.balance);
+
+        let settlement = self
+            .ledger
+            .balances()
+            .filter(|(account, _)| matches!(account, LedgerAccount::Settlement(_)))
+            .map(|(_, balance)| balance)
+            .sum();
+
+        ConservationReport {
+            net_deposits: Quote::new(-self.ledger.balance(LedgerAccount::External).value()),
+            trader_funds,
+            treasury: self.treasury,
+            insurance_fund,
+            lp_pool_fees,
+            settlement,
+            socialized_loss: self.ledger.balance(LedgerAccount::SocializedLoss),
+            mismatches,
+        }
     }
 
     pub(super) fn emit_event(&mut self, payload: EventPayload) {
@@ -211,3 +862,681 @@ impl Engine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::CancelReason;
+    use crate::market::MarketConfig;
+    use crate::order::TimeInForce;
+    use crate::portfolio::CorrelationOffset;
+    use crate::referral::ReferralTier;
+    use crate::types::{Leverage, OrderId, Price, Side};
+    use rust_decimal_macros::dec;
+
+    // BTC and ETH markets with an 80% offset, trader on portfolio margin, maker with depth
+    fn setup_portfolio(deposit: Decimal) -> (Engine, AccountId, AccountId) {
+        let mut config = EngineConfig::default();
+        config.portfolio_margin.correlation_offsets.push(CorrelationOffset {
+            market_a: MarketId(1),
+            market_b: MarketId(2),
+            offset: dec!(0.8),
+        });
+        let mut engine = Engine::new(config);
+        engine.add_market(MarketConfig::btc_perp());
+        engine.add_market(MarketConfig {
+            id: MarketId(2),
+            name: "ETH-PERP".to_string(),
+            base_asset: "ETH".to_string(),
+            ..MarketConfig::btc_perp()
+        });
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        engine.update_index_price(MarketId(2), Price::new_unchecked(dec!(3125))).unwrap();
+
+        let trader = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(trader, Quote::new(deposit)).unwrap();
+        engine.deposit(maker, Quote::new(dec!(1000000))).unwrap();
+        engine.set_margin_mode(trader, MarginMode::Portfolio).unwrap();
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine
+            .place_limit_order(maker, MarketId(2), Side::Long, dec!(16), Price::new_unchecked(dec!(3125)), TimeInForce::GTC)
+            .unwrap();
+        (engine, trader, maker)
+    }
+
+    #[test]
+    fn portfolio_margin_credits_hedged_book() {
+        let (mut engine, trader, _) = setup_portfolio(dec!(5000));
+
+        // long 1 BTC alone: 8% scan = 4000 IM, nothing reserved in the position
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+        let account = engine.get_account(trader).unwrap();
+        assert!(account.get_position(MarketId(1)).unwrap().collateral.value().is_zero());
+        assert_eq!(account.balance.value(), dec!(4975));
+        assert_eq!(engine.account_metrics(trader).unwrap().margin_used.value(), dec!(4000));
+
+        // short 16 ETH against it: 8000 scan risk less 6400 offset credit
+        engine.place_market_order(trader, MarketId(2), Side::Short, dec!(16)).unwrap();
+        let requirement = engine.portfolio_margin(trader).unwrap();
+        assert_eq!(requirement.scan_risk.value(), dec!(8000));
+        assert_eq!(requirement.offset_credit.value(), dec!(6400));
+        assert_eq!(engine.account_metrics(trader).unwrap().margin_used.value(), dec!(1600));
+    }
+
+    #[test]
+    fn portfolio_pre_trade_check_rejects_order_over_requirement() {
+        let (mut engine, trader, _) = setup_portfolio(dec!(3000));
+
+        let result = engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+        assert!(result.fills.is_empty());
+        assert!(engine.get_account(trader).unwrap().positions.is_empty());
+        assert!(engine.events().iter().any(|e| matches!(
+            &e.payload,
+            EventPayload::OrderCanceled(ev) if matches!(ev.reason, CancelReason::InsufficientMargin)
+        )));
+    }
+
+    #[test]
+    fn portfolio_account_liquidated_on_account_equity() {
+        let (mut engine, trader, maker) = setup_portfolio(dec!(5000));
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Long, dec!(1), Price::new_unchecked(dec!(46000)), TimeInForce::GTC)
+            .unwrap();
+
+        // 2% drop: equity 3975 still above scenario MM of ~1960
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(49000))).unwrap();
+        assert!(engine.check_liquidations(MarketId(1)).unwrap().is_empty());
+
+        // 8% drop: equity 975 under MM of 1840, but the balance still covers loss and penalty
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(46000))).unwrap();
+        let liquidations = engine.check_liquidations(MarketId(1)).unwrap();
+        assert_eq!(liquidations.len(), 1);
+        let result = &liquidations[0];
+        assert!(result.bad_debt.value().is_zero());
+
+        // losses and penalty settle against the balance
+        let account = engine.get_account(trader).unwrap();
+        assert!(account.positions.is_empty());
+        assert_eq!(
+            account.balance.value(),
+            dec!(4975) + result.realized_pnl.value() - result.penalty.value()
+        );
+    }
+
+    #[test]
+    fn portfolio_liquidation_keeps_the_hedge_leg() {
+        let (mut engine, trader, maker) = setup_portfolio(dec!(5000));
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+        engine.place_market_order(trader, MarketId(2), Side::Short, dec!(8)).unwrap();
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Long, dec!(1), Price::new_unchecked(dec!(46000)), TimeInForce::GTC)
+            .unwrap();
+
+        // 8% BTC drop: equity ~962 under scenario MM of 1240. closing the ETH hedge would leave
+        // BTC's 3680 scan uncovered, closing BTC leaves ETH's 2000
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(46000))).unwrap();
+        let hedge_closed = engine.portfolio_requirement(
+            engine.get_account(trader).unwrap(),
+            Some((MarketId(2), SignedSize::new(dec!(8)), Price::new_unchecked(dec!(3125)))),
+        );
+        assert!(hedge_closed.maintenance > engine.portfolio_margin(trader).unwrap().maintenance);
+
+        // scanning the ETH market still closes the BTC leg
+        let liquidations = engine.check_liquidations(MarketId(2)).unwrap();
+        assert_eq!(liquidations.len(), 1);
+        assert_eq!(liquidations[0].market_id, MarketId(1));
+        assert_eq!(liquidations[0].position_size.value(), dec!(1));
+
+        let account = engine.get_account(trader).unwrap();
+        assert!(account.get_position(MarketId(1)).is_none());
+        assert_eq!(account.get_position(MarketId(2)).unwrap().size.value(), dec!(-8));
+        assert!(engine.check_liquidations(MarketId(2)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn margin_mode_locked_with_open_position() {
+        let (mut engine, trader, _) = setup_portfolio(dec!(5000));
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+
+        assert!(matches!(
+            engine.set_margin_mode(trader, MarginMode::Isolated),
+            Err(EngineError::MarginModeLocked(_))
+        ));
+    }
+
+    #[test]
+    fn withdraw_limited_to_free_collateral_with_open_position() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let trader = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(trader, Quote::new(dec!(20000))).unwrap();
+        engine.deposit(maker, Quote::new(dec!(100000))).unwrap();
+        engine
+            .set_leverage(trader, MarketId(1), Leverage::new(dec!(10)).unwrap())
+            .unwrap();
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+
+        // balance 14975 after 5k IM and 25 fee. at 48k: equity 17975, IM 4800
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(48000))).unwrap();
+        let withdrawable = engine.withdrawable_balance(trader).unwrap();
+        assert!(withdrawable.value() < dec!(13175));
+        assert!(withdrawable.value() > dec!(13000));
+
+        let result = engine.withdraw(trader, Quote::new(dec!(14000)));
+        assert!(matches!(
+            result,
+            Err(EngineError::Account(AccountError::InsufficientFreeCollateral { .. }))
+        ));
+        let rejected = engine.events().iter().rev().find_map(|e| match &e.payload {
+            EventPayload::WithdrawalRejected(ev) => Some(ev.reason.clone()),
+            _ => None,
+        });
+        assert!(matches!(
+            rejected,
+            Some(WithdrawalRejectReason::InsufficientFreeCollateral { .. })
+        ));
+
+        engine.withdraw(trader, Quote::new(dec!(13000))).unwrap();
+        assert_eq!(engine.get_account(trader).unwrap().balance.value(), dec!(1975));
+    }
+
+    #[test]
+    fn btc_collateral_converted_to_fund_isolated_position() {
+        let mut config = EngineConfig::default();
+        config.collateral_weights.insert(CollateralType::Btc, dec!(0.9));
+        let mut engine = Engine::new(config);
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        engine.update_collateral_price(CollateralType::Btc, Price::new_unchecked(dec!(50000)));
+
+        let trader = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit_collateral(trader, CollateralType::Btc, dec!(0.2)).unwrap();
+        engine.deposit(maker, Quote::new(dec!(100000))).unwrap();
+        engine
+            .set_leverage(trader, MarketId(1), Leverage::new(dec!(10)).unwrap())
+            .unwrap();
+
+        // 0.2 BTC at a 90% weight backs 9000 of margin
+        assert_eq!(engine.account_metrics(trader).unwrap().total_equity.value(), dec!(9000));
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+
+        // 25 fee + 5000 IM sold out of BTC at its 45000 margin value, the sale rounded up a satoshi
+        let account = engine.get_account(trader).unwrap();
+        assert_eq!(account.get_position(MarketId(1)).unwrap().collateral.value(), dec!(5000));
+        assert_eq!(account.balance.value(), dec!(0.00015));
+        assert_eq!(account.asset_balance(CollateralType::Btc), dec!(0.08833333));
+        // the sale moves value from collateral to balance without changing equity, 9000 less the fee
+        assert_eq!(engine.collateral_value(account).value(), dec!(3974.99985));
+        assert_eq!(engine.account_metrics(trader).unwrap().total_equity.value(), dec!(8975));
+        assert!(engine
+            .events()
+            .iter()
+            .any(|e| matches!(e.payload, EventPayload::CollateralConverted(_))));
+    }
+
+    // long 1 BTC at 50k with 10x, then closed at 44.5k for a loss larger than the position's collateral
+    fn close_at_loss(auto_convert: bool) -> (Engine, AccountId) {
+        let mut config = EngineConfig {
+            auto_convert_collateral: auto_convert,
+            ..EngineConfig::default()
+        };
+        config.collateral_weights.insert(CollateralType::Eth, dec!(0.85));
+        let mut engine = Engine::new(config);
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        engine.update_collateral_price(CollateralType::Eth, Price::new_unchecked(dec!(3000)));
+
+        let trader = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(trader, Quote::new(dec!(5100))).unwrap();
+        engine.deposit_collateral(trader, CollateralType::Eth, dec!(1)).unwrap();
+        engine.deposit(maker, Quote::new(dec!(100000))).unwrap();
+        engine
+            .set_leverage(trader, MarketId(1), Leverage::new(dec!(10)).unwrap())
+            .unwrap();
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Long, dec!(1), Price::new_unchecked(dec!(44500)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(trader, MarketId(1), Side::Short, dec!(1)).unwrap();
+        (engine, trader)
+    }
+
+    #[test]
+    fn negative_quote_balance_covered_from_collateral() {
+        let (engine, trader) = close_at_loss(true);
+
+        // 75 + 5000 collateral - 5500 loss - 22.25 fee = -447.25, covered by ETH
+        let account = engine.get_account(trader).unwrap();
+        assert!(account.balance.value() >= dec!(0));
+        assert!(account.balance.value() < dec!(0.001));
+        assert!(account.asset_balance(CollateralType::Eth) < dec!(0.86));
+        assert!(!engine
+            .events()
+            .iter()
+            .any(|e| matches!(e.payload, EventPayload::NegativeQuoteBalance(_))));
+    }
+
+    #[test]
+    fn negative_quote_balance_flagged_without_conversion() {
+        let (engine, trader) = close_at_loss(false);
+
+        assert_eq!(engine.get_account(trader).unwrap().balance.value(), dec!(-447.25));
+        let flagged = engine.events().iter().find_map(|e| match &e.payload {
+            EventPayload::NegativeQuoteBalance(ev) => Some(ev.clone()),
+            _ => None,
+        });
+        assert_eq!(flagged.unwrap().collateral_value.value(), dec!(2550));
+    }
+
+    #[test]
+    fn collateral_backing_debt_cannot_be_withdrawn() {
+        let (mut engine, trader) = close_at_loss(false);
+        assert!(!engine.get_account(trader).unwrap().has_open_positions());
+
+        // 2550 of ETH margin value against 447.25 of debt
+        let err = engine.withdraw_collateral(trader, CollateralType::Eth, dec!(1)).unwrap_err();
+        assert!(matches!(
+            err,
+            EngineError::Account(AccountError::InsufficientFreeCollateral { .. })
+        ));
+        assert_eq!(engine.get_account(trader).unwrap().asset_balance(CollateralType::Eth), dec!(1));
+
+        // the part not needed to cover the debt can still leave
+        engine.withdraw_collateral(trader, CollateralType::Eth, dec!(0.5)).unwrap();
+        assert_eq!(engine.get_account(trader).unwrap().asset_balance(CollateralType::Eth), dec!(0.5));
+    }
+
+    #[test]
+    fn subaccounts_transfer_within_group_only() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let master = engine.create_account();
+        let outsider = engine.create_account();
+        engine.deposit(master, Quote::new(dec!(10000))).unwrap();
+
+        let sub1 = engine.create_subaccount(master).unwrap();
+        let sub2 = engine.create_subaccount(master).unwrap();
+        assert_eq!(engine.get_account(sub2).unwrap().subaccount_number, 2);
+        assert!(matches!(engine.create_subaccount(sub1), Err(EngineError::NestedSubaccount(_))));
+
+        engine.transfer(master, sub1, Quote::new(dec!(4000))).unwrap();
+        engine.transfer(sub1, sub2, Quote::new(dec!(1500))).unwrap();
+        assert!(matches!(
+            engine.transfer(master, outsider, Quote::new(dec!(100))),
+            Err(EngineError::TransferOutsideGroup { .. })
+        ));
+
+        let consolidated = engine.consolidated_metrics(sub2).unwrap();
+        assert_eq!(consolidated.master, master);
+        assert_eq!(consolidated.accounts.len(), 3);
+        assert_eq!(consolidated.total_balance.value(), dec!(10000));
+        assert_eq!(engine.get_account(sub1).unwrap().balance.value(), dec!(2500));
+    }
+
+    #[test]
+    fn subaccount_transfer_limited_to_free_collateral() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let master = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(maker, Quote::new(dec!(100000))).unwrap();
+        let strategy = engine.create_subaccount(master).unwrap();
+        engine.deposit(strategy, Quote::new(dec!(8000))).unwrap();
+        engine
+            .set_leverage(strategy, MarketId(1), Leverage::new(dec!(10)).unwrap())
+            .unwrap();
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(strategy, MarketId(1), Side::Long, dec!(1)).unwrap();
+
+        // 8000 - 5000 IM - 25 fee leaves 2975, less the funding buffer. the IM stays put
+        assert!(matches!(
+            engine.transfer(strategy, master, Quote::new(dec!(2975))),
+            Err(EngineError::Account(AccountError::InsufficientFreeCollateral { .. }))
+        ));
+        engine.transfer(strategy, master, Quote::new(dec!(2900))).unwrap();
+        assert_eq!(engine.get_account(master).unwrap().balance.value(), dec!(2900));
+        assert!(engine.get_account(master).unwrap().positions.is_empty());
+    }
+
+    fn setup_resting_bids() -> (Engine, AccountId, OrderId) {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let trader = engine.create_account();
+        engine.deposit(trader, Quote::new(dec!(10000))).unwrap();
+        engine
+            .set_leverage(trader, MarketId(1), Leverage::new(dec!(10)).unwrap())
+            .unwrap();
+
+        // two bids at 10x hold 4900 each
+        let first = engine
+            .place_limit_order(trader, MarketId(1), Side::Long, dec!(1), Price::new_unchecked(dec!(49000)), TimeInForce::GTC)
+            .unwrap();
+        engine
+            .place_limit_order(trader, MarketId(1), Side::Long, dec!(1), Price::new_unchecked(dec!(49000)), TimeInForce::GTC)
+            .unwrap();
+        (engine, trader, first.order_id)
+    }
+
+    #[test]
+    fn resting_orders_hold_margin_until_canceled() {
+        let (mut engine, trader, first) = setup_resting_bids();
+        let account = engine.get_account(trader).unwrap();
+        assert_eq!(account.reserved_order_margin().value(), dec!(9800));
+        assert_eq!(account.free_collateral().value(), dec!(200));
+
+        // a third bid would need 12250 held
+        let result = engine
+            .place_limit_order(trader, MarketId(1), Side::Long, dec!(0.5), Price::new_unchecked(dec!(49000)), TimeInForce::GTC)
+            .unwrap();
+        assert!(!result.is_posted);
+        assert!(engine.events().iter().any(|e| matches!(
+            &e.payload,
+            EventPayload::OrderCanceled(ev) if ev.order_id == result.order_id
+                && matches!(ev.reason, CancelReason::InsufficientMargin)
+        )));
+
+        assert!(matches!(
+            engine.withdraw(trader, Quote::new(dec!(1000))),
+            Err(EngineError::Account(AccountError::InsufficientFreeCollateral { .. }))
+        ));
+
+        engine.cancel_order(MarketId(1), first).unwrap();
+        assert_eq!(engine.get_account(trader).unwrap().reserved_order_margin().value(), dec!(4900));
+        engine.withdraw(trader, Quote::new(dec!(5000))).unwrap();
+    }
+
+    #[test]
+    fn filled_order_releases_its_hold() {
+        let (mut engine, trader, _) = setup_resting_bids();
+        let seller = engine.create_account();
+        engine.deposit(seller, Quote::new(dec!(100000))).unwrap();
+
+        engine.place_market_order(seller, MarketId(1), Side::Short, dec!(1)).unwrap();
+
+        // position took 4900 IM out of balance, the other bid still holds 4900
+        let account = engine.get_account(trader).unwrap();
+        assert_eq!(account.get_position(MarketId(1)).unwrap().size.value(), dec!(1));
+        assert_eq!(account.reserved_order_margin().value(), dec!(4900));
+        assert!(account.balance.value() > dec!(4900));
+
+        // an ask that only closes the long holds nothing extra
+        let result = engine
+            .place_limit_order(trader, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(52000)), TimeInForce::GTC)
+            .unwrap();
+        assert!(result.is_posted);
+        assert_eq!(engine.get_account(trader).unwrap().reserved_order_margin().value(), dec!(4900));
+    }
+
+    #[test]
+    fn market_order_cannot_spend_margin_held_for_resting_orders() {
+        let (mut engine, trader, first) = setup_resting_bids();
+        let seller = engine.create_account();
+        engine.deposit(seller, Quote::new(dec!(100000))).unwrap();
+        engine
+            .place_limit_order(seller, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+
+        // 0.5 at 10x needs 2500, only 200 isn't held for the bids
+        let result = engine.place_market_order(trader, MarketId(1), Side::Long, dec!(0.5)).unwrap();
+        assert!(result.filled_size.is_zero());
+        assert!(engine.events().iter().any(|e| matches!(
+            &e.payload,
+            EventPayload::OrderCanceled(ev) if ev.order_id == result.order_id
+                && matches!(ev.reason, CancelReason::InsufficientMargin)
+        )));
+        let account = engine.get_account(trader).unwrap();
+        assert!(account.get_position(MarketId(1)).is_none());
+        assert_eq!(account.free_collateral().value(), dec!(200));
+        let book = &engine.get_market(MarketId(1)).unwrap().order_book;
+        assert_eq!(book.best_ask().unwrap().value(), dec!(50000));
+
+        // releasing one bid frees enough
+        engine.cancel_order(MarketId(1), first).unwrap();
+        let result = engine.place_market_order(trader, MarketId(1), Side::Long, dec!(0.5)).unwrap();
+        assert_eq!(result.filled_size, dec!(0.5));
+        assert!(engine.get_account(trader).unwrap().free_collateral().value() >= Decimal::ZERO);
+    }
+
+    #[test]
+    fn taking_orders_checked_at_worst_fill_price_before_matching() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let trader = engine.create_account();
+        let seller = engine.create_account();
+        engine.deposit(trader, Quote::new(dec!(5100))).unwrap();
+        engine.deposit(seller, Quote::new(dec!(100000))).unwrap();
+        engine.set_leverage(trader, MarketId(1), Leverage::new(dec!(10)).unwrap()).unwrap();
+        for price in [dec!(50000), dec!(60000)] {
+            engine
+                .place_limit_order(seller, MarketId(1), Side::Short, dec!(0.5), Price::new_unchecked(price), TimeInForce::GTC)
+                .unwrap();
+        }
+        let canceled = |engine: &Engine, order_id: OrderId| {
+            engine.events().iter().any(|e| matches!(
+                &e.payload,
+                EventPayload::OrderCanceled(ev) if ev.order_id == order_id
+                    && matches!(ev.reason, CancelReason::InsufficientMargin)
+            ))
+        };
+
+        // 1 BTC clears at mark, but the sweep reaches 60000: 6000 IM against 5100
+        let result = engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+        assert!(result.fills.is_empty());
+        assert!(canceled(&engine, result.order_id));
+
+        // an IOC is checked at its limit before it touches the book
+        let result = engine
+            .place_limit_order(trader, MarketId(1), Side::Long, dec!(1), Price::new_unchecked(dec!(60000)), TimeInForce::IOC)
+            .unwrap();
+        assert!(result.fills.is_empty());
+        assert!(canceled(&engine, result.order_id));
+        let book = &engine.get_market(MarketId(1)).unwrap().order_book;
+        assert_eq!(book.best_ask().unwrap().value(), dec!(50000));
+        assert_eq!(book.depth_to_price(Side::Long, Price::new_unchecked(dec!(60000))), dec!(1));
+
+        let result = engine
+            .place_limit_order(trader, MarketId(1), Side::Long, dec!(0.5), Price::new_unchecked(dec!(50000)), TimeInForce::IOC)
+            .unwrap();
+        assert_eq!(result.filled_size, dec!(0.5));
+
+        // closing only reduces, so it isn't held to the sweep
+        engine
+            .place_limit_order(seller, MarketId(1), Side::Long, dec!(0.5), Price::new_unchecked(dec!(40000)), TimeInForce::GTC)
+            .unwrap();
+        let result = engine.place_market_order(trader, MarketId(1), Side::Short, dec!(0.5)).unwrap();
+        assert_eq!(result.filled_size, dec!(0.5));
+        assert!(engine.get_account(trader).unwrap().get_position(MarketId(1)).is_none());
+    }
+
+    fn last_fill_fee(engine: &Engine, account_id: AccountId) -> Decimal {
+        engine
+            .events()
+            .iter()
+            .rev()
+            .find_map(|e| match &e.payload {
+                EventPayload::Fill(fill) if fill.account_id == account_id => Some(fill.fee.value()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn volume_tier_discounts_fees_and_decays() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let taker = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(taker, Quote::new(dec!(500000))).unwrap();
+        engine.deposit(maker, Quote::new(dec!(500000))).unwrap();
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(21), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+
+        // first 1M of volume pays the full 5 bps
+        engine.place_market_order(taker, MarketId(1), Side::Long, dec!(20)).unwrap();
+        assert_eq!(last_fill_fee(&engine, taker), dec!(500));
+
+        // 10% tier from here on: 4.5 bps taker, 1.8 bps maker
+        let tier = engine.fee_tier(taker).unwrap();
+        assert_eq!(tier.discount_pct, 10);
+        engine.place_market_order(taker, MarketId(1), Side::Long, dec!(1)).unwrap();
+        assert_eq!(last_fill_fee(&engine, taker), dec!(22.5));
+        assert_eq!(last_fill_fee(&engine, maker), dec!(9));
+
+        engine.advance_time(31 * 24 * 60 * 60 * 1000);
+        assert_eq!(engine.fee_tier(taker).unwrap().discount_pct, 0);
+        assert_eq!(engine.fee_tier(taker).unwrap().volume_30d, dec!(0));
+
+        // VIP override wins regardless of volume
+        let vip = FeeOverride { maker_fee_bps: 0, taker_fee_bps: 1 };
+        engine.set_fee_override(taker, Some(vip)).unwrap();
+        let tier = engine.fee_tier(taker).unwrap();
+        assert!(tier.vip);
+        assert_eq!(tier.taker_fee_bps, dec!(1));
+        engine.set_fee_override(taker, None).unwrap();
+        assert!(!engine.fee_tier(taker).unwrap().vip);
+    }
+
+    #[test]
+    fn treasury_takes_net_fees_and_ledger_conserves() {
+        let mut config = EngineConfig::default();
+        config.fees.maker_fee_bps = -1;
+        let mut engine = Engine::new(config);
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let taker = engine.create_account();
+        let maker = engine.create_account();
+        let referrer = engine.create_account();
+        engine.deposit(taker, Quote::new(dec!(10000))).unwrap();
+        engine.deposit(maker, Quote::new(dec!(10000))).unwrap();
+        engine.set_referrer(taker, referrer).unwrap();
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(taker, MarketId(1), Side::Long, dec!(1)).unwrap();
+
+        // 25 taker fee, 2.5 referral cut, 5 maker rebate
+        assert_eq!(engine.treasury_balance().value(), dec!(17.5));
+        assert_eq!(engine.get_account(referrer).unwrap().balance.value(), dec!(2.5));
+
+        let kinds: Vec<EntryKind> = engine.ledger().entries().iter().map(|e| e.kind).collect();
+        assert!(kinds.contains(&EntryKind::MakerRebate));
+        assert!(kinds.contains(&EntryKind::ReferralReward));
+
+        engine.withdraw(referrer, Quote::new(dec!(2.5))).unwrap();
+        let report = engine.conservation_report();
+        assert!(report.is_conserved());
+        assert_eq!(report.net_deposits.value(), dec!(19997.5));
+        assert_eq!(report.treasury.value(), dec!(17.5));
+    }
+
+    #[test]
+    fn referral_tiers_discount_referee_and_pay_upstream() {
+        let mut config = EngineConfig::default();
+        config.referrals.tiers = vec![ReferralTier {
+            min_referred_volume: dec!(40000),
+            referrer_rebate_pct: dec!(0.15),
+            referee_discount_pct: dec!(0.05),
+        }];
+        let mut engine = Engine::new(config);
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let upstream = engine.create_account();
+        let referrer = engine.create_account();
+        let taker = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(taker, Quote::new(dec!(100000))).unwrap();
+        engine.deposit(maker, Quote::new(dec!(100000))).unwrap();
+        engine.create_referral_code(upstream, "up").unwrap_err();
+        engine.create_referral_code(upstream, "Upstream").unwrap();
+        engine.create_referral_code(referrer, "ref-1").unwrap();
+        assert_eq!(engine.apply_referral_code(referrer, "UPSTREAM").unwrap(), upstream);
+        engine.apply_referral_code(taker, "ref-1").unwrap();
+
+        let trade = |engine: &mut Engine| {
+            engine
+                .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+                .unwrap();
+            engine.place_market_order(taker, MarketId(1), Side::Long, dec!(1)).unwrap();
+        };
+
+        // base tier: 25 fee, 10% to the referrer, 2% upstream
+        trade(&mut engine);
+        assert_eq!(last_fill_fee(&engine, taker), dec!(25));
+        assert_eq!(engine.get_account(referrer).unwrap().balance.value(), dec!(2.5));
+        assert_eq!(engine.get_account(upstream).unwrap().balance.value(), dec!(0.5));
+
+        // 50k referred crosses the tier: taker pays 5% less, referrer earns 15%
+        assert_eq!(engine.fee_tier(taker).unwrap().referral_discount_pct, dec!(0.05));
+        trade(&mut engine);
+        assert_eq!(last_fill_fee(&engine, taker), dec!(23.75));
+        assert_eq!(engine.get_account(referrer).unwrap().balance.value(), dec!(6.0625));
+        assert_eq!(engine.get_account(upstream).unwrap().balance.value(), dec!(0.975));
+
+        let stats = engine.referral_stats(referrer).unwrap();
+        assert_eq!(stats.referees, vec![taker]);
+        assert_eq!(stats.referred_volume, dec!(100000));
+        assert_eq!(stats.total_earned.value(), dec!(6.0625));
+        assert_eq!(stats.history.len(), 2);
+        assert_eq!(engine.referral_stats(upstream).unwrap().history[0].level, 2);
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn referral_rejects_self_subaccounts_and_cycles() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let a = engine.create_account();
+        let b = engine.create_account();
+        let c = engine.create_account();
+        let a_sub = engine.create_subaccount(a).unwrap();
+
+        engine.create_referral_code(a, "alice").unwrap();
+        assert!(matches!(engine.create_referral_code(b, "ALICE"), Err(EngineError::ReferralCodeTaken(_))));
+        assert!(matches!(engine.create_referral_code(a, "alice2"), Err(EngineError::ReferralCodeExists(_))));
+        assert!(matches!(engine.apply_referral_code(b, "nobody"), Err(EngineError::InvalidReferralCode(_))));
+
+        assert!(matches!(engine.apply_referral_code(a, "alice"), Err(EngineError::SelfReferral(_))));
+        assert!(matches!(engine.apply_referral_code(a_sub, "alice"), Err(EngineError::SelfReferral(_))));
+
+        // a -> b -> c, then c -> a would close the loop
+        engine.set_referrer(b, a).unwrap();
+        engine.set_referrer(c, b).unwrap();
+        assert!(matches!(engine.set_referrer(a, c), Err(EngineError::ReferralCycle { .. })));
+        assert!(engine.get_account(a).unwrap().referrer.is_none());
+    }
+}
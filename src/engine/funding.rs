@@ -1,130 +1,483 @@
-// 8.8: funding rate settlement. LP fee split: payers pay full, receivers get (1 - lp_fee_fraction).
+// 8.8: funding settlement. the rate comes from the premium sampled through the window, lazy:
+// settle_funding advances the market index, positions realize against it when touched. LP fee split: payers pay full, receivers get (1 - lp_fee_fraction).
 
 use super::core::Engine;
 use super::results::{EngineError, FundingResult};
+use crate::account::{Account, AccountError};
 use crate::events::{EventPayload, FundingFeeCollectedEvent, FundingSettledEvent, OiUpdatedEvent};
-use crate::funding::{calculate_funding_payment, calculate_funding_rate, calculate_premium_index};
-use crate::types::{AccountId, MarketId, Quote, SignedSize};
+use crate::funding::{
+    calculate_funding_payment, calculate_funding_rate, calculate_impact_premium, FundingRecord, PredictedFunding,
+};
+use crate::ledger::{EntryKind, LedgerAccount};
+use crate::types::{AccountId, MarketId, Quote, Side};
 use rust_decimal::Decimal;
-use rust_decimal::prelude::ToPrimitive;
 
 impl Engine {
-    // settle funding for a market. paying side pays full rate,
-    // receiving side gets (1 - lp_fee_fraction). remainder accrues to pool.
+    // funding the account would owe next period if the window's premium so far holds.
+    // only the paying side counts, expected receipts are not lent against.
+    pub(super) fn funding_buffer(&self, account: &Account) -> Quote {
+        let mut buffer = Decimal::ZERO;
+
+        for (market_id, position) in &account.positions {
+            let Some(market) = self.markets.get(market_id) else {
+                continue;
+            };
+            let Some(mark_price) = market.mark_price else {
+                continue;
+            };
+
+            let premium = market.funding_state.premium_twap(self.current_time);
+            let rate = calculate_funding_rate(premium, &market.config.funding_params);
+            let payment = calculate_funding_payment(position.size, mark_price, rate);
+            if payment.value() > Decimal::ZERO {
+                buffer += payment.value();
+            }
+        }
+
+        Quote::new(buffer)
+    }
+
+    // 5.5: settle every funding period that has ended. O(1) per period: only the cumulative
+    // index moves, by the period's rate times mark, i.e. quote owed per unit of long.
+    // positions pick it up the next time they're touched (realize_funding). periods already
+    // settled are skipped, so calling this again before the next boundary is a no-op.
     pub fn settle_funding(&mut self, market_id: MarketId) -> Result<FundingResult, EngineError> {
         let market = self
             .markets
             .get(&market_id)
             .ok_or(EngineError::MarketNotFound(market_id))?;
 
-        let Some(mark_price) = market.mark_price else {
+        if market.mark_price.is_none() {
             return Err(EngineError::NoMarkPrice(market_id));
-        };
+        }
 
-        let Some(index_price) = market.index_price else {
+        if market.index_price.is_none() {
             return Err(EngineError::NoIndexPrice(market_id));
-        };
-
-        let premium = calculate_premium_index(mark_price, index_price);
-        let funding_rate = calculate_funding_rate(premium, &market.config.funding_params);
-
-        let elapsed_ms = self.current_time.as_millis() - market.funding_state.last_update.as_millis();
-        let funding_period_ms = (market.config.funding_params.period_hours * rust_decimal_macros::dec!(3600000))
-            .to_i64()
-            .unwrap_or(8 * 3600000);
-        let time_fraction = Decimal::from(elapsed_ms) / Decimal::from(funding_period_ms);
-        let prorated_rate = funding_rate * time_fraction;
-        let lp_fee_fraction = market.config.funding_params.lp_fee_fraction;
+        }
 
-        let mut total_long_payments = Decimal::ZERO;
-        let mut total_short_payments = Decimal::ZERO;
-        let mut account_payments: Vec<(AccountId, Quote, SignedSize)> = Vec::new();
+        // the book as of now prices the period that's just opened, not the ones that ended
+        self.record_premium_sample(market_id, true);
+        Ok(self.catch_up_funding(market_id))
+    }
 
-        // First pass: compute gross payments per account
-        for (account_id, account) in &self.accounts {
-            if let Some(position) = account.get_position(market_id) {
-                let payment = calculate_funding_payment(position.size, mark_price, prorated_rate);
+    // 5.9: settle closed periods one by one, each on its own samples and at the OI it ended
+    // with. nothing changes OI without catching up first, so that's the OI through the period
+    pub(super) fn catch_up_funding(&mut self, market_id: MarketId) -> FundingResult {
+        let now = self.current_time;
+        let market = self.markets.get_mut(&market_id).unwrap();
+        let params = market.config.funding_params.clone();
+        market.funding_state.roll_windows(now, &params);
 
-                if position.size.is_long() {
-                    total_long_payments += payment.value();
-                } else {
-                    total_short_payments += payment.value();
-                }
+        let long_oi = market.open_interest_long;
+        let short_oi = market.open_interest_short;
+        let mut result = FundingResult {
+            funding_rate: Decimal::ZERO,
+            total_long_payments: Quote::zero(),
+            total_short_payments: Quote::zero(),
+            lp_fee_collected: Quote::zero(),
+            accounts_affected: 0,
+            open_interest: long_oi.max(short_oi),
+            periods_settled: 0,
+        };
 
-                account_payments.push((*account_id, payment, position.size));
+        let state = &mut market.funding_state;
+        while let Some(window) = state.closed_windows.pop_front() {
+            if window.end <= state.last_update {
+                continue;
             }
-        }
 
-        // Determine the LP fee pool cut from the gross receiving side.
-        // Whichever side has negative total (receiving money), we skim lp_fee_fraction.
-        let gross_receiver_total = if total_long_payments > Decimal::ZERO {
-            // Longs are paying, shorts are receiving
-            total_short_payments.abs()
-        } else {
-            total_long_payments.abs()
-        };
-        let lp_fee_amount = gross_receiver_total * lp_fee_fraction;
-
-        // Second pass: apply payments. Payers pay full. Receivers get (1 - lp_fee_fraction).
-        for (account_id, payment, position_size) in &account_payments {
-            let is_receiver = payment.value() < Decimal::ZERO; // negative payment = receiving
-            let adjusted_payment = if is_receiver {
-                // Receiver gets less: they receive (1 - lp_fee_fraction) of gross
-                Quote::new(payment.value() * (Decimal::ONE - lp_fee_fraction))
+            // a period sampled from partway through (market launch) pays pro rata
+            let time_fraction = Decimal::from(window.end.as_millis() - window.start.as_millis())
+                / Decimal::from(params.period_ms());
+            let rate = calculate_funding_rate(window.premium_twap, &params) * time_fraction;
+            let per_unit = rate * window.mark_price.value();
+            let total_long_payments = long_oi * per_unit;
+            let total_short_payments = -short_oi * per_unit;
+
+            // receivers forgo lp_fee_fraction of what they're owed, the pool gets it as they realize
+            let gross_receiver_total = if per_unit > Decimal::ZERO {
+                total_short_payments.abs()
             } else {
-                *payment // payer pays full amount
+                total_long_payments.abs()
             };
+            let lp_fee_amount = gross_receiver_total * params.lp_fee_fraction;
 
-            let account = self.accounts.get_mut(account_id).unwrap();
-            let new_balance = account.balance.value() - adjusted_payment.value();
-            account.balance = Quote::new(new_balance.max(Decimal::ZERO));
+            state.record_settlement(
+                FundingRecord {
+                    timestamp: window.end,
+                    rate,
+                    premium: window.premium_twap,
+                    mark_price: window.mark_price,
+                    index_price: window.index_price,
+                    open_interest_long: long_oi,
+                    open_interest_short: short_oi,
+                    total_paid: Quote::new(total_long_payments.max(total_short_payments)),
+                    lp_fee: Quote::new(lp_fee_amount),
+                },
+                params.max_history,
+            );
+            state.last_update = window.end;
+            state.cumulative_funding += per_unit;
+            state.current_rate = rate;
+            state.twap_premium = window.premium_twap;
 
-            if let Some(position) = account.get_position_mut(market_id) {
-                let market = self.markets.get(&market_id).unwrap();
-                position.entry_funding_index = market.funding_state.cumulative_funding;
-            }
+            result.funding_rate += rate;
+            result.total_long_payments = result.total_long_payments.add(Quote::new(total_long_payments));
+            result.total_short_payments = result.total_short_payments.add(Quote::new(total_short_payments));
+            result.lp_fee_collected = result.lp_fee_collected.add(Quote::new(lp_fee_amount));
+            result.periods_settled += 1;
+        }
 
-            self.emit_event(EventPayload::FundingSettled(FundingSettledEvent {
+        if result.periods_settled > 0 {
+            result.accounts_affected = market.position_holders.len();
+            self.emit_event(EventPayload::OiUpdated(OiUpdatedEvent {
                 market_id,
-                account_id: *account_id,
-                payment: adjusted_payment,
-                funding_rate: prorated_rate,
-                position_size: *position_size,
+                long_oi,
+                short_oi,
+                total_oi: long_oi.max(short_oi),
             }));
         }
+        result
+    }
 
-        // Accrue LP fee to market state
-        let market = self.markets.get_mut(&market_id).unwrap();
-        market.pool_funding_fees += lp_fee_amount;
-        market.funding_state.last_update = self.current_time;
-        market.funding_state.cumulative_funding += prorated_rate;
-        market.funding_state.current_rate = prorated_rate;
+    // past settlements, most recent first
+    pub fn funding_history(&self, market_id: MarketId, limit: usize) -> Result<Vec<FundingRecord>, EngineError> {
+        let market = self
+            .markets
+            .get(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        Ok(market.funding_state.recent_history(limit))
+    }
+
+    pub fn predicted_funding(&self, market_id: MarketId) -> Result<PredictedFunding, EngineError> {
+        let market = self
+            .markets
+            .get(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        Ok(market.funding_state.predict(self.current_time, &market.config.funding_params))
+    }
+
+    // 5.6: sample the premium if the market's sample interval has passed. runs on every mark
+    // update, keepers can call it between oracle ticks. true if a sample was taken
+    pub fn sample_premium(&mut self, market_id: MarketId) -> Result<bool, EngineError> {
+        if !self.markets.contains_key(&market_id) {
+            return Err(EngineError::MarketNotFound(market_id));
+        }
+        Ok(self.record_premium_sample(market_id, false))
+    }
+
+    // premium from the impact bid/ask for the configured notional against index
+    pub(super) fn record_premium_sample(&mut self, market_id: MarketId, force: bool) -> bool {
+        let now = self.current_time;
+        let Some(market) = self.markets.get_mut(&market_id) else {
+            return false;
+        };
+        let Some(index_price) = market.index_price else {
+            return false;
+        };
+        let params = &market.config.funding_params;
+        let mark_price = market.mark_price.unwrap_or(index_price);
+        let due = market
+            .funding_state
+            .last_sample
+            .is_none_or(|last| now.as_millis() - last.as_millis() >= params.premium_sample_interval_ms);
+        if !force && !due {
+            return false;
+        }
+
+        let impact_bid = market.order_book.impact_price(Side::Short, params.impact_notional);
+        let impact_ask = market.order_book.impact_price(Side::Long, params.impact_notional);
+        let premium = calculate_impact_premium(impact_bid, impact_ask, index_price);
+        market.funding_state.record_premium(now, premium, mark_price, index_price, params);
+        true
+    }
+
+    // settle a single position's funding now rather than on its next fill or liquidation
+    pub fn settle_position_funding(&mut self, account_id: AccountId, market_id: MarketId) -> Result<Quote, EngineError> {
+        let account = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+        if account.get_position(market_id).is_none() {
+            return Err(EngineError::Account(AccountError::PositionNotFound(market_id)));
+        }
+        Ok(self.realize_funding(account_id, market_id))
+    }
+
+    // 5.6: pay or collect what the position has accrued since its entry index and move it to
+    // the current one. payers pay in full, receivers get (1 - lp_fee_fraction) and the rest
+    // goes to the pool. returns what left the account (negative = received)
+    pub(super) fn realize_funding(&mut self, account_id: AccountId, market_id: MarketId) -> Quote {
+        if !self.markets.contains_key(&market_id) {
+            return Quote::zero();
+        }
+        self.catch_up_funding(market_id);
+        let market = &self.markets[&market_id];
+        let funding_index = market.funding_state.cumulative_funding;
+        let funding_rate = market.funding_state.current_rate;
+        let lp_fee_fraction = market.config.funding_params.lp_fee_fraction;
+
+        let Some(position) = self
+            .accounts
+            .get_mut(&account_id)
+            .and_then(|account| account.get_position_mut(market_id))
+        else {
+            return Quote::zero();
+        };
+        let gross = position.pending_funding(funding_index);
+        position.entry_funding_index = funding_index;
+        let position_size = position.size;
+        if gross.value().is_zero() {
+            return Quote::zero();
+        }
 
-        if lp_fee_amount > Decimal::ZERO {
+        let lp_fee = if gross.value() < Decimal::ZERO {
+            Quote::new(-gross.value() * lp_fee_fraction)
+        } else {
+            Quote::zero()
+        };
+        let payment = gross.add(lp_fee);
+
+        // payers who can't cover it go into debt rather than being let off (see 10.4)
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        account.balance = account.balance.sub(payment);
+        self.post(
+            LedgerAccount::Trader(account_id),
+            LedgerAccount::Settlement(market_id),
+            payment,
+            EntryKind::Funding,
+        );
+
+        if lp_fee.value() > Decimal::ZERO {
+            self.markets.get_mut(&market_id).unwrap().pool_funding_fees += lp_fee.value();
+            self.post(
+                LedgerAccount::Settlement(market_id),
+                LedgerAccount::LpPool(market_id),
+                lp_fee,
+                EntryKind::FundingLpFee,
+            );
             self.emit_event(EventPayload::FundingFeeCollected(FundingFeeCollectedEvent {
                 market_id,
-                lp_fee_amount: Quote::new(lp_fee_amount),
-                funding_rate: prorated_rate,
+                lp_fee_amount: lp_fee,
+                funding_rate,
             }));
         }
 
-        // Emit OI snapshot after funding
-        let market = self.markets.get(&market_id).unwrap();
-        let long_oi = market.open_interest_long;
-        let short_oi = market.open_interest_short;
-        self.emit_event(EventPayload::OiUpdated(OiUpdatedEvent {
+        self.emit_event(EventPayload::FundingSettled(FundingSettledEvent {
             market_id,
-            long_oi,
-            short_oi,
-            total_oi: long_oi.max(short_oi),
+            account_id,
+            payment,
+            funding_rate,
+            position_size,
         }));
+        self.settle_debt(account_id);
+        payment
+    }
+
+    // every open position on the account, lowest market id first
+    pub(super) fn realize_account_funding(&mut self, account_id: AccountId) {
+        let Some(account) = self.accounts.get(&account_id) else {
+            return;
+        };
+        let mut market_ids: Vec<MarketId> = account.positions.keys().copied().collect();
+        market_ids.sort_by_key(|id| id.0);
+        for market_id in market_ids {
+            self.realize_funding(account_id, market_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineConfig;
+    use crate::market::MarketConfig;
+    use crate::order::TimeInForce;
+    use crate::types::Price;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn late_book_skew_barely_moves_the_rate() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let maker = engine.create_account();
+        let whale = engine.create_account();
+        engine.deposit(maker, Quote::new(dec!(100000))).unwrap();
+        engine.deposit(whale, Quote::new(dec!(100000))).unwrap();
+        for (side, price) in [(Side::Long, dec!(49400)), (Side::Short, dec!(50600))] {
+            engine
+                .place_limit_order(maker, MarketId(1), side, dec!(1), Price::new_unchecked(price), TimeInForce::GTC)
+                .unwrap();
+        }
+
+        // book straddles index for the window, then a bid 1% over index in the final minute
+        engine.advance_time(8 * 60 * 60 * 1000 - 60_000);
+        engine
+            .place_limit_order(whale, MarketId(1), Side::Long, dec!(1), Price::new_unchecked(dec!(50500)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        assert!(!engine.sample_premium(MarketId(1)).unwrap());
+        engine.advance_time(60_000);
+
+        let result = engine.settle_funding(MarketId(1)).unwrap();
+        let market = engine.get_market(MarketId(1)).unwrap();
+        let skew = market.funding_state.last_premium;
+        assert_eq!(skew.round_dp(12), dec!(0.01));
+        let twap = skew * dec!(60000) / dec!(28800000);
+        assert_eq!(market.funding_state.twap_premium, twap);
+        assert_eq!(result.funding_rate, twap * dec!(0.5) + dec!(0.0001));
+
+        // the skew carries into the next window until the book changes
+        assert_eq!(market.funding_state.premium_twap(engine.time()), skew);
+    }
+
+    #[test]
+    fn positions_realize_funding_when_touched() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let long = engine.create_account();
+        let short = engine.create_account();
+        let maker = engine.create_account();
+        for account in [long, short, maker] {
+            engine.deposit(account, Quote::new(dec!(10000))).unwrap();
+        }
+        engine
+            .place_limit_order(short, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(long, MarketId(1), Side::Long, dec!(1)).unwrap();
+
+        let balance = |engine: &Engine, id| engine.get_account(id).unwrap().balance.value();
+        let settled = |engine: &Engine, id| {
+            engine.events().iter().find_map(|e| match &e.payload {
+                EventPayload::FundingSettled(ev) if ev.account_id == id => Some(ev.payment.value()),
+                _ => None,
+            })
+        };
+        let (long_before, short_before) = (balance(&engine, long), balance(&engine, short));
+
+        engine.advance_time(8 * 60 * 60 * 1000);
+        let result = engine.settle_funding(MarketId(1)).unwrap();
+        assert_eq!(result.accounts_affected, 2);
+        let owed = result.total_long_payments.value();
+        assert!(owed > Decimal::ZERO);
+
+        // only the index moved
+        assert_eq!(balance(&engine, long), long_before);
+        assert_eq!(balance(&engine, short), short_before);
+        assert!(settled(&engine, long).is_none());
+
+        // a withdrawal realizes what's owed before anything leaves
+        engine.withdraw(long, Quote::new(dec!(100))).unwrap();
+        assert_eq!(settled(&engine, long), Some(owed));
+        assert_eq!(balance(&engine, long), long_before - owed - dec!(100));
+
+        // a fill realizes the receiver's side, less the LP cut
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(short, MarketId(1), Side::Long, dec!(1)).unwrap();
+        let received = owed * (Decimal::ONE - dec!(0.10));
+        assert_eq!(settled(&engine, short), Some(-received));
+        assert_eq!(engine.get_market(MarketId(1)).unwrap().pool_funding_fees, owed - received);
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn settlements_are_recorded_and_next_rate_predicted() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let long = engine.create_account();
+        let short = engine.create_account();
+        engine.deposit(long, Quote::new(dec!(100000))).unwrap();
+        engine.deposit(short, Quote::new(dec!(100000))).unwrap();
+        engine
+            .place_limit_order(short, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(long, MarketId(1), Side::Long, dec!(1)).unwrap();
+
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            engine.advance_time(8 * 60 * 60 * 1000);
+            results.push(engine.settle_funding(MarketId(1)).unwrap());
+        }
+
+        let history = engine.funding_history(MarketId(1), 10).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].timestamp, engine.time());
+        assert_eq!(history[0].open_interest_long, dec!(1));
+        assert_eq!(history[0].total_paid, results[1].total_long_payments);
+        assert_eq!(history[1].rate, results[0].funding_rate);
+
+        // flat book, so only the interest rate: 0.01% a period, 10.95% a year
+        engine.advance_time(2 * 60 * 60 * 1000);
+        let predicted = engine.predicted_funding(MarketId(1)).unwrap();
+        assert_eq!(predicted.rate, dec!(0.0001));
+        assert_eq!(predicted.annualized_rate, dec!(0.1095));
+        assert_eq!(predicted.time_to_next_ms, 6 * 60 * 60 * 1000);
+    }
+
+    #[test]
+    fn missed_periods_settle_one_by_one_and_repeats_are_noops() {
+        let hour = 60 * 60 * 1000;
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let long = engine.create_account();
+        let short = engine.create_account();
+        let maker = engine.create_account();
+        for id in [long, short, maker] {
+            engine.deposit(id, Quote::new(dec!(100000))).unwrap();
+        }
+        engine
+            .place_limit_order(short, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(long, MarketId(1), Side::Long, dec!(1)).unwrap();
+        for (side, price) in [(Side::Long, dec!(49400)), (Side::Short, dec!(50600))] {
+            engine
+                .place_limit_order(maker, MarketId(1), side, dec!(1), Price::new_unchecked(price), TimeInForce::GTC)
+                .unwrap();
+        }
+
+        // flat through the first period, bid 1% over index from 10h on, no keeper until 25h
+        engine.advance_time(10 * hour);
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Long, dec!(1), Price::new_unchecked(dec!(50500)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        engine.advance_time(15 * hour);
+
+        let result = engine.settle_funding(MarketId(1)).unwrap();
+        assert_eq!(result.periods_settled, 3);
+
+        let history = engine.funding_history(MarketId(1), 10).unwrap();
+        let skew = history[0].premium;
+        let boundaries: Vec<i64> = history.iter().map(|r| r.timestamp.as_millis() / hour).collect();
+        assert_eq!(boundaries, vec![24, 16, 8]);
+        assert_eq!(history[2].premium, Decimal::ZERO);
+        assert_eq!(history[1].premium, skew * dec!(6) / dec!(8));
+        assert!(history[0].rate > history[1].rate && history[1].rate > history[2].rate);
+
+        let index: Decimal = history.iter().map(|r| r.rate * r.mark_price.value()).sum();
+        let state = &engine.get_market(MarketId(1)).unwrap().funding_state;
+        assert_eq!(state.cumulative_funding, index);
+        assert_eq!(state.last_update.as_millis(), 24 * hour);
+
+        // same period again, or any time before the 32h boundary
+        let again = engine.settle_funding(MarketId(1)).unwrap();
+        engine.advance_time(6 * hour);
+        let later = engine.settle_funding(MarketId(1)).unwrap();
+        assert_eq!((again.periods_settled, later.periods_settled), (0, 0));
+        assert_eq!(engine.get_market(MarketId(1)).unwrap().funding_state.cumulative_funding, index);
 
-        Ok(FundingResult {
-            funding_rate: prorated_rate,
-            total_long_payments: Quote::new(total_long_payments),
-            total_short_payments: Quote::new(total_short_payments),
-            lp_fee_collected: Quote::new(lp_fee_amount),
-            accounts_affected: account_payments.len(),
-        })
+        // the long pays all three periods when it's next touched
+        let paid = engine.settle_position_funding(long, MarketId(1)).unwrap();
+        assert_eq!(paid.value(), index);
     }
 }
@@ -0,0 +1,424 @@
+// 8.12: insurance funds (6.1). a market's liquidation penalties go to its own fund up to the
+// market's target and overflow to the global fund. bad debt draws on the market's fund first
+// and the global fund only once that is empty, so one market can't drain another's cover.
+// accounts can stake into the global fund (6.1.1) and leave only after a cooldown.
+
+use super::core::Engine;
+use super::results::EngineError;
+use crate::account::AccountError;
+use crate::events::{EventPayload, InsuranceStakedEvent, InsuranceUnstakedEvent, UnstakeRequestedEvent};
+use crate::ledger::{EntryKind, LedgerAccount};
+use crate::liquidation::{InsuranceFlow, InsuranceFlowKind};
+use crate::types::{AccountId, MarketId, Quote, Timestamp};
+use rust_decimal::Decimal;
+
+impl Engine {
+    pub fn market_insurance_balance(&self, market_id: MarketId) -> Result<Quote, EngineError> {
+        let market = self
+            .markets
+            .get(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        Ok(market.insurance_fund.balance)
+    }
+
+    // flows in and out of a market's fund, or the global fund for None. most recent first
+    pub fn insurance_history(&self, market_id: Option<MarketId>, limit: usize) -> Result<Vec<InsuranceFlow>, EngineError> {
+        let fund = match market_id {
+            Some(id) => &self.markets.get(&id).ok_or(EngineError::MarketNotFound(id))?.insurance_fund,
+            None => &self.insurance_fund,
+        };
+        Ok(fund.recent_history(limit))
+    }
+
+    pub fn fund_market_insurance(&mut self, market_id: MarketId, amount: Quote) -> Result<(), EngineError> {
+        if !self.markets.contains_key(&market_id) {
+            return Err(EngineError::MarketNotFound(market_id));
+        }
+        if amount.value() <= Decimal::ZERO {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        self.credit_market_insurance(
+            LedgerAccount::External,
+            market_id,
+            amount,
+            InsuranceFlowKind::Deposit,
+            EntryKind::InsuranceDeposit,
+        );
+        Ok(())
+    }
+
+    pub fn insurance_shares(&self, staker: AccountId) -> Decimal {
+        self.insurance_fund.shares_of(staker)
+    }
+
+    pub fn insurance_stake_value(&self, staker: AccountId) -> Quote {
+        self.insurance_fund.share_value(self.insurance_fund.shares_of(staker))
+    }
+
+    // 6.1.1: move free balance into the global fund for shares at the current share value
+    pub fn stake_insurance(&mut self, staker: AccountId, amount: Quote) -> Result<Decimal, EngineError> {
+        if amount.value() <= Decimal::ZERO {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        if staker == self.backstop.account_id {
+            return Err(EngineError::AccountNotFound(staker));
+        }
+        let available = self.withdrawable_balance(staker)?;
+        if amount.value() > available.value() {
+            return Err(EngineError::Account(AccountError::InsufficientFreeCollateral {
+                requested: amount,
+                available,
+            }));
+        }
+
+        let account = self.accounts.get_mut(&staker).unwrap();
+        account.balance = account.balance.sub(amount);
+        self.post(LedgerAccount::Trader(staker), LedgerAccount::InsuranceFund, amount, EntryKind::InsuranceStake);
+        let shares = self.insurance_fund.stake(staker, amount);
+        self.insurance_fund
+            .record(InsuranceFlowKind::Stake, None, amount, self.current_time);
+
+        self.emit_event(EventPayload::InsuranceStaked(InsuranceStakedEvent {
+            account_id: staker,
+            amount,
+            shares,
+        }));
+        Ok(shares)
+    }
+
+    // starts the cooldown on `shares`. they keep earning and absorbing losses until withdrawn,
+    // and a new request replaces the old one and restarts the clock. a request not used within
+    // unstake_window_ms of the cooldown lapses, so it can't be held open against a future loss
+    pub fn request_unstake(&mut self, staker: AccountId, shares: Decimal) -> Result<Timestamp, EngineError> {
+        if !self.accounts.contains_key(&staker) {
+            return Err(EngineError::AccountNotFound(staker));
+        }
+        let held = self.insurance_fund.shares_of(staker);
+        if shares <= Decimal::ZERO || shares > held {
+            return Err(EngineError::InsufficientShares { requested: shares, available: held });
+        }
+
+        let params = &self.config.insurance;
+        let available_at = Timestamp::from_millis(self.current_time.as_millis() + params.unstake_cooldown_ms);
+        let expires_at = Timestamp::from_millis(available_at.as_millis() + params.unstake_window_ms);
+        self.insurance_fund.request_unstake(staker, shares, available_at, expires_at);
+        self.emit_event(EventPayload::UnstakeRequested(UnstakeRequestedEvent {
+            account_id: staker,
+            shares,
+            available_at,
+        }));
+        Ok(available_at)
+    }
+
+    // redeems the requested shares at today's share value once the cooldown has run
+    pub fn unstake_insurance(&mut self, staker: AccountId) -> Result<Quote, EngineError> {
+        let request = self
+            .insurance_fund
+            .pending_unstake(staker)
+            .ok_or(EngineError::NoPendingUnstake(staker))?;
+        if self.current_time.as_millis() < request.available_at.as_millis() {
+            return Err(EngineError::UnstakeCooldown { available_at: request.available_at });
+        }
+        if self.current_time.as_millis() > request.expires_at.as_millis() {
+            let expired_at = request.expires_at;
+            self.insurance_fund.cancel_unstake(staker);
+            return Err(EngineError::UnstakeExpired { expired_at });
+        }
+
+        let (shares, amount) = self.insurance_fund.redeem(staker).unwrap();
+        self.insurance_fund
+            .record(InsuranceFlowKind::Unstake, None, Quote::new(-amount.value()), self.current_time);
+        let account = self.accounts.get_mut(&staker).unwrap();
+        account.balance = account.balance.add(amount);
+        self.post(LedgerAccount::InsuranceFund, LedgerAccount::Trader(staker), amount, EntryKind::InsuranceUnstake);
+
+        self.emit_event(EventPayload::InsuranceUnstaked(InsuranceUnstakedEvent {
+            account_id: staker,
+            amount,
+            shares,
+        }));
+        Ok(amount)
+    }
+
+    // a liquidation's insurance cut. while anyone is staked, staker_penalty_share of it goes
+    // to the stakers in the global fund and the rest to the market's own
+    pub(super) fn credit_penalty(&mut self, from: LedgerAccount, market_id: MarketId, amount: Quote) {
+        let to_stakers = if self.insurance_fund.staked_shares() > Decimal::ZERO {
+            Quote::new(amount.value() * self.config.insurance.staker_penalty_share)
+        } else {
+            Quote::zero()
+        };
+        self.insurance_fund.credit_stakers(to_stakers);
+        self.insurance_fund
+            .record(InsuranceFlowKind::Penalty, Some(market_id), to_stakers, self.current_time);
+        self.post(from, LedgerAccount::InsuranceFund, to_stakers, EntryKind::LiquidationPenalty);
+
+        self.credit_market_insurance(
+            from,
+            market_id,
+            amount.sub(to_stakers),
+            InsuranceFlowKind::Penalty,
+            EntryKind::LiquidationPenalty,
+        );
+    }
+
+    // pays `amount` from `from` into the market's fund, then moves anything over its target
+    // to the global fund
+    pub(super) fn credit_market_insurance(
+        &mut self,
+        from: LedgerAccount,
+        market_id: MarketId,
+        amount: Quote,
+        kind: InsuranceFlowKind,
+        entry: EntryKind,
+    ) {
+        let now = self.current_time;
+        let market = self.markets.get_mut(&market_id).unwrap();
+        let target = market.config.liquidation_params.insurance_target;
+        let fund = &mut market.insurance_fund;
+        fund.deposit(amount);
+        fund.record(kind, Some(market_id), amount, now);
+        let overflow = fund.take_overflow(target);
+        fund.record(InsuranceFlowKind::Overflow, Some(market_id), Quote::new(-overflow.value()), now);
+
+        self.insurance_fund.deposit(overflow);
+        self.insurance_fund.record(InsuranceFlowKind::Overflow, Some(market_id), overflow, now);
+
+        let market_fund = LedgerAccount::MarketInsurance(market_id);
+        self.post(from, market_fund, amount, entry);
+        self.post(market_fund, LedgerAccount::InsuranceFund, overflow, EntryKind::InsuranceOverflow);
+    }
+
+    // what the market's fund and the global fund could pay out between them
+    pub(super) fn insurance_available(&self, market_id: MarketId) -> Quote {
+        let market_fund = self.markets.get(&market_id).map_or(Quote::zero(), |m| m.insurance_fund.balance);
+        market_fund.add(self.insurance_fund.balance)
+    }
+
+    // covers as much of `amount` as the funds hold, market first. debt with no market behind
+    // it goes straight to the global fund
+    pub(super) fn cover_from_insurance(&mut self, to: LedgerAccount, market_id: Option<MarketId>, amount: Quote) -> Quote {
+        let now = self.current_time;
+        let mut covered = Quote::zero();
+        if let Some(id) = market_id.filter(|id| self.markets.contains_key(id)) {
+            let fund = &mut self.markets.get_mut(&id).unwrap().insurance_fund;
+            covered = fund.cover_bad_debt(amount);
+            fund.record(InsuranceFlowKind::Payout, market_id, Quote::new(-covered.value()), now);
+            self.post(LedgerAccount::MarketInsurance(id), to, covered, EntryKind::InsuranceCover);
+        }
+
+        let from_global = self.insurance_fund.cover_bad_debt(amount.sub(covered));
+        self.insurance_fund
+            .record(InsuranceFlowKind::Payout, market_id, Quote::new(-from_global.value()), now);
+        self.post(LedgerAccount::InsuranceFund, to, from_global, EntryKind::InsuranceCover);
+        covered.add(from_global)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, EngineConfig, EngineError};
+    use crate::liquidation::InsuranceFlowKind;
+    use crate::market::MarketConfig;
+    use crate::order::TimeInForce;
+    use crate::types::{Leverage, MarketId, Price, Quote, Side};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn market_fund_overflows_to_global_and_pays_first() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let mut config = MarketConfig::btc_perp();
+        config.liquidation_params.insurance_target = Quote::new(dec!(1000));
+        engine.add_market(config);
+        let market = MarketId(1);
+
+        // funding above target spills over
+        engine.fund_market_insurance(market, Quote::new(dec!(1500))).unwrap();
+        engine.fund_insurance(Quote::new(dec!(10000)));
+        assert_eq!(engine.market_insurance_balance(market).unwrap().value(), dec!(1000));
+        assert_eq!(engine.insurance_fund_balance().value(), dec!(10500));
+
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        engine.deposit(buyer, Quote::new(dec!(10000))).unwrap();
+        engine.deposit(seller, Quote::new(dec!(100000))).unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+
+        // 5000 collateral, closed into a bid at 40000: 5000 of debt, and a bankrupt close pays no penalty
+        let bidder = engine.create_account();
+        engine.deposit(bidder, Quote::new(dec!(100000))).unwrap();
+        engine
+            .place_limit_order(bidder, market, Side::Long, dec!(1), Price::new_unchecked(dec!(40000)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(40000))).unwrap();
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+        let debt = result.bad_debt.value();
+        assert_eq!(debt, dec!(5000));
+        assert!(result.penalty.value().is_zero());
+
+        // the market fund goes first and the global fund covers the rest
+        assert!(engine.market_insurance_balance(market).unwrap().value().is_zero());
+        assert_eq!(engine.insurance_fund_balance().value(), dec!(10500) - (debt - dec!(1000)));
+
+        let flows = engine.insurance_history(Some(market), 10).unwrap();
+        let kinds: Vec<_> = flows.iter().map(|f| f.kind).collect();
+        assert_eq!(kinds, vec![InsuranceFlowKind::Payout, InsuranceFlowKind::Overflow, InsuranceFlowKind::Deposit]);
+        let global = engine.insurance_history(None, 1).unwrap();
+        assert_eq!(global[0].kind, InsuranceFlowKind::Payout);
+        assert_eq!(global[0].market_id, Some(market));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn stakers_earn_penalties_and_leave_after_cooldown() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        let market = MarketId(1);
+
+        let staker = engine.create_account();
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        for (account, amount) in [(staker, dec!(20000)), (buyer, dec!(10000)), (seller, dec!(100000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        assert_eq!(engine.stake_insurance(staker, Quote::new(dec!(10000))).unwrap(), dec!(10000));
+        assert_eq!(engine.get_account(staker).unwrap().balance.value(), dec!(10000));
+
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+        // the seller buys back into the liquidation
+        engine
+            .place_limit_order(seller, market, Side::Long, dec!(1), Price::new_unchecked(dec!(47300)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(47300))).unwrap();
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+
+        // a fifth of the insurance half of the penalty belongs to the staker
+        let earned = result.penalty.value() / dec!(2) * dec!(0.2);
+        assert!(earned > dec!(0));
+        assert_eq!(engine.insurance_stake_value(staker).value(), dec!(10000) + earned);
+
+        assert!(matches!(engine.unstake_insurance(staker), Err(EngineError::NoPendingUnstake(_))));
+        assert!(matches!(
+            engine.request_unstake(staker, dec!(20000)),
+            Err(EngineError::InsufficientShares { .. })
+        ));
+        // the cut came as new shares
+        let shares = engine.insurance_shares(staker);
+        assert!(shares > dec!(10000));
+        let available_at = engine.request_unstake(staker, shares).unwrap();
+        engine.advance_time(60_000);
+        assert!(matches!(engine.unstake_insurance(staker), Err(EngineError::UnstakeCooldown { .. })));
+
+        engine.advance_time(available_at.as_millis());
+        assert_eq!(engine.unstake_insurance(staker).unwrap().value(), dec!(10000) + earned);
+        assert_eq!(engine.insurance_shares(staker), dec!(0));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn staker_cut_is_not_shared_with_operator_capital() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        let market = MarketId(1);
+        engine.fund_insurance(Quote::new(dec!(30000)));
+
+        let staker = engine.create_account();
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        for (account, amount) in [(staker, dec!(10000)), (buyer, dec!(10000)), (seller, dec!(100000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        // a quarter of the global fund's shares, the operator holds the rest
+        engine.stake_insurance(staker, Quote::new(dec!(10000))).unwrap();
+
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Long, dec!(1), Price::new_unchecked(dec!(47300)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(47300))).unwrap();
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+
+        // the whole staker share of the insurance half, not a quarter of it
+        let earned = result.penalty.value() / dec!(2) * dec!(0.2);
+        assert!(earned > dec!(0));
+        assert_eq!(engine.insurance_stake_value(staker).value().round_dp(8), dec!(10000) + earned);
+        assert_eq!(engine.insurance_fund_balance().value(), dec!(40000) + earned);
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn unstake_request_lapses_after_the_claim_window() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let staker = engine.create_account();
+        engine.deposit(staker, Quote::new(dec!(10000))).unwrap();
+        engine.stake_insurance(staker, Quote::new(dec!(10000))).unwrap();
+
+        let params = engine.config.insurance.clone();
+        let available_at = engine.request_unstake(staker, dec!(10000)).unwrap();
+        engine.advance_time(params.unstake_cooldown_ms + params.unstake_window_ms + 1);
+        assert!(matches!(
+            engine.unstake_insurance(staker),
+            Err(EngineError::UnstakeExpired { expired_at }) if expired_at.as_millis() == available_at.as_millis() + params.unstake_window_ms
+        ));
+
+        // the lapsed request is gone, the shares stay staked and a new one starts the cooldown over
+        assert!(matches!(engine.unstake_insurance(staker), Err(EngineError::NoPendingUnstake(_))));
+        assert_eq!(engine.insurance_shares(staker), dec!(10000));
+        engine.request_unstake(staker, dec!(10000)).unwrap();
+        assert!(matches!(engine.unstake_insurance(staker), Err(EngineError::UnstakeCooldown { .. })));
+        engine.advance_time(params.unstake_cooldown_ms);
+        assert_eq!(engine.unstake_insurance(staker).unwrap().value(), dec!(10000));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn shares_in_cooldown_still_absorb_losses() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        let market = MarketId(1);
+
+        let staker = engine.create_account();
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        for (account, amount) in [(staker, dec!(10000)), (buyer, dec!(10000)), (seller, dec!(100000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        engine.stake_insurance(staker, Quote::new(dec!(10000))).unwrap();
+        engine.request_unstake(staker, dec!(10000)).unwrap();
+
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+
+        // a 5000 loss lands on the global fund while the staker waits out the cooldown
+        engine
+            .place_limit_order(seller, market, Side::Long, dec!(1), Price::new_unchecked(dec!(40000)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(40000))).unwrap();
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+        assert_eq!(result.bad_debt.value(), dec!(5000));
+        assert_eq!(engine.insurance_stake_value(staker).value(), dec!(5000));
+
+        engine.advance_time(engine.config.insurance.unstake_cooldown_ms);
+        assert_eq!(engine.unstake_insurance(staker).unwrap().value(), dec!(5000));
+        assert!(engine.conservation_report().is_conserved());
+    }
+}
@@ -2,11 +2,20 @@
 
 use super::core::Engine;
 use super::results::{EngineError, LiquidationResult};
-use crate::events::{BadDebtEvent, EventPayload, LiquidationEvent, OiUpdatedEvent};
-use crate::liquidation::{calculate_liquidation_penalty, evaluate_liquidation, LiquidationStatus};
-use crate::margin::{calculate_margin_requirement, MarginRequirement};
+use crate::account::Account;
+use crate::adl::should_trigger_adl;
+use crate::events::{
+    BadDebtEvent, EventPayload, LiquidationEvent, LiquidationUnwindEndedEvent, LiquidationUnwindSliceEvent,
+    LiquidationUnwindStartedEvent, MarginCallClearedEvent, MarginCallEvent, OiUpdatedEvent,
+};
+use crate::ledger::{EntryKind, LedgerAccount};
+use crate::liquidation::{
+    calculate_liquidation_amount, calculate_liquidation_penalty, evaluate_liquidation, BadDebtMode, LiquidationParams,
+    LiquidationStatus, LiquidationUnwind, UnwindEndReason,
+};
+use crate::margin::{calculate_margin_requirement, MarginParams, MarginRequirement};
 use crate::position::Position;
-use crate::types::{AccountId, MarketId, Price, Quote, Side};
+use crate::types::{AccountId, MarketId, Price, Quote, Side, SignedSize};
 use rust_decimal::Decimal;
 
 impl Engine {
@@ -21,101 +30,488 @@ impl Engine {
         };
 
         let margin_params = market.config.margin_params.clone();
-        let liq_params = market.config.liquidation_params.clone();
         let funding_index = market.funding_state.cumulative_funding;
 
-        let mut liquidatable: Vec<(AccountId, Position, MarginRequirement)> = Vec::new();
-
-        for (account_id, account) in &self.accounts {
-            if let Some(position) = account.get_position(market_id) {
-                let margin_req = calculate_margin_requirement(
-                    position.size,
-                    mark_price,
-                    position.leverage,
-                    &margin_params,
-                );
-
-                let equity = position.equity(mark_price, funding_index);
-                let notional = position.notional_value(mark_price);
-
-                let status = evaluate_liquidation(
-                    equity,
-                    &margin_req,
-                    notional,
-                    position.entry_price,
-                    mark_price,
-                    position.side().unwrap(),
-                );
-
-                match status {
-                    LiquidationStatus::Liquidatable { .. } | LiquidationStatus::Bankrupt { .. } => {
-                        liquidatable.push((*account_id, position.clone(), margin_req));
+        let mut liquidatable: Vec<(AccountId, MarketId, Position, MarginRequirement)> = Vec::new();
+
+        for (account_id, account, position) in self.position_holders(market_id) {
+            if let Some(margin_req) = self.liquidation_check(account, position, mark_price, funding_index, &margin_params) {
+                // a portfolio account is flagged as a whole and closes the leg that relieves it
+                // most, which needn't be the one in this market
+                let leg = if account.is_portfolio_margin() { self.portfolio_close_leg(account, market_id) } else { market_id };
+                liquidatable.push((account_id, leg, account.positions[&leg].clone(), margin_req));
+            }
+        }
+
+        // 8.9.5: unwinds whose position is back above maintenance, or gone, end here
+        let mut ended: Vec<(AccountId, UnwindEndReason)> = self
+            .liquidation_unwinds
+            .keys()
+            .filter(|(account_id, id)| *id == market_id && !liquidatable.iter().any(|(a, ..)| a == account_id))
+            .map(|(account_id, _)| {
+                let open = self.accounts[account_id].get_position(market_id).is_some();
+                (*account_id, if open { UnwindEndReason::Restored } else { UnwindEndReason::Closed })
+            })
+            .collect();
+        ended.sort_by_key(|(account_id, _)| account_id.0);
+        for (account_id, reason) in ended {
+            self.end_unwind(account_id, market_id, reason);
+        }
+
+        let mut results = Vec::new();
+
+        for (account_id, leg, position, margin_req) in liquidatable {
+            let market = &self.markets[&leg];
+            let (Some(mark_price), liq_params) = (market.mark_price, market.config.liquidation_params.clone()) else {
+                continue;
+            };
+            if !self.unwind_gate(account_id, leg, &position, mark_price, &liq_params) {
+                continue;
+            }
+            // nothing to close against yet, the position is retried on the next check
+            match self.execute_liquidation(account_id, leg, position, margin_req, mark_price, &liq_params, None) {
+                Ok(result) => results.push(result),
+                Err(EngineError::LiquidationUnfilled { .. }) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(results)
+    }
+
+    // equity and requirement a position is judged on. portfolio accounts are margined as a
+    // whole: account equity vs scenario MM
+    fn position_health(
+        &self,
+        account: &Account,
+        position: &Position,
+        mark_price: Price,
+        funding_index: Decimal,
+        margin_params: &MarginParams,
+    ) -> (Quote, MarginRequirement) {
+        if account.is_portfolio_margin() {
+            let requirement = self.portfolio_requirement(account, None);
+            let margin_req = MarginRequirement {
+                initial: requirement.initial,
+                maintenance: requirement.maintenance,
+                effective_leverage: position.leverage,
+            };
+            return (self.metrics_for(account).total_equity, margin_req);
+        }
+
+        let margin_req = calculate_margin_requirement(position.size, mark_price, position.leverage, margin_params);
+        // unpaid debt has already drained collateral, what's left counts here
+        (position.equity(mark_price, funding_index).sub(account.debt()), margin_req)
+    }
+
+    // 8.9.1: runs on every mark update. a call goes out when a position enters the at-risk
+    // band and stays active until equity is back above margin_call_clear_ratio x MM
+    pub(super) fn update_margin_calls(&mut self, market_id: MarketId) {
+        let Some(market) = self.markets.get(&market_id) else {
+            return;
+        };
+        let Some(mark_price) = market.mark_price else {
+            return;
+        };
+        let margin_params = market.config.margin_params.clone();
+        let clear_ratio = market.config.liquidation_params.margin_call_clear_ratio;
+        let funding_index = market.funding_state.cumulative_funding;
+
+        let mut raised = Vec::new();
+        let mut cleared = Vec::new();
+
+        for (account_id, account, position) in self.position_holders(market_id) {
+            let (equity, margin_req) =
+                self.position_health(account, position, mark_price, funding_index, &margin_params);
+            let notional = position.notional_value(mark_price);
+            let margin_ratio = if notional.value().is_zero() {
+                Decimal::MAX
+            } else {
+                equity.value() / notional.value()
+            };
+
+            let status = evaluate_liquidation(
+                equity,
+                &margin_req,
+                notional,
+                position.entry_price,
+                mark_price,
+                position.side().unwrap(),
+            );
+            let active = self.margin_calls.contains(&(account_id, market_id));
+
+            match status {
+                LiquidationStatus::Safe { .. } => {
+                    let recovered = equity.value() >= margin_req.maintenance.value() * clear_ratio;
+                    if active && recovered {
+                        cleared.push(MarginCallClearedEvent {
+                            account_id,
+                            market_id,
+                            margin_ratio,
+                            current_equity: equity,
+                        });
                     }
-                    _ => {}
                 }
+                _ if !active => raised.push(MarginCallEvent {
+                    account_id,
+                    market_id,
+                    margin_ratio,
+                    maintenance_margin: margin_req.maintenance,
+                    current_equity: equity,
+                }),
+                _ => {}
             }
         }
 
-        let mut results = Vec::new();
+        // calls on positions that have since closed or been liquidated
+        let accounts = &self.accounts;
+        self.margin_calls.retain(|(account_id, id)| {
+            *id != market_id || accounts.get(account_id).is_some_and(|a| a.get_position(market_id).is_some())
+        });
+
+        raised.sort_by_key(|call| call.account_id.0);
+        cleared.sort_by_key(|clear| clear.account_id.0);
+        for call in raised {
+            self.margin_calls.insert((call.account_id, market_id));
+            self.emit_event(EventPayload::MarginCall(call));
+        }
+        for clear in cleared {
+            self.margin_calls.remove(&(clear.account_id, market_id));
+            self.emit_event(EventPayload::MarginCallCleared(clear));
+        }
+    }
 
-        for (account_id, position, margin_req) in liquidatable {
-            let result = self.execute_liquidation(
+    pub fn has_margin_call(&self, account_id: AccountId, market_id: MarketId) -> bool {
+        self.margin_calls.contains(&(account_id, market_id))
+    }
+
+    // 8.9.3: permissionless entry point. anyone outside the account's group can close an
+    // underwater position and collect the liquidator share of the penalty
+    pub fn liquidate(
+        &mut self,
+        account_id: AccountId,
+        market_id: MarketId,
+        liquidator: AccountId,
+    ) -> Result<LiquidationResult, EngineError> {
+        let liquidator_root = self
+            .accounts
+            .get(&liquidator)
+            .ok_or(EngineError::AccountNotFound(liquidator))?
+            .root();
+        let account = self.accounts.get(&account_id).ok_or(EngineError::AccountNotFound(account_id))?;
+        if account.root() == liquidator_root {
+            return Err(EngineError::SelfLiquidation(liquidator));
+        }
+
+        let market = self.markets.get(&market_id).ok_or(EngineError::MarketNotFound(market_id))?;
+        let Some(mark_price) = market.mark_price else {
+            return Err(EngineError::NoMarkPrice(market_id));
+        };
+        let funding_index = market.funding_state.cumulative_funding;
+
+        let position = account
+            .get_position(market_id)
+            .ok_or(EngineError::NotLiquidatable { account: account_id, market: market_id })?;
+        let margin_req = self
+            .liquidation_check(account, position, mark_price, funding_index, &market.config.margin_params)
+            .ok_or(EngineError::NotLiquidatable { account: account_id, market: market_id })?;
+
+        // whichever leg the liquidator named, a portfolio account closes the one that relieves it most
+        let market_id = if account.is_portfolio_margin() { self.portfolio_close_leg(account, market_id) } else { market_id };
+        let market = &self.markets[&market_id];
+        let mark_price = market.mark_price.unwrap_or(mark_price);
+        let liq_params = market.config.liquidation_params.clone();
+        let position = account.positions[&market_id].clone();
+        if !self.unwind_gate(account_id, market_id, &position, mark_price, &liq_params) {
+            return Err(EngineError::UnwindInProgress { account: account_id, market: market_id });
+        }
+        self.execute_liquidation(account_id, market_id, position, margin_req, mark_price, &liq_params, Some(liquidator))
+    }
+
+    // 3.5: the leg whose full close leaves the smallest scenario MM, so a hedge isn't closed
+    // ahead of the risk it offsets. legs without a mark can't be closed, ties keep `flagged`
+    fn portfolio_close_leg(&self, account: &Account, flagged: MarketId) -> MarketId {
+        let mut legs: Vec<(MarketId, Decimal)> = account
+            .positions
+            .iter()
+            .filter_map(|(id, position)| {
+                let mark_price = self.markets.get(id)?.mark_price?;
+                let pending = Some((*id, SignedSize::new(-position.size.value()), mark_price));
+                Some((*id, self.portfolio_requirement(account, pending).maintenance.value()))
+            })
+            .collect();
+        legs.sort_by_key(|(id, _)| (*id != flagged, id.0));
+        legs.into_iter()
+            .min_by(|(_, a), (_, b)| a.cmp(b))
+            .map_or(flagged, |(id, _)| id)
+    }
+
+    // 8.9.5: a position above unwind_threshold is taken over the first time it's liquidatable
+    // and from then on liquidated a slice per interval. false while the next slice isn't due
+    fn unwind_gate(
+        &mut self,
+        account_id: AccountId,
+        market_id: MarketId,
+        position: &Position,
+        mark_price: Price,
+        liq_params: &LiquidationParams,
+    ) -> bool {
+        let key = (account_id, market_id);
+        if !self.liquidation_unwinds.contains_key(&key) {
+            let notional = position.notional_value(mark_price);
+            if notional.value() <= liq_params.unwind_threshold.value() {
+                return true;
+            }
+            self.liquidation_unwinds.insert(key, LiquidationUnwind::new(position.size, self.current_time));
+            self.emit_event(EventPayload::LiquidationUnwindStarted(LiquidationUnwindStartedEvent {
+                market_id,
                 account_id,
+                size: position.size,
+                notional,
+            }));
+        }
+        self.liquidation_unwinds[&key].slice_due(self.current_time, liq_params)
+    }
+
+    // a slice trades no further from mark than unwind_depth_band, nor past bankruptcy
+    fn unwind_limit(close_side: Side, bankruptcy: Price, mark_price: Price, liq_params: &LiquidationParams) -> Price {
+        match close_side {
+            Side::Short => bankruptcy.max(Price::new_unchecked(mark_price.value() * (Decimal::ONE - liq_params.unwind_depth_band))),
+            Side::Long => bankruptcy.min(Price::new_unchecked(mark_price.value() * (Decimal::ONE + liq_params.unwind_depth_band))),
+        }
+    }
+
+    // largest slice the book can take without being swept: max_liquidation_size, and
+    // unwind_depth_fraction of what rests between mark and the slice's limit. 0 on an empty book
+    fn unwind_slice_size(
+        &self,
+        market_id: MarketId,
+        close_side: Side,
+        limit: Price,
+        mark_price: Price,
+        liq_params: &LiquidationParams,
+    ) -> Decimal {
+        let market = &self.markets[&market_id];
+        let depth = market.order_book.depth_to_price(close_side, limit);
+        let cap = (liq_params.max_liquidation_size.value() / mark_price.value()).min(depth * liq_params.unwind_depth_fraction);
+        let lot = market.config.lot_size;
+        (cap / lot).floor() * lot
+    }
+
+    fn end_unwind(&mut self, account_id: AccountId, market_id: MarketId, reason: UnwindEndReason) {
+        if let Some(unwind) = self.liquidation_unwinds.remove(&(account_id, market_id)) {
+            self.emit_event(EventPayload::LiquidationUnwindEnded(LiquidationUnwindEndedEvent {
                 market_id,
-                position,
-                margin_req,
-                mark_price,
-                &liq_params,
-            )?;
-            results.push(result);
+                account_id,
+                slices: unwind.slices,
+                closed: unwind.closed,
+                reason,
+            }));
         }
+    }
 
-        Ok(results)
+    // the requirement to restore when the position is liquidatable or bankrupt
+    fn liquidation_check(
+        &self,
+        account: &Account,
+        position: &Position,
+        mark_price: Price,
+        funding_index: Decimal,
+        margin_params: &MarginParams,
+    ) -> Option<MarginRequirement> {
+        let (equity, margin_req) = self.position_health(account, position, mark_price, funding_index, margin_params);
+        let status = evaluate_liquidation(
+            equity,
+            &margin_req,
+            position.notional_value(mark_price),
+            position.entry_price,
+            mark_price,
+            position.side()?,
+        );
+        match status {
+            LiquidationStatus::Liquidatable { .. } | LiquidationStatus::Bankrupt { .. } => Some(margin_req),
+            _ => None,
+        }
     }
 
+    // 8.9.4: the close goes to the book first as a reduce-only IOC bounded by the bankruptcy
+    // price. whatever the book can't absorb goes to the backstop
+    #[allow(clippy::too_many_arguments)]
     fn execute_liquidation(
         &mut self,
         account_id: AccountId,
         market_id: MarketId,
         position: Position,
-        _margin_req: MarginRequirement,
+        margin_req: MarginRequirement,
         mark_price: Price,
-        liq_params: &crate::liquidation::LiquidationParams,
+        liq_params: &LiquidationParams,
+        liquidator: Option<AccountId>,
     ) -> Result<LiquidationResult, EngineError> {
-        let funding_index = {
+        // realize funding first, a payer short of balance may have lost collateral to it
+        self.realize_funding(account_id, market_id);
+        let position = self
+            .accounts
+            .get(&account_id)
+            .and_then(|a| a.get_position(market_id))
+            .cloned()
+            .unwrap_or(position);
+        let (funding_index, margin_params) = {
             let market = self.markets.get(&market_id).unwrap();
-            market.funding_state.cumulative_funding
+            (market.funding_state.cumulative_funding, market.config.margin_params.clone())
         };
 
-        let equity = position.equity(mark_price, funding_index);
-        let position_value = position.notional_value(mark_price);
-        let penalty = calculate_liquidation_penalty(position_value, liq_params);
-        let remaining_equity = equity.value() - penalty.total.value();
+        // portfolio positions hold no collateral, their losses settle against the balance
+        let account = self.accounts.get(&account_id).ok_or(EngineError::AccountNotFound(account_id))?;
+        let portfolio_margin = account.is_portfolio_margin();
+        let (health, _) = self.position_health(account, &position, mark_price, funding_index, &margin_params);
 
-        let bad_debt = if remaining_equity < Decimal::ZERO {
-            Quote::new(-remaining_equity)
+        // 8.9.2: close only enough to bring the rest back to target. portfolio accounts and
+        // bankrupt positions go all at once
+        let size = position.size.abs();
+        let close_size = if portfolio_margin {
+            size
         } else {
-            Quote::zero()
+            self.partial_close_size(&position, health, &margin_req, mark_price, liq_params).unwrap_or(size)
         };
-
+        let close_side = if position.size.is_long() { Side::Short } else { Side::Long };
+        // resting orders would otherwise trade against the liquidation
+        self.cancel_orders_for_liquidation(account_id, market_id);
+
+        // 8.9.5: an unwinding position goes a slice at a time until it's bankrupt, and what the
+        // book doesn't take of a slice waits for the next one instead of going to the backstop
+        let slicing = self.liquidation_unwinds.contains_key(&(account_id, market_id)) && health.value() > Decimal::ZERO;
+        let bankruptcy = self.bankruptcy_price(&position, health, mark_price);
+        let (close_size, limit) = if slicing {
+            let limit = Self::unwind_limit(close_side, bankruptcy, mark_price, liq_params);
+            (close_size.min(self.unwind_slice_size(market_id, close_side, limit, mark_price, liq_params)), limit)
+        } else {
+            (close_size, bankruptcy)
+        };
+        let fills = if close_size > Decimal::ZERO {
+            self.execute_liquidation_order(account_id, market_id, close_side, close_size, limit)?
+        } else {
+            Vec::new()
+        };
+        let filled: Decimal = fills.iter().map(|f| f.size).sum();
+        let book_value: Decimal = fills.iter().map(|f| f.size * f.price.value()).sum();
+
+        // 16.3: the vault takes what the book left of the close at the bankruptcy price, so there
+        // is nothing left to penalize
+        let full_close = close_size == size;
+        let mut unfilled = close_size - filled;
+        let mut close_size = filled;
+        let mut backstop_size = Decimal::ZERO;
+        let mut adl_size = Decimal::ZERO;
+        let mut deleveraged = false;
+        let mut penalized_value = book_value;
+        let mut exit_value = book_value;
+        if unfilled > Decimal::ZERO && !slicing {
+            if self.backstop_takeover(account_id, market_id, position.side().unwrap(), unfilled, limit) {
+                backstop_size = unfilled;
+            } else if full_close {
+                // 6.2.1: a shortfall the insurance fund can't absorb goes to the other side's
+                // most profitable positions instead, closed against this one at bankruptcy.
+                // the shortfall is estimated as if the rest closed at mark
+                let estimate = book_value + unfilled * mark_price.value();
+                let shortfall =
+                    self.uncovered_shortfall(account_id, &position, estimate, book_value, funding_index, liq_params);
+                if liq_params.bad_debt_mode == BadDebtMode::AutoDeleverage && should_trigger_adl(shortfall, &self.config.adl) {
+                    adl_size = self.auto_deleverage(account_id, market_id, position.side().unwrap(), unfilled, limit);
+                    deleveraged = true;
+                } else {
+                    // past bankruptcy the book is taken down to mark, the loss is bad debt
+                    let fills = self.execute_liquidation_order(account_id, market_id, close_side, unfilled, mark_price)?;
+                    let value: Decimal = fills.iter().map(|f| f.size * f.price.value()).sum();
+                    let taken: Decimal = fills.iter().map(|f| f.size).sum();
+                    close_size += taken;
+                    unfilled -= taken;
+                    penalized_value += value;
+                    exit_value += value;
+                }
+            }
+            // with nothing else to take it, the rest goes to the other side's profitable
+            // positions at bankruptcy rather than staying open. one round per check
+            if backstop_size.is_zero() && !deleveraged && unfilled > Decimal::ZERO {
+                adl_size = self.auto_deleverage(account_id, market_id, position.side().unwrap(), unfilled, limit);
+            }
+            close_size += backstop_size + adl_size;
+            exit_value += (backstop_size + adl_size) * limit.value();
+        }
+        if close_size.is_zero() {
+            if slicing {
+                let now = self.current_time;
+                self.liquidation_unwinds.get_mut(&(account_id, market_id)).unwrap().defer(now);
+            }
+            return Err(EngineError::LiquidationUnfilled { account: account_id, market: market_id });
+        }
+        let closed = SignedSize::new(if position.size.is_long() { close_size } else { -close_size });
+        let remaining = SignedSize::new(position.size.value() - closed.value());
+        let exit_price = Price::new_unchecked(exit_value / close_size);
+
+        let entry_value = close_size * position.entry_price.value();
+        let realized_pnl = Quote::new(if position.size.is_long() { exit_value - entry_value } else { entry_value - exit_value });
+        let trader = LedgerAccount::Trader(account_id);
+        let mut bad_debt = Quote::zero();
         let mut events_to_emit: Vec<EventPayload> = Vec::new();
 
-        {
-            let account = self
-                .accounts
-                .get_mut(&account_id)
-                .ok_or(EngineError::AccountNotFound(account_id))?;
+        // 6.4: the penalty comes out of what the close leaves, never out of bad debt
+        let equity = position.collateral.value() + realized_pnl.value() - position.pending_funding(funding_index).value();
+        let available = match (remaining.is_zero(), portfolio_margin) {
+            (true, true) => self.accounts[&account_id].balance.value() + equity,
+            (true, false) => equity,
+            (false, _) => position.collateral.value() + realized_pnl.value(),
+        };
+        let penalty = calculate_liquidation_penalty(Quote::new(penalized_value), liq_params)
+            .capped(Quote::new(available), liq_params);
+
+        if remaining.is_zero() {
+            let remaining_equity = if portfolio_margin {
+                self.accounts[&account_id].balance.value() + equity - penalty.total.value()
+            } else {
+                equity - penalty.total.value()
+            };
+            if remaining_equity < Decimal::ZERO {
+                bad_debt = Quote::new(-remaining_equity);
+            }
 
-            if remaining_equity > Decimal::ZERO {
+            let account = self.accounts.get_mut(&account_id).unwrap();
+            if portfolio_margin {
+                account.balance = Quote::new(remaining_equity.max(Decimal::ZERO));
+            } else if remaining_equity > Decimal::ZERO {
                 account.return_collateral(Quote::new(remaining_equity));
             }
-
             account.remove_position(market_id);
+
+            self.post(
+                LedgerAccount::Settlement(market_id),
+                trader,
+                Quote::new(equity - position.collateral.value()),
+                EntryKind::RealizedPnl,
+            );
+        } else {
+            // the closed part's pnl and penalty settle into the collateral backing the rest
+            let now = self.current_time;
+            if let Some(kept) = self.accounts.get_mut(&account_id).and_then(|a| a.get_position_mut(market_id)) {
+                kept.size = remaining;
+                kept.collateral = Quote::new(kept.collateral.value() + realized_pnl.value() - penalty.total.value());
+                kept.realized_pnl = kept.realized_pnl.add(realized_pnl);
+                kept.updated_at = now;
+            }
+            self.post(LedgerAccount::Settlement(market_id), trader, realized_pnl, EntryKind::RealizedPnl);
         }
 
+        self.pay_liquidator(account_id, penalty.liquidator_reward, liquidator);
+
         if bad_debt.value() > Decimal::ZERO {
-            let covered = self.insurance_fund.cover_bad_debt(bad_debt);
+            let covered = self.cover_from_insurance(trader, Some(market_id), bad_debt);
             let uncovered = Quote::new(bad_debt.value() - covered.value());
+            // 6.2.3: socializing markets recover what they can from the other side's profit
+            let recovered = if liq_params.bad_debt_mode == BadDebtMode::Socialize {
+                self.socialize_loss(account_id, market_id, position.side().unwrap(), uncovered)
+            } else {
+                Quote::zero()
+            };
+            self.post(LedgerAccount::SocializedLoss, trader, uncovered.sub(recovered), EntryKind::DebtSocialized);
 
             if uncovered.value() > Decimal::ZERO {
                 events_to_emit.push(EventPayload::BadDebt(BadDebtEvent {
@@ -128,29 +524,57 @@ impl Engine {
             }
         }
 
-        self.insurance_fund.deposit(penalty.insurance_contribution);
+        self.credit_penalty(trader, market_id, penalty.insurance_contribution);
 
         let market = self.markets.get_mut(&market_id).unwrap();
         match position.side().unwrap() {
-            Side::Long => market.update_open_interest(-position.size.abs(), Decimal::ZERO),
-            Side::Short => market.update_open_interest(Decimal::ZERO, -position.size.abs()),
+            Side::Long => market.update_open_interest(-close_size, Decimal::ZERO),
+            Side::Short => market.update_open_interest(Decimal::ZERO, -close_size),
         }
-
-        let realized_pnl = position.unrealized_pnl(mark_price);
+        market.track_holder(account_id, !remaining.is_zero());
 
         events_to_emit.push(EventPayload::Liquidation(LiquidationEvent {
             market_id,
             account_id,
-            liquidated_size: position.size,
-            liquidation_price: mark_price,
+            liquidated_size: closed,
+            liquidation_price: exit_price,
             penalty: penalty.total,
-            liquidator_account: None,
+            liquidator_account: liquidator,
+            remaining_size: remaining,
+            backstop_size,
         }));
 
         for event in events_to_emit {
             self.emit_event(event);
         }
 
+        let now = self.current_time;
+        if let Some(unwind) = self.liquidation_unwinds.get_mut(&(account_id, market_id)) {
+            if slicing {
+                unwind.record_slice(close_size, now);
+                let slice = unwind.slices;
+                self.emit_event(EventPayload::LiquidationUnwindSlice(LiquidationUnwindSliceEvent {
+                    market_id,
+                    account_id,
+                    slice,
+                    size: close_size,
+                    filled,
+                    remaining,
+                }));
+            }
+            if remaining.is_zero() {
+                let reason = if slicing { UnwindEndReason::Closed } else { UnwindEndReason::Bankrupt };
+                self.end_unwind(account_id, market_id, reason);
+            }
+        }
+
+        self.refresh_order_margin(account_id, market_id);
+        if remaining.is_zero() {
+            self.margin_calls.remove(&(account_id, market_id));
+            // debt carried from before the liquidation is written off once the account is empty
+            self.settle_debt(account_id);
+        }
+
         // Emit OI snapshot after liquidation
         let market = self.markets.get(&market_id).unwrap();
         self.emit_event(EventPayload::OiUpdated(OiUpdatedEvent {
@@ -163,13 +587,92 @@ impl Engine {
         Ok(LiquidationResult {
             account_id,
             market_id,
-            position_size: position.size,
-            liquidation_price: mark_price,
+            position_size: closed,
+            liquidation_price: exit_price,
             penalty: penalty.total,
             bad_debt,
             realized_pnl,
+            remaining_size: remaining,
+            backstop_size,
+            adl_size,
+            liquidator,
         })
     }
+
+    // what a full close at these values would leave once the insurance fund has paid out
+    fn uncovered_shortfall(
+        &self,
+        account_id: AccountId,
+        position: &Position,
+        exit_value: Decimal,
+        penalized_value: Decimal,
+        funding_index: Decimal,
+        liq_params: &LiquidationParams,
+    ) -> Quote {
+        let entry_value = position.size.abs() * position.entry_price.value();
+        let pnl = if position.size.is_long() { exit_value - entry_value } else { entry_value - exit_value };
+        let account = &self.accounts[&account_id];
+        let mut equity = position.collateral.value() + pnl - position.pending_funding(funding_index).value();
+        if account.is_portfolio_margin() {
+            equity += account.balance.value();
+        }
+        let penalty = calculate_liquidation_penalty(Quote::new(penalized_value), liq_params);
+        equity -= penalty.capped(Quote::new(equity), liq_params).total.value();
+        Quote::new((-equity - self.insurance_available(position.market_id).value()).max(Decimal::ZERO))
+    }
+
+    // units to close this step, rounded up to the lot. None when the step would take the
+    // whole position or leave less than a minimum order behind
+    fn partial_close_size(
+        &self,
+        position: &Position,
+        equity: Quote,
+        margin_req: &MarginRequirement,
+        mark_price: Price,
+        liq_params: &LiquidationParams,
+    ) -> Option<Decimal> {
+        let config = &self.markets.get(&position.market_id)?.config;
+        let notional = position.notional_value(mark_price);
+        if notional.value().is_zero() {
+            return None;
+        }
+
+        let target_fraction = liq_params.restore_to.requirement(margin_req).value() / notional.value();
+        let amount = calculate_liquidation_amount(position.size, mark_price, equity, target_fraction, liq_params);
+        let close_size = (amount / config.lot_size).ceil() * config.lot_size;
+
+        let size = position.size.abs();
+        (close_size > Decimal::ZERO && size - close_size >= config.min_order_size).then_some(close_size)
+    }
+
+    // price where the position's equity reaches zero, equity moves by size per unit of price.
+    // rounded to the tick on the side that never fills past it
+    fn bankruptcy_price(&self, position: &Position, equity: Quote, mark_price: Price) -> Price {
+        let tick = self.markets[&position.market_id].config.tick_size;
+        let price = mark_price.value() - equity.value() / position.size.value();
+        let rounded = if position.size.is_long() {
+            (price / tick).ceil() * tick
+        } else {
+            (price / tick).floor() * tick
+        };
+        Price::new_unchecked(rounded.max(tick))
+    }
+
+    // the liquidator's share goes to whoever called liquidate, the treasury when the engine
+    // liquidated on its own
+    fn pay_liquidator(&mut self, account_id: AccountId, reward: Quote, liquidator: Option<AccountId>) {
+        let trader = LedgerAccount::Trader(account_id);
+        match liquidator.and_then(|id| self.accounts.get_mut(&id).map(|a| (id, a))) {
+            Some((id, account)) => {
+                account.balance = account.balance.add(reward);
+                self.post(trader, LedgerAccount::Trader(id), reward, EntryKind::LiquidatorReward);
+            }
+            None => {
+                self.post(trader, LedgerAccount::Treasury, reward, EntryKind::LiquidationPenalty);
+                self.treasury = self.treasury.add(reward);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -178,6 +681,7 @@ mod tests {
     use crate::engine::EngineConfig;
     use crate::market::MarketConfig;
     use crate::order::TimeInForce;
+    use crate::types::Leverage;
     use rust_decimal_macros::dec;
 
     fn setup_engine() -> Engine {
@@ -186,6 +690,13 @@ mod tests {
         engine
     }
 
+    // vault with `amount` to take over what the book can't
+    fn fund_backstop(engine: &mut Engine, amount: Decimal) {
+        let depositor = engine.create_account();
+        engine.deposit(depositor, Quote::new(amount)).unwrap();
+        engine.deposit_to_backstop(depositor, Quote::new(amount)).unwrap();
+    }
+
     #[test]
     fn create_account_and_deposit() {
         let mut engine = setup_engine();
@@ -302,6 +813,320 @@ mod tests {
         assert!(engine.get_account(buyer).unwrap().get_position(MarketId(1)).is_none());
     }
 
+    #[test]
+    fn liquidation_steps_down_until_bankrupt() {
+        let mut engine = setup_engine();
+
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+
+        engine.deposit(buyer, Quote::new(dec!(10000))).unwrap();
+        engine.deposit(seller, Quote::new(dec!(100000))).unwrap();
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, MarketId(1), Leverage::new(dec!(10)).unwrap()).unwrap();
+
+        engine
+            .place_limit_order(seller, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, MarketId(1), Side::Long, dec!(1)).unwrap();
+
+        let position = |engine: &Engine| engine.get_account(buyer).unwrap().get_position(MarketId(1)).cloned();
+        let liquidation_events = |engine: &Engine| {
+            engine.events().iter().filter(|e| matches!(e.payload, EventPayload::Liquidation(_))).count()
+        };
+
+        // 5000 collateral, equity 2300 vs 2365 MM. closing q leaves 2300 - 473q >= (1 - q) * 4730
+        let bidder = engine.create_account();
+        engine.deposit(bidder, Quote::new(dec!(100000))).unwrap();
+        engine.set_leverage(bidder, MarketId(1), Leverage::new(dec!(1)).unwrap()).unwrap();
+        engine
+            .place_limit_order(bidder, MarketId(1), Side::Long, dec!(0.5709), Price::new_unchecked(dec!(47300)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(47300))).unwrap();
+        let first = engine.check_liquidations(MarketId(1)).unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].bad_debt.value(), dec!(0));
+        let kept = position(&engine).unwrap();
+        assert_eq!(kept.size, first[0].remaining_size);
+        assert_eq!(first[0].position_size.value(), dec!(0.5709));
+        assert_eq!(kept.size.value(), dec!(0.4291));
+        assert_eq!(liquidation_events(&engine), 1);
+        assert!(engine.check_liquidations(MarketId(1)).unwrap().is_empty());
+
+        // past bankruptcy the rest closes in one go, to the vault
+        fund_backstop(&mut engine, dec!(100000));
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(30000))).unwrap();
+        let last = engine.check_liquidations(MarketId(1)).unwrap();
+        assert_eq!(last[0].position_size, kept.size);
+        assert_eq!(last[0].remaining_size, SignedSize::zero());
+        assert!(position(&engine).is_none());
+        assert_eq!(liquidation_events(&engine), 2);
+    }
+
+    #[test]
+    fn oversized_liquidation_unwinds_in_slices() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let mut config = MarketConfig::btc_perp();
+        config.liquidation_params.unwind_threshold = Quote::new(dec!(100000));
+        engine.add_market(config);
+        let market = MarketId(1);
+
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        let bidder = engine.create_account();
+        for (account, amount) in [(buyer, dec!(50000)), (seller, dec!(500000)), (bidder, dec!(500000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(5), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(5)).unwrap();
+        engine
+            .place_limit_order(bidder, market, Side::Long, dec!(4), Price::new_unchecked(dec!(45600)), TimeInForce::GTC)
+            .unwrap();
+
+        // 230k notional is over the threshold: a quarter of the 4 bid within 1% goes first
+        engine.update_index_price(market, Price::new_unchecked(dec!(46000))).unwrap();
+        let first = engine.check_liquidations(market).unwrap();
+        assert_eq!(first[0].position_size.value(), dec!(1));
+        assert_eq!(first[0].remaining_size.value(), dec!(4));
+
+        // the engine holds the position until the next interval
+        assert!(engine.check_liquidations(market).unwrap().is_empty());
+        assert!(matches!(
+            engine.place_market_order(buyer, market, Side::Short, dec!(1)),
+            Err(EngineError::UnwindInProgress { .. })
+        ));
+        assert!(matches!(
+            engine.liquidate(buyer, market, seller),
+            Err(EngineError::UnwindInProgress { .. })
+        ));
+
+        engine.advance_time(30_000);
+        let second = engine.check_liquidations(market).unwrap();
+        assert_eq!(second[0].position_size.value(), dec!(0.75));
+
+        // back above maintenance, the rest is the account's again
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        assert!(engine.check_liquidations(market).unwrap().is_empty());
+        assert_eq!(engine.get_account(buyer).unwrap().get_position(market).unwrap().size.value(), dec!(3.25));
+        assert!(engine.place_market_order(buyer, market, Side::Short, dec!(0.1)).is_ok());
+
+        let stages: Vec<_> = engine
+            .events()
+            .iter()
+            .filter_map(|e| match &e.payload {
+                EventPayload::LiquidationUnwindStarted(_) => Some("started".to_string()),
+                EventPayload::LiquidationUnwindSlice(s) => Some(format!("slice {} {}", s.slice, s.size.normalize())),
+                EventPayload::LiquidationUnwindEnded(end) => Some(format!("{:?} {}", end.reason, end.closed.normalize())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stages, vec!["started", "slice 1 1", "slice 2 0.75", "Restored 1.75"]);
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn unwind_waits_for_depth_inside_the_slice_limit() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let mut config = MarketConfig::btc_perp();
+        config.liquidation_params.unwind_threshold = Quote::new(dec!(100000));
+        engine.add_market(config);
+        let market = MarketId(1);
+
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        let bidder = engine.create_account();
+        for (account, amount) in [(buyer, dec!(50000)), (seller, dec!(500000)), (bidder, dec!(500000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(5), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(5)).unwrap();
+        // above the 45000 bankruptcy price but outside the 1% band under a 46000 mark
+        engine
+            .place_limit_order(bidder, market, Side::Long, dec!(4), Price::new_unchecked(dec!(45500)), TimeInForce::GTC)
+            .unwrap();
+
+        // nothing inside the slice's limit: no slice, nothing closes, and the next try waits
+        engine.update_index_price(market, Price::new_unchecked(dec!(46000))).unwrap();
+        assert!(engine.check_liquidations(market).unwrap().is_empty());
+        assert_eq!(engine.get_account(buyer).unwrap().get_position(market).unwrap().size.value(), dec!(5));
+        let state = engine.get_market(market).unwrap();
+        assert_eq!(state.open_interest_long, state.open_interest_short);
+        engine
+            .place_limit_order(bidder, market, Side::Long, dec!(4), Price::new_unchecked(dec!(45600)), TimeInForce::GTC)
+            .unwrap();
+        assert!(engine.check_liquidations(market).unwrap().is_empty());
+
+        // a quarter of the 4 inside the band goes once the interval is up, the 45500 bid is untouched
+        engine.advance_time(30_000);
+        let first = engine.check_liquidations(market).unwrap();
+        assert_eq!(first[0].position_size.value(), dec!(1));
+        assert_eq!(first[0].liquidation_price.value(), dec!(45600));
+        let state = engine.get_market(market).unwrap();
+        assert_eq!(state.open_interest_long, state.open_interest_short);
+
+        let slices: Vec<u32> = engine
+            .events()
+            .iter()
+            .filter_map(|e| match &e.payload {
+                EventPayload::LiquidationUnwindSlice(s) => Some(s.slice),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(slices, vec![1]);
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn bankrupt_close_pays_penalty_only_from_what_is_left() {
+        let mut engine = setup_engine();
+        let market = MarketId(1);
+        engine.fund_insurance(Quote::new(dec!(10000)));
+
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        let bidder = engine.create_account();
+        let liquidator = engine.create_account();
+        for (account, amount) in [(buyer, dec!(10000)), (seller, dec!(100000)), (bidder, dec!(100000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+
+        // underwater at 44000, but a bid just over the 45000 bankruptcy price takes the close
+        engine
+            .place_limit_order(bidder, market, Side::Long, dec!(1), Price::new_unchecked(dec!(45100)), TimeInForce::GTC)
+            .unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(44000))).unwrap();
+
+        let before = engine.get_account(liquidator).unwrap().balance.value();
+        let result = engine.liquidate(buyer, market, liquidator).unwrap();
+
+        // 100 of equity left against a 451 penalty: the penalty is the 100, nothing is owed
+        assert_eq!(result.liquidation_price.value(), dec!(45100));
+        assert_eq!(result.penalty.value(), dec!(100));
+        assert!(result.bad_debt.value().is_zero());
+        assert_eq!(engine.get_account(liquidator).unwrap().balance.value() - before, dec!(50));
+        assert_eq!(engine.insurance_fund_balance().value(), dec!(10000));
+        assert_eq!(engine.market_insurance_balance(market).unwrap().value(), dec!(50));
+        let flows = engine.insurance_history(Some(market), 10).unwrap();
+        assert!(flows.iter().all(|f| f.kind != crate::liquidation::InsuranceFlowKind::Payout));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn liquidator_closes_through_book_and_deleverages_the_rest() {
+        let mut engine = setup_engine();
+
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        let bidder = engine.create_account();
+        let liquidator = engine.create_account();
+
+        engine.deposit(buyer, Quote::new(dec!(10000))).unwrap();
+        engine.deposit(seller, Quote::new(dec!(100000))).unwrap();
+        engine.deposit(bidder, Quote::new(dec!(100000))).unwrap();
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, MarketId(1), Leverage::new(dec!(10)).unwrap()).unwrap();
+
+        engine
+            .place_limit_order(seller, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, MarketId(1), Side::Long, dec!(1)).unwrap();
+        // a resting buyer bid would otherwise match its own liquidation
+        engine
+            .place_limit_order(buyer, MarketId(1), Side::Long, dec!(0.1), Price::new_unchecked(dec!(40000)), TimeInForce::GTC)
+            .unwrap();
+
+        // healthy positions can't be taken
+        let err = engine.liquidate(buyer, MarketId(1), liquidator).unwrap_err();
+        assert!(matches!(err, EngineError::NotLiquidatable { .. }));
+
+        // bankruptcy at 45000 (5000 collateral on 1 BTC), so the 44000 bid is out of reach
+        for (size, price) in [(dec!(0.3), dec!(47200)), (dec!(1), dec!(44000))] {
+            engine
+                .place_limit_order(bidder, MarketId(1), Side::Long, size, Price::new_unchecked(price), TimeInForce::GTC)
+                .unwrap();
+        }
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(47300))).unwrap();
+
+        let err = engine.liquidate(buyer, MarketId(1), buyer).unwrap_err();
+        assert!(matches!(err, EngineError::SelfLiquidation(_)));
+
+        let before = engine.get_account(liquidator).unwrap().balance.value();
+        let result = engine.liquidate(buyer, MarketId(1), liquidator).unwrap();
+
+        // the unfunded vault can't take the other 0.2709, the seller's short is deleveraged instead
+        assert_eq!(result.liquidator, Some(liquidator));
+        assert_eq!(result.position_size.value(), dec!(0.5709));
+        assert_eq!(result.remaining_size.value(), dec!(0.4291));
+        assert!(result.backstop_size.is_zero());
+        assert_eq!(result.adl_size, dec!(0.2709));
+        // 0.3 at 47200 and 0.2709 at the 45000 bankruptcy price, against a 50000 entry
+        assert_eq!(result.realized_pnl.value(), dec!(-2194.5));
+        let state = engine.get_market(MarketId(1)).unwrap();
+        assert_eq!(state.open_interest_long, state.open_interest_short);
+        let bidder_position = engine.get_account(bidder).unwrap().get_position(MarketId(1)).unwrap().size;
+        assert_eq!(bidder_position.value(), dec!(0.3));
+
+        let reward = engine.get_account(liquidator).unwrap().balance.value() - before;
+        assert_eq!(reward, result.penalty.value() / dec!(2));
+        assert!(engine.get_market(MarketId(1)).unwrap().order_book.orders_for_account(buyer).next().is_none());
+
+        let event = engine.events().iter().rev().find_map(|e| match &e.payload {
+            EventPayload::Liquidation(liq) => Some(liq.clone()),
+            _ => None,
+        });
+        assert_eq!(event.unwrap().liquidator_account, Some(liquidator));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn unfunded_vault_falls_back_to_deleveraging_at_bankruptcy() {
+        let mut engine = setup_engine();
+        let market = MarketId(1);
+
+        let buyer = engine.create_account();
+        let seller = engine.create_account();
+        engine.deposit(buyer, Quote::new(dec!(10000))).unwrap();
+        engine.deposit(seller, Quote::new(dec!(100000))).unwrap();
+        engine.update_index_price(market, Price::new_unchecked(dec!(50000))).unwrap();
+        engine.set_leverage(buyer, market, Leverage::new(dec!(10)).unwrap()).unwrap();
+        engine
+            .place_limit_order(seller, market, Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(buyer, market, Side::Long, dec!(1)).unwrap();
+
+        // bankrupt, an empty book and nothing in the vault. insurance would cover the shortfall,
+        // so ADL isn't triggered, but the seller's short is the only thing left to close against
+        engine.fund_insurance(Quote::new(dec!(100000)));
+        engine.update_index_price(market, Price::new_unchecked(dec!(44000))).unwrap();
+        let result = engine.check_liquidations(market).unwrap().remove(0);
+        assert!(result.backstop_size.is_zero());
+        assert_eq!(result.adl_size, dec!(1));
+        assert_eq!(result.liquidation_price.value(), dec!(45000));
+        assert!(result.bad_debt.value().is_zero());
+
+        assert!(engine.get_account(buyer).unwrap().get_position(market).is_none());
+        assert!(engine.get_account(seller).unwrap().get_position(market).is_none());
+        let state = engine.get_market(market).unwrap();
+        assert_eq!(state.open_interest_long, state.open_interest_short);
+        assert!(state.open_interest_long.is_zero());
+        assert_eq!(engine.insurance_fund_balance().value(), dec!(100000));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
     #[test]
     fn funding_settlement() {
         let mut engine = setup_engine();
@@ -337,4 +1162,216 @@ mod tests {
         let net = funding_result.total_long_payments.value() + funding_result.total_short_payments.value();
         assert!(net.abs() < dec!(0.01));
     }
+
+    // trader long 1 BTC at 10x with nothing left in balance, funding forced to `rate` per period
+    fn setup_funding_payer(rate: Decimal) -> (Engine, AccountId) {
+        let mut engine = Engine::new(EngineConfig::default());
+        let mut market = MarketConfig::btc_perp();
+        market.funding_params.interest_rate = rate;
+        market.funding_params.max_rate = rate;
+        engine.add_market(market);
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let trader = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(trader, Quote::new(dec!(5025))).unwrap();
+        engine.deposit(maker, Quote::new(dec!(1000000))).unwrap();
+        engine
+            .set_leverage(trader, MarketId(1), crate::types::Leverage::new(dec!(10)).unwrap())
+            .unwrap();
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(1)).unwrap();
+        assert!(engine.get_account(trader).unwrap().balance.value().is_zero());
+
+        engine.advance_time(8 * 60 * 60 * 1000);
+        (engine, trader)
+    }
+
+    #[test]
+    fn unaffordable_funding_taken_from_position_collateral() {
+        let (mut engine, trader) = setup_funding_payer(dec!(0.01));
+        let result = engine.settle_funding(MarketId(1)).unwrap();
+        engine.settle_position_funding(trader, MarketId(1)).unwrap();
+
+        // payer is charged in full, so receivers plus LP cut still match it
+        let paid = result.total_long_payments.value();
+        assert!(paid > Decimal::ZERO);
+        let received = -result.total_short_payments.value() * (Decimal::ONE - dec!(0.10));
+        assert_eq!(paid, received + result.lp_fee_collected.value());
+
+        let account = engine.get_account(trader).unwrap();
+        assert!(account.balance.value().is_zero());
+        assert_eq!(
+            account.get_position(MarketId(1)).unwrap().collateral.value(),
+            dec!(5000) - paid
+        );
+    }
+
+    #[test]
+    fn liquidation_realizes_funding_before_closing() {
+        let (mut engine, trader) = setup_funding_payer(dec!(0.2));
+        engine.fund_insurance(Quote::new(dec!(100000)));
+        fund_backstop(&mut engine, dec!(100000));
+        engine.settle_funding(MarketId(1)).unwrap();
+        assert!(engine.get_account(trader).unwrap().balance.value().is_zero());
+
+        // nothing touched the position since settlement, the liquidation picks the funding up first
+        let liquidations = engine.check_liquidations(MarketId(1)).unwrap();
+        assert_eq!(liquidations.len(), 1);
+        let kind = |payload: &EventPayload| match payload {
+            EventPayload::FundingSettled(ev) if ev.account_id == trader => Some(0),
+            EventPayload::Liquidation(ev) if ev.account_id == trader => Some(1),
+            _ => None,
+        };
+        let order: Vec<u8> = engine.events().iter().filter_map(|e| kind(&e.payload)).collect();
+        assert_eq!(order.first(), Some(&0));
+        assert!(order.contains(&1));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn residual_debt_counts_toward_liquidation_and_is_paid_by_the_close() {
+        let (mut engine, trader) = setup_funding_payer(dec!(0.2));
+        engine.fund_insurance(Quote::new(dec!(100000)));
+        fund_backstop(&mut engine, dec!(100000));
+        engine.settle_funding(MarketId(1)).unwrap();
+        engine.settle_position_funding(trader, MarketId(1)).unwrap();
+
+        // ~10k owed against 5k of collateral: the rest stays as debt
+        let debt = engine.get_account(trader).unwrap().debt();
+        assert_eq!(debt.value(), dec!(5000));
+        assert!(engine
+            .events()
+            .iter()
+            .any(|e| matches!(e.payload, EventPayload::NegativeQuoteBalance(_))));
+
+        // the debt moves the bankruptcy price to 55000, where the vault takes the position
+        let liquidations = engine.check_liquidations(MarketId(1)).unwrap();
+        assert_eq!(liquidations.len(), 1);
+        assert_eq!(liquidations[0].liquidation_price.value(), dec!(55000));
+        assert!(liquidations[0].bad_debt.value().is_zero());
+
+        assert!(!engine
+            .events()
+            .iter()
+            .any(|e| matches!(e.payload, EventPayload::DebtWrittenOff(_))));
+        assert!(engine.get_account(trader).unwrap().balance.value().is_zero());
+        assert_eq!(engine.insurance_fund_balance().value(), dec!(100000));
+        assert!(engine.conservation_report().is_conserved());
+    }
+
+    #[test]
+    fn margin_call_raised_once_and_cleared_with_hysteresis() {
+        let mut engine = setup_engine();
+        let trader = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(trader, Quote::new(dec!(1000))).unwrap();
+        engine.deposit(maker, Quote::new(dec!(100000))).unwrap();
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        engine
+            .set_leverage(trader, MarketId(1), crate::types::Leverage::new(dec!(10)).unwrap())
+            .unwrap();
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(0.1), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(trader, MarketId(1), Side::Long, dec!(0.1)).unwrap();
+
+        // 500 collateral, MM 0.5% of notional at mark. at risk below ~47870, clears above ~48650
+        let count = |engine: &Engine| {
+            let calls = engine.events().iter().filter(|e| matches!(e.payload, EventPayload::MarginCall(_))).count();
+            let clears = engine
+                .events()
+                .iter()
+                .filter(|e| matches!(e.payload, EventPayload::MarginCallCleared(_)))
+                .count();
+            (calls, clears)
+        };
+
+        for (price, expected) in [
+            (dec!(47800), (1, 0)),
+            (dec!(47500), (1, 0)), // still in the band, no repeat
+            (dec!(48300), (1, 0)), // out of the band but short of the clear ratio
+            (dec!(47800), (1, 0)),
+            (dec!(48700), (1, 1)),
+            (dec!(47800), (2, 1)),
+        ] {
+            engine.update_index_price(MarketId(1), Price::new_unchecked(price)).unwrap();
+            assert_eq!(count(&engine), expected, "at {}", price);
+        }
+        assert!(engine.has_margin_call(trader, MarketId(1)));
+    }
+
+    #[test]
+    fn margin_calls_tracked_per_account_and_rearmed_after_recovery() {
+        let mut engine = setup_engine();
+        let tight = engine.create_account();
+        let loose = engine.create_account();
+        let maker = engine.create_account();
+        for (account, amount) in [(tight, dec!(1000)), (loose, dec!(2000)), (maker, dec!(100000))] {
+            engine.deposit(account, Quote::new(amount)).unwrap();
+        }
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+        engine
+            .set_leverage(tight, MarketId(1), crate::types::Leverage::new(dec!(10)).unwrap())
+            .unwrap();
+        engine
+            .set_leverage(loose, MarketId(1), crate::types::Leverage::new(dec!(5)).unwrap())
+            .unwrap();
+
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Short, dec!(0.2), Price::new_unchecked(dec!(50000)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(tight, MarketId(1), Side::Long, dec!(0.1)).unwrap();
+        engine.place_market_order(loose, MarketId(1), Side::Long, dec!(0.1)).unwrap();
+
+        let called = |engine: &Engine| -> Vec<AccountId> {
+            engine
+                .events()
+                .iter()
+                .filter_map(|e| match &e.payload {
+                    EventPayload::MarginCall(call) => Some(call.account_id),
+                    _ => None,
+                })
+                .collect()
+        };
+        let cleared = |engine: &Engine| {
+            engine
+                .events()
+                .iter()
+                .filter(|e| matches!(e.payload, EventPayload::MarginCallCleared(_)))
+                .count()
+        };
+
+        // 500 vs 1000 of collateral: the 10x long is called first, the 5x one only much lower
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(47800))).unwrap();
+        assert_eq!(called(&engine), vec![tight]);
+        assert!(!engine.has_margin_call(loose, MarketId(1)));
+
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(42000))).unwrap();
+        assert_eq!(called(&engine), vec![tight, loose]);
+
+        // both recover past the clear ratio, then only the tight one falls back into the band
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(49000))).unwrap();
+        assert_eq!(cleared(&engine), 2);
+        assert!(!engine.has_margin_call(tight, MarketId(1)));
+        assert!(!engine.has_margin_call(loose, MarketId(1)));
+
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(47800))).unwrap();
+        assert_eq!(called(&engine), vec![tight, loose, tight]);
+        assert!(engine.has_margin_call(tight, MarketId(1)));
+        assert!(!engine.has_margin_call(loose, MarketId(1)));
+
+        // a call on a position that has since closed is dropped on the next mark
+        engine
+            .place_limit_order(maker, MarketId(1), Side::Long, dec!(0.1), Price::new_unchecked(dec!(47800)), TimeInForce::GTC)
+            .unwrap();
+        engine.place_market_order(tight, MarketId(1), Side::Short, dec!(0.1)).unwrap();
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(47700))).unwrap();
+        assert!(!engine.has_margin_call(tight, MarketId(1)));
+        assert_eq!(engine.get_market(MarketId(1)).unwrap().position_holders.len(), 2);
+    }
 }
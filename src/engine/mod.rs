@@ -9,6 +9,9 @@ mod positions;
 mod pricing;
 mod funding;
 mod liquidations;
+mod backstop;
+mod adl;
+mod insurance;
 mod results;
 
 pub use config::EngineConfig;
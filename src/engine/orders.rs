@@ -2,10 +2,15 @@
 
 use super::core::Engine;
 use super::results::{EngineError, OrderResult};
-use crate::events::{CancelReason, EventPayload, FillEvent, OiUpdatedEvent, OrderCanceledEvent, OrderPlacedEvent};
-use crate::margin::calculate_margin_requirement;
-use crate::market::MarketConfig;
+use crate::events::{
+    CancelReason, EventPayload, FillEvent, OiUpdatedEvent, OrderCanceledEvent, OrderPlacedEvent, ReferralRewardEvent,
+};
+use crate::account::Account;
+use crate::ledger::{EntryKind, LedgerAccount};
+use crate::margin::{calculate_open_order_margin, RestingSide};
+use crate::market::{MarketConfig, MarketState};
 use crate::order::{match_order, Fill, Order, TimeInForce, OrderType};
+use crate::referral::ReferralPayout;
 use crate::types::{AccountId, MarketId, OrderId, Price, Quote, Side, SignedSize};
 use rust_decimal::Decimal;
 
@@ -38,6 +43,9 @@ impl Engine {
         if !self.accounts.contains_key(&account_id) {
             return Err(EngineError::AccountNotFound(account_id));
         }
+        if self.liquidation_unwinds.contains_key(&(account_id, market_id)) {
+            return Err(EngineError::UnwindInProgress { account: account_id, market: market_id });
+        }
 
         market.config.validate_size(size).map_err(EngineError::Market)?;
 
@@ -87,6 +95,9 @@ impl Engine {
         if !self.accounts.contains_key(&account_id) {
             return Err(EngineError::AccountNotFound(account_id));
         }
+        if self.liquidation_unwinds.contains_key(&(account_id, market_id)) {
+            return Err(EngineError::UnwindInProgress { account: account_id, market: market_id });
+        }
 
         market.config.validate_size(size).map_err(EngineError::Market)?;
         let validated_price = market.config.validate_price(price).map_err(EngineError::Market)?;
@@ -133,6 +144,8 @@ impl Engine {
             reason: CancelReason::UserRequested,
         }));
 
+        self.refresh_order_margin(order.account_id, market_id);
+
         Ok(())
     }
 
@@ -145,6 +158,45 @@ impl Engine {
         let order_type = order.order_type;
         let time_in_force = order.time_in_force;
 
+        // portfolio accounts reserve nothing per fill, so the whole order is checked up front.
+        // so is anything that takes without resting, which otherwise could only fail halfway
+        // through matching. checked at the worst price it can fill at: the limit, or as far as
+        // a market order would sweep the book. orders that only reduce a position lock nothing
+        let account = self.accounts.get(&account_id).ok_or(EngineError::AccountNotFound(account_id))?;
+        let portfolio = account.is_portfolio_margin();
+        let reduces = account.get_position(market_id).is_some_and(|p| {
+            p.side() == Some(order_side.opposite()) && p.size.abs() >= order.size
+        });
+        let takes_only = order_type == OrderType::Market || matches!(time_in_force, TimeInForce::IOC | TimeInForce::FOK);
+        if (portfolio || takes_only) && !reduces {
+            let check_price = order.price.or_else(|| {
+                self.markets
+                    .get(&market_id)
+                    .and_then(|m| m.order_book.sweep_price(order_side, order.size))
+            });
+            // nothing to sweep means nothing fills
+            let passes = match check_price {
+                Some(price) => self.check_margin_for_order(account_id, market_id, order_side, order.size, price)?,
+                None => true,
+            };
+            if !passes {
+                self.emit_event(EventPayload::OrderCanceled(OrderCanceledEvent {
+                    market_id,
+                    order_id,
+                    account_id,
+                    reason: CancelReason::InsufficientMargin,
+                }));
+                return Ok(OrderResult {
+                    order_id,
+                    filled_size: Decimal::ZERO,
+                    remaining_size: order.size,
+                    average_price: None,
+                    is_posted: false,
+                    fills: Vec::new(),
+                });
+            }
+        }
+
         let market = self
             .markets
             .get_mut(&market_id)
@@ -181,6 +233,7 @@ impl Engine {
                                 resting_order.remaining_size = remaining;
                                 let market = self.markets.get_mut(&market_id).unwrap();
                                 market.order_book.insert(resting_order);
+                                self.refresh_order_margin(account_id, market_id);
                                 true
                             } else {
                                 self.emit_event(EventPayload::OrderCanceled(OrderCanceledEvent {
@@ -202,11 +255,20 @@ impl Engine {
                                     reason: CancelReason::PostOnlyWouldTake,
                                 }));
                                 false
+                            } else if !self.check_margin_for_order(account_id, market_id, order_side, remaining, order.price.unwrap())? {
+                                self.emit_event(EventPayload::OrderCanceled(OrderCanceledEvent {
+                                    market_id,
+                                    order_id,
+                                    account_id,
+                                    reason: CancelReason::InsufficientMargin,
+                                }));
+                                false
                             } else {
                                 let mut resting_order = order.clone();
                                 resting_order.remaining_size = remaining;
                                 let market = self.markets.get_mut(&market_id).unwrap();
                                 market.order_book.insert(resting_order);
+                                self.refresh_order_margin(account_id, market_id);
                                 true
                             }
                         }
@@ -256,61 +318,278 @@ impl Engine {
             Side::Short => SignedSize::new(-size),
         };
 
-        let max_leverage = market.config.margin_params.max_leverage;
-        let margin_req = calculate_margin_requirement(
-            signed_size,
-            price,
-            max_leverage,
-            &market.config.margin_params,
+        // portfolio: post-trade scenario requirement against equity not already held by
+        // resting orders. trades that lower the requirement always pass so an underwater
+        // book can still de-risk
+        if account.is_portfolio_margin() {
+            let equity = match self.account_metrics(account_id) {
+                Ok(metrics) => metrics.total_equity,
+                Err(EngineError::NoMarkPrice(_)) => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            let current = self.portfolio_requirement(account, None);
+            let after = self.portfolio_requirement(account, Some((market_id, signed_size, price)));
+            return Ok(after.initial.value() <= current.initial.value()
+                || equity.value() - account.reserved_order_margin().value() >= after.initial.value());
+        }
+
+        // 10.6: the market's resting orders plus this one, worst side net of the position.
+        // what this market already holds is released back into the pool being compared
+        let required = self.order_margin_for(account, market, Some((side, size, price)));
+        let held = account.order_margin.get(&market_id).copied().unwrap_or(Quote::zero());
+
+        // other collateral is sold into quote when the reservation happens
+        let mut fundable = account.free_collateral().add(held);
+        if self.config.auto_convert_collateral {
+            fundable = fundable.add(self.collateral_value(account));
+        }
+        Ok(fundable.value() >= required.value())
+    }
+
+    // 3.6: margin for the account's resting orders in one market, optionally with an
+    // order that isn't on the book yet
+    fn order_margin_for(&self, account: &Account, market: &MarketState, extra: Option<(Side, Decimal, Price)>) -> Quote {
+        let mut bids = RestingSide::default();
+        let mut asks = RestingSide::default();
+        let resting = market
+            .order_book
+            .orders_for_account(account.id)
+            .filter_map(|o| o.price.map(|price| (o.side, o.remaining_size, price)));
+
+        for (side, size, price) in resting.chain(extra) {
+            let totals = match side {
+                Side::Long => &mut bids,
+                Side::Short => &mut asks,
+            };
+            totals.size += size;
+            totals.notional += size * price.value();
+        }
+
+        let position_size = account
+            .get_position(market.config.id)
+            .map(|p| p.size)
+            .unwrap_or(SignedSize::new(Decimal::ZERO));
+        let params = &market.config.margin_params;
+        let leverage = account.leverage_for(market.config.id, params.max_leverage);
+        calculate_open_order_margin(position_size, bids, asks, leverage, params)
+    }
+
+    // recompute the hold after anything that changes resting orders or the position
+    pub(super) fn refresh_order_margin(&mut self, account_id: AccountId, market_id: MarketId) {
+        let (Some(account), Some(market)) = (self.accounts.get(&account_id), self.markets.get(&market_id)) else {
+            return;
+        };
+        let amount = self.order_margin_for(account, market, None);
+        self.accounts.get_mut(&account_id).unwrap().set_order_margin(market_id, amount);
+    }
+
+    // fee already taken from the account, book it to the treasury. negative = rebate paid out
+    fn collect_fee(&mut self, account_id: AccountId, fee: Quote, kind: EntryKind) {
+        self.treasury = self.treasury.add(fee);
+        self.post(LedgerAccount::Trader(account_id), LedgerAccount::Treasury, fee, kind);
+    }
+
+    // 15.4: credit referred volume and pay the referee's referrers out of the treasury.
+    // level 1 at the referrer's tier rate, level 2 at the flat upstream rate
+    fn pay_referrals(&mut self, referee: AccountId, fee: Quote, notional: Decimal) {
+        let Some(referrer) = self.accounts.get(&referee).and_then(|a| a.referrer) else {
+            return;
+        };
+        let (rebate_pct, _) = self.referral_rates(referrer);
+        self.referrals.record_volume(referrer, notional);
+        if fee.value() <= Decimal::ZERO {
+            return;
+        }
+
+        let upstream = self.accounts.get(&referrer).and_then(|a| a.referrer);
+        let mut payouts = vec![(referrer, 1, rebate_pct)];
+        if let Some(upstream) = upstream {
+            payouts.push((upstream, 2, self.config.referrals.upstream_rebate_pct));
+        }
+
+        for (recipient, level, pct) in payouts {
+            let amount = Quote::new(fee.value() * pct);
+            let Some(account) = self.accounts.get_mut(&recipient) else {
+                continue;
+            };
+            account.balance = account.balance.add(amount);
+            self.treasury = self.treasury.sub(amount);
+            self.post(LedgerAccount::Treasury, LedgerAccount::Trader(recipient), amount, EntryKind::ReferralReward);
+
+            let payout = ReferralPayout { timestamp: self.current_time, referee, level, fee, amount };
+            self.referrals.record_payout(recipient, payout, self.config.referrals.max_history);
+            self.emit_event(EventPayload::ReferralReward(ReferralRewardEvent {
+                referrer: recipient,
+                referee,
+                level,
+                fee,
+                amount,
+            }));
+        }
+    }
+
+    // 8.9.3: a liquidation pulls the account's resting orders in the market before it closes
+    pub(super) fn cancel_orders_for_liquidation(&mut self, account_id: AccountId, market_id: MarketId) {
+        let Some(market) = self.markets.get_mut(&market_id) else {
+            return;
+        };
+        let order_ids: Vec<OrderId> = market.order_book.orders_for_account(account_id).map(|o| o.id).collect();
+        for order_id in &order_ids {
+            market.order_book.remove(*order_id);
+        }
+
+        for order_id in order_ids {
+            self.emit_event(EventPayload::OrderCanceled(OrderCanceledEvent {
+                market_id,
+                order_id,
+                account_id,
+                reason: CancelReason::Liquidation,
+            }));
+        }
+        self.refresh_order_margin(account_id, market_id);
+    }
+
+    // 8.9.4: reduce-only IOC for a liquidation. makers settle as on any other fill, the
+    // liquidated side is left to the caller which closes the position itself
+    pub(super) fn execute_liquidation_order(
+        &mut self,
+        account_id: AccountId,
+        market_id: MarketId,
+        side: Side,
+        size: Decimal,
+        limit: Price,
+    ) -> Result<Vec<Fill>, EngineError> {
+        let order_id = self.next_order_id();
+        let mut order = Order::new_limit(
+            order_id,
+            account_id,
+            market_id,
+            side,
+            size,
+            limit,
+            TimeInForce::IOC,
+            self.current_time,
         );
+        order.reduce_only = true;
+
+        self.emit_event(EventPayload::OrderPlaced(OrderPlacedEvent {
+            market_id,
+            order_id,
+            account_id,
+            side,
+            size,
+            price: Some(limit),
+            reduce_only: true,
+        }));
+
+        let market = self
+            .markets
+            .get_mut(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        let match_result = match_order(&mut market.order_book, order);
+        for fill in &match_result.fills {
+            market.record_trade(fill.price, fill.size);
+        }
+
+        let market_config = market.config.clone();
+        for fill in &match_result.fills {
+            self.process_liquidation_fill(fill, &market_config)?;
+        }
 
-        Ok(account.balance.value() >= margin_req.initial.value())
+        Ok(match_result.fills)
+    }
+
+    // maker half of process_fill. the liquidated taker pays the penalty rather than a fee
+    fn process_liquidation_fill(&mut self, fill: &Fill, config: &MarketConfig) -> Result<(), EngineError> {
+        let notional = fill.size * fill.price.value();
+        let maker_fee = Quote::new(self.fee_tier(fill.maker_account_id)?.maker_fee(notional));
+        let window_ms = self.config.fees.volume_window_ms;
+        let now = self.current_time;
+
+        {
+            let maker = self.accounts.get_mut(&fill.maker_account_id)
+                .ok_or(EngineError::AccountNotFound(fill.maker_account_id))?;
+            maker.deduct_fee(maker_fee);
+            maker.record_volume(now, notional, window_ms);
+        }
+        let maker_kind = if maker_fee.value() < Decimal::ZERO {
+            EntryKind::MakerRebate
+        } else {
+            EntryKind::TradingFee
+        };
+        self.collect_fee(fill.maker_account_id, maker_fee, maker_kind);
+        self.pay_referrals(fill.maker_account_id, maker_fee, notional);
+
+        let maker_side = fill.taker_side.opposite();
+        self.refresh_order_margin(fill.maker_account_id, config.id);
+        self.update_position_for_fill(fill.maker_account_id, config, maker_side, fill.size, fill.price)?;
+        self.settle_debt(fill.maker_account_id);
+        self.refresh_order_margin(fill.maker_account_id, config.id);
+
+        self.emit_event(EventPayload::Fill(FillEvent {
+            market_id: config.id,
+            order_id: fill.taker_order_id,
+            account_id: fill.taker_account_id,
+            side: fill.taker_side,
+            size: fill.size,
+            price: fill.price,
+            fee: Quote::zero(),
+            is_maker: false,
+        }));
+
+        self.emit_event(EventPayload::Fill(FillEvent {
+            market_id: config.id,
+            order_id: fill.maker_order_id,
+            account_id: fill.maker_account_id,
+            side: maker_side,
+            size: fill.size,
+            price: fill.price,
+            fee: maker_fee,
+            is_maker: true,
+        }));
+
+        Ok(())
     }
 
     // 8.5: process fill: update positions, apply fees, route referral cuts
     fn process_fill(&mut self, fill: &Fill, config: &MarketConfig) -> Result<(), EngineError> {
         let notional = fill.size * fill.price.value();
 
-        // --- calculate fees ---
-        let taker_fee_bps = self.config.fees.taker_fee_bps;
-        let maker_fee_bps = self.config.fees.maker_fee_bps;
-        let referral_pct = self.config.fees.referral_fee_pct;
+        // --- calculate fees from each side's tier, volume before this fill ---
+        let taker_tier = self.fee_tier(fill.taker_account_id)?;
+        let maker_tier = self.fee_tier(fill.maker_account_id)?;
 
-        let taker_fee = Quote::new(notional * Decimal::from(taker_fee_bps) / Decimal::from(10_000));
-        let maker_fee = Quote::new(notional * Decimal::from(maker_fee_bps) / Decimal::from(10_000));
+        let taker_fee = Quote::new(taker_tier.taker_fee(notional));
+        let maker_fee = Quote::new(maker_tier.maker_fee(notional));
+        let window_ms = self.config.fees.volume_window_ms;
+        let now = self.current_time;
 
-        // --- deduct taker fee ---
+        // --- deduct taker fee into the treasury ---
         {
             let taker = self.accounts.get_mut(&fill.taker_account_id)
                 .ok_or(EngineError::AccountNotFound(fill.taker_account_id))?;
             taker.deduct_fee(taker_fee);
-            taker.trading_volume_30d += notional;
-
-            // route referral cut
-            if let Some(referrer_id) = taker.referrer {
-                let referral_amount = Quote::new(taker_fee.value() * referral_pct);
-                if let Some(referrer) = self.accounts.get_mut(&referrer_id) {
-                    referrer.balance = referrer.balance.add(referral_amount);
-                }
-            }
+            taker.record_volume(now, notional, window_ms);
         }
+        self.collect_fee(fill.taker_account_id, taker_fee, EntryKind::TradingFee);
+
+        self.pay_referrals(fill.taker_account_id, taker_fee, notional);
 
-        // --- deduct maker fee (can be negative = rebate) ---
+        // --- deduct maker fee (can be negative = rebate paid by the treasury) ---
         {
             let maker = self.accounts.get_mut(&fill.maker_account_id)
                 .ok_or(EngineError::AccountNotFound(fill.maker_account_id))?;
             maker.deduct_fee(maker_fee); // negative fee adds to balance
-            maker.trading_volume_30d += notional;
-
-            if let Some(referrer_id) = maker.referrer {
-                if maker_fee_bps > 0 {
-                    let referral_amount = Quote::new(maker_fee.value() * referral_pct);
-                    if let Some(referrer) = self.accounts.get_mut(&referrer_id) {
-                        referrer.balance = referrer.balance.add(referral_amount);
-                    }
-                }
-            }
+            maker.record_volume(now, notional, window_ms);
         }
+        let maker_kind = if maker_fee.value() < Decimal::ZERO {
+            EntryKind::MakerRebate
+        } else {
+            EntryKind::TradingFee
+        };
+        self.collect_fee(fill.maker_account_id, maker_fee, maker_kind);
+
+        self.pay_referrals(fill.maker_account_id, maker_fee, notional);
 
         // --- update positions ---
         self.update_position_for_fill(
@@ -326,6 +605,9 @@ impl Engine {
             Side::Short => Side::Long,
         };
 
+        // the filled part of the maker's order is off the book, release its hold so the
+        // position can reserve from it
+        self.refresh_order_margin(fill.maker_account_id, config.id);
         self.update_position_for_fill(
             fill.maker_account_id,
             config,
@@ -334,6 +616,14 @@ impl Engine {
             fill.price,
         )?;
 
+        // --- settle debt from fees or realized losses ---
+        self.settle_debt(fill.taker_account_id);
+        self.settle_debt(fill.maker_account_id);
+
+        // --- filled size no longer rests, position moved ---
+        self.refresh_order_margin(fill.taker_account_id, config.id);
+        self.refresh_order_margin(fill.maker_account_id, config.id);
+
         // --- emit fill events with fees ---
         self.emit_event(EventPayload::Fill(FillEvent {
             market_id: config.id,
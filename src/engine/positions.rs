@@ -2,16 +2,239 @@
 
 use super::core::Engine;
 use super::results::EngineError;
+use crate::account::{Account, AccountError};
+use crate::api::PositionInfo;
+use crate::custody::CollateralType;
 use crate::events::{
-    CloseReason, EventPayload, PositionClosedEvent, PositionOpenedEvent, PositionUpdatedEvent,
+    CloseReason, CollateralConvertedEvent, EventPayload, LeverageUpdatedEvent, PositionClosedEvent,
+    PositionOpenedEvent, PositionUpdatedEvent,
+};
+use crate::ledger::{EntryKind, Ledger, LedgerAccount};
+use crate::liquidation::position_liquidation_price;
+use crate::margin::{
+    calculate_margin_requirement, effective_max_leverage, evaluate_margin_status, MarginStatus,
 };
-use crate::margin::calculate_margin_requirement;
 use crate::market::MarketConfig;
 use crate::position::{increase_position, reduce_position, Position};
-use crate::types::{AccountId, Price, Quote, Side, SignedSize};
+use crate::types::{AccountId, Leverage, MarketId, Price, Quote, Side, SignedSize, Timestamp};
 use rust_decimal::Decimal;
+use std::collections::HashMap;
 
 impl Engine {
+    /** 8.6.1: pick leverage for a market. capped by the tier of the open position (if any),
+    and only changed mid-position while equity still covers IM at the new leverage */
+    pub fn set_leverage(
+        &mut self,
+        account_id: AccountId,
+        market_id: MarketId,
+        leverage: Leverage,
+    ) -> Result<(), EngineError> {
+        let market = self
+            .markets
+            .get(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        let account = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+
+        let params = &market.config.margin_params;
+        let old_leverage = account.leverage_for(market_id, params.max_leverage);
+        let position = account.get_position(market_id);
+
+        let mark_price = match position {
+            Some(_) => Some(
+                market
+                    .effective_mark_price()
+                    .ok_or(EngineError::NoMarkPrice(market_id))?,
+            ),
+            None => None,
+        };
+
+        let notional = match (position, mark_price) {
+            (Some(position), Some(mark_price)) => position.notional_value(mark_price),
+            _ => Quote::zero(),
+        };
+        let tier_max = effective_max_leverage(notional, params);
+        let maximum = if tier_max.value() < params.max_leverage.value() {
+            tier_max
+        } else {
+            params.max_leverage
+        };
+        if leverage.value() > maximum.value() {
+            return Err(EngineError::InvalidLeverage {
+                requested: leverage,
+                maximum,
+            });
+        }
+
+        if let (Some(position), Some(mark_price)) = (position, mark_price) {
+            let margin_req = calculate_margin_requirement(position.size, mark_price, leverage, params);
+            let equity = position.equity(mark_price, market.funding_state.cumulative_funding);
+            if evaluate_margin_status(equity, &margin_req) != MarginStatus::Healthy {
+                return Err(EngineError::Account(AccountError::InsufficientMargin {
+                    required: margin_req.initial,
+                    available: equity,
+                }));
+            }
+        }
+
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        account.set_leverage(market_id, leverage);
+        let has_open_position = match account.get_position_mut(market_id) {
+            Some(position) => {
+                position.leverage = leverage;
+                position.updated_at = self.current_time;
+                true
+            }
+            None => false,
+        };
+
+        self.emit_event(EventPayload::LeverageUpdated(LeverageUpdatedEvent {
+            market_id,
+            account_id,
+            old_leverage: old_leverage.value(),
+            new_leverage: leverage.value(),
+            has_open_position,
+        }));
+
+        // resting orders are margined at the new leverage too
+        self.refresh_order_margin(account_id, market_id);
+
+        Ok(())
+    }
+
+    /** 8.6.2: move collateral from account balance into an isolated position */
+    pub fn add_position_margin(
+        &mut self,
+        account_id: AccountId,
+        market_id: MarketId,
+        amount: Quote,
+    ) -> Result<(), EngineError> {
+        if amount.value() <= Decimal::ZERO {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        if !self.markets.contains_key(&market_id) {
+            return Err(EngineError::MarketNotFound(market_id));
+        }
+
+        let account = self
+            .accounts
+            .get_mut(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+        if account.get_position(market_id).is_none() {
+            return Err(EngineError::Account(AccountError::PositionNotFound(market_id)));
+        }
+
+        self.realize_funding(account_id, market_id);
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        account.reserve_collateral(amount).map_err(EngineError::Account)?;
+        let position = account.get_position_mut(market_id).unwrap();
+        position.collateral = position.collateral.add(amount);
+        position.updated_at = self.current_time;
+        let position = position.clone();
+
+        self.emit_margin_change(account_id, &position);
+        Ok(())
+    }
+
+    /** 8.6.3: pull collateral out of an isolated position. what's left must still
+    cover initial margin at the current mark price */
+    pub fn remove_position_margin(
+        &mut self,
+        account_id: AccountId,
+        market_id: MarketId,
+        amount: Quote,
+    ) -> Result<(), EngineError> {
+        if amount.value() <= Decimal::ZERO {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        self.realize_funding(account_id, market_id);
+
+        let market = self
+            .markets
+            .get(&market_id)
+            .ok_or(EngineError::MarketNotFound(market_id))?;
+        let account = self
+            .accounts
+            .get(&account_id)
+            .ok_or(EngineError::AccountNotFound(account_id))?;
+        let position = account
+            .get_position(market_id)
+            .ok_or(EngineError::Account(AccountError::PositionNotFound(market_id)))?;
+
+        let mark_price = market
+            .effective_mark_price()
+            .ok_or(EngineError::NoMarkPrice(market_id))?;
+        let margin_req = calculate_margin_requirement(
+            position.size,
+            mark_price,
+            position.leverage,
+            &market.config.margin_params,
+        );
+        let equity = position.equity(mark_price, market.funding_state.cumulative_funding);
+
+        // can't take out more than was put in, or dip below IM
+        let removable = Quote::new(
+            (equity.value() - margin_req.initial.value())
+                .min(position.collateral.value())
+                .max(Decimal::ZERO),
+        );
+        if amount.value() > removable.value() {
+            return Err(EngineError::Account(AccountError::InsufficientMargin {
+                required: Quote::new(margin_req.initial.value() + amount.value()),
+                available: equity,
+            }));
+        }
+
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        let position = account.get_position_mut(market_id).unwrap();
+        position.collateral = position.collateral.sub(amount);
+        position.updated_at = self.current_time;
+        let position = position.clone();
+        account.return_collateral(amount);
+
+        self.emit_margin_change(account_id, &position);
+        Ok(())
+    }
+
+    // the position as the API reports it, valued at mark with its ADL indicator filled in.
+    // None when the account has no position in the market
+    pub fn position_info(&self, account_id: AccountId, market_id: MarketId) -> Result<Option<PositionInfo>, EngineError> {
+        let account = self.accounts.get(&account_id).ok_or(EngineError::AccountNotFound(account_id))?;
+        let market = self.markets.get(&market_id).ok_or(EngineError::MarketNotFound(market_id))?;
+        let Some(position) = account.get_position(market_id) else {
+            return Ok(None);
+        };
+
+        let mut info = PositionInfo::from(position);
+        if let Some(mark_price) = market.mark_price {
+            info.mark_price = mark_price.value();
+            info.unrealized_pnl = position.unrealized_pnl(mark_price).value();
+        }
+        info.liquidation_price = position_liquidation_price(position, &market.config.margin_params).map(|p| p.value());
+        info.leverage = position.leverage.value();
+        info.adl_quantile = self.adl_quantile(account_id, market_id);
+        Ok(Some(info))
+    }
+
+    fn emit_margin_change(&mut self, account_id: AccountId, position: &Position) {
+        let margin_params = &self.markets[&position.market_id].config.margin_params;
+        let liquidation_price = position_liquidation_price(position, margin_params);
+
+        self.emit_event(EventPayload::PositionUpdated(PositionUpdatedEvent {
+            market_id: position.market_id,
+            account_id,
+            old_size: position.size,
+            new_size: position.size,
+            old_entry_price: position.entry_price,
+            new_entry_price: position.entry_price,
+            realized_pnl: Quote::zero(),
+            collateral: position.collateral,
+            liquidation_price,
+        }));
+    }
+
     pub(super) fn update_position_for_fill(
         &mut self,
         account_id: AccountId,
@@ -22,10 +245,13 @@ impl Engine {
     ) -> Result<(), EngineError> {
         let market_id = config.id;
 
+        // settle accrued funding first so the fill works from the current index
+        self.realize_funding(account_id, market_id);
         let funding_index = {
             let market = self.markets.get(&market_id).unwrap();
             market.funding_state.cumulative_funding
         };
+        let old_size = self.accounts.get(&account_id).and_then(|a| a.get_position(market_id)).map(|p| p.size.value());
 
         let signed_size = match side {
             Side::Long => SignedSize::new(size),
@@ -85,11 +311,17 @@ impl Engine {
             }
         }
 
+        // OI is the sum of each side's positions, so closes and flips move both legs
+        let old_size = old_size.unwrap_or(Decimal::ZERO);
+        let new_size = self.accounts[&account_id]
+            .get_position(market_id)
+            .map_or(Decimal::ZERO, |p| p.size.value());
         let market = self.markets.get_mut(&market_id).unwrap();
-        match side {
-            Side::Long => market.update_open_interest(size, Decimal::ZERO),
-            Side::Short => market.update_open_interest(Decimal::ZERO, size),
-        }
+        market.update_open_interest(
+            new_size.max(Decimal::ZERO) - old_size.max(Decimal::ZERO),
+            (-new_size).max(Decimal::ZERO) - (-old_size).max(Decimal::ZERO),
+        );
+        market.track_holder(account_id, !new_size.is_zero());
 
         for event in events_to_emit {
             self.emit_event(event);
@@ -98,6 +330,7 @@ impl Engine {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn handle_position_increase(
         &mut self,
         account_id: AccountId,
@@ -109,23 +342,27 @@ impl Engine {
         events: &mut Vec<EventPayload>,
     ) -> Result<(), EngineError> {
         let market_id = config.id;
-        let max_leverage = config.margin_params.max_leverage;
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        let leverage = account.leverage_for(market_id, config.margin_params.max_leverage);
 
         let margin_req = calculate_margin_requirement(
             signed_size,
             price,
-            max_leverage,
+            leverage,
             &config.margin_params,
         );
 
-        let account = self.accounts.get_mut(&account_id).unwrap();
-        account.reserve_collateral(margin_req.initial).map_err(EngineError::Account)?;
+        let collateral = account.collateral_to_reserve(margin_req.initial);
+        if self.config.auto_convert_collateral {
+            fund_reservation(account, collateral, &self.collateral_prices, &self.config.collateral_weights, &mut self.ledger, self.current_time, events);
+        }
+        account.reserve_collateral(collateral).map_err(EngineError::Account)?;
 
         let new_position = increase_position(
             position,
             signed_size.value(),
             price,
-            margin_req.initial,
+            collateral,
             funding_index,
             self.current_time,
         );
@@ -140,11 +377,14 @@ impl Engine {
             old_entry_price: position.entry_price,
             new_entry_price: new_position.entry_price,
             realized_pnl: Quote::zero(),
+            collateral: new_position.collateral,
+            liquidation_price: position_liquidation_price(&new_position, &config.margin_params),
         }));
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn handle_position_reduce_or_flip(
         &mut self,
         account_id: AccountId,
@@ -169,6 +409,7 @@ impl Engine {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn handle_full_close_or_flip(
         &mut self,
         account_id: AccountId,
@@ -194,6 +435,13 @@ impl Engine {
         let account = self.accounts.get_mut(&account_id).unwrap();
         account.realize_pnl(close_update.realized_pnl);
         account.return_collateral(close_update.collateral_returned);
+        self.ledger.post(
+            LedgerAccount::Settlement(market_id),
+            LedgerAccount::Trader(account_id),
+            close_update.realized_pnl,
+            EntryKind::RealizedPnl,
+            self.current_time,
+        );
 
         events.push(EventPayload::PositionClosed(PositionClosedEvent {
             market_id,
@@ -213,22 +461,26 @@ impl Engine {
                 Side::Short => SignedSize::new(-flip_size),
             };
 
-            let max_leverage = config.margin_params.max_leverage;
+            let leverage = account.leverage_for(market_id, config.margin_params.max_leverage);
             let margin_req = calculate_margin_requirement(
                 flip_signed,
                 price,
-                max_leverage,
+                leverage,
                 &config.margin_params,
             );
 
-            account.reserve_collateral(margin_req.initial).map_err(EngineError::Account)?;
+            let collateral = account.collateral_to_reserve(margin_req.initial);
+            if self.config.auto_convert_collateral {
+                fund_reservation(account, collateral, &self.collateral_prices, &self.config.collateral_weights, &mut self.ledger, self.current_time, events);
+            }
+            account.reserve_collateral(collateral).map_err(EngineError::Account)?;
 
             let new_position = Position::new(
                 market_id,
                 flip_signed,
                 price,
-                margin_req.initial,
-                max_leverage,
+                collateral,
+                margin_req.effective_leverage,
                 funding_index,
                 self.current_time,
             );
@@ -241,14 +493,15 @@ impl Engine {
                 side,
                 size: flip_size,
                 entry_price: price,
-                leverage: max_leverage.value(),
-                collateral: margin_req.initial,
+                leverage: margin_req.effective_leverage.value(),
+                collateral,
             }));
         }
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn handle_partial_close(
         &mut self,
         account_id: AccountId,
@@ -272,6 +525,13 @@ impl Engine {
         let account = self.accounts.get_mut(&account_id).unwrap();
         account.realize_pnl(close_update.realized_pnl);
         account.return_collateral(close_update.collateral_returned);
+        self.ledger.post(
+            LedgerAccount::Settlement(market_id),
+            LedgerAccount::Trader(account_id),
+            close_update.realized_pnl,
+            EntryKind::RealizedPnl,
+            self.current_time,
+        );
 
         if let Some(new_pos) = close_update.new_position {
             account.set_position(new_pos.clone());
@@ -284,12 +544,15 @@ impl Engine {
                 old_entry_price: position.entry_price,
                 new_entry_price: new_pos.entry_price,
                 realized_pnl: close_update.realized_pnl,
+                collateral: new_pos.collateral,
+                liquidation_price: position_liquidation_price(&new_pos, &config.margin_params),
             }));
         }
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn handle_new_position(
         &mut self,
         account_id: AccountId,
@@ -302,24 +565,28 @@ impl Engine {
         events: &mut Vec<EventPayload>,
     ) -> Result<(), EngineError> {
         let market_id = config.id;
-        let max_leverage = config.margin_params.max_leverage;
+        let account = self.accounts.get_mut(&account_id).unwrap();
+        let leverage = account.leverage_for(market_id, config.margin_params.max_leverage);
 
         let margin_req = calculate_margin_requirement(
             signed_size,
             price,
-            max_leverage,
+            leverage,
             &config.margin_params,
         );
 
-        let account = self.accounts.get_mut(&account_id).unwrap();
-        account.reserve_collateral(margin_req.initial).map_err(EngineError::Account)?;
+        let collateral = account.collateral_to_reserve(margin_req.initial);
+        if self.config.auto_convert_collateral {
+            fund_reservation(account, collateral, &self.collateral_prices, &self.config.collateral_weights, &mut self.ledger, self.current_time, events);
+        }
+        account.reserve_collateral(collateral).map_err(EngineError::Account)?;
 
         let new_position = Position::new(
             market_id,
             signed_size,
             price,
-            margin_req.initial,
-            max_leverage,
+            collateral,
+            margin_req.effective_leverage,
             funding_index,
             self.current_time,
         );
@@ -332,10 +599,207 @@ impl Engine {
             side,
             size,
             entry_price: price,
-            leverage: max_leverage.value(),
-            collateral: margin_req.initial,
+            leverage: margin_req.effective_leverage.value(),
+            collateral,
         }));
 
         Ok(())
     }
 }
+
+// reservations come out of free collateral. top the balance up from other collateral first
+fn fund_reservation(
+    account: &mut Account,
+    amount: Quote,
+    prices: &HashMap<CollateralType, Price>,
+    weights: &HashMap<CollateralType, Decimal>,
+    ledger: &mut Ledger,
+    now: Timestamp,
+    events: &mut Vec<EventPayload>,
+) {
+    let target = amount.add(account.reserved_order_margin());
+    if target.value() <= account.balance.value() {
+        return;
+    }
+    let valuation = |asset| Some((*prices.get(&asset)?, *weights.get(&asset)?));
+    for conversion in account.convert_to_quote(target, valuation) {
+        ledger.post(
+            LedgerAccount::External,
+            LedgerAccount::Trader(account.id),
+            conversion.proceeds,
+            EntryKind::CollateralConversion,
+            now,
+        );
+        events.push(EventPayload::CollateralConverted(CollateralConvertedEvent {
+            account_id: account.id,
+            asset: conversion.asset,
+            amount: conversion.amount,
+            price: conversion.price,
+            proceeds: conversion.proceeds,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineConfig;
+    use crate::market::MarketConfig;
+    use crate::order::TimeInForce;
+    use crate::types::MarketId;
+    use rust_decimal_macros::dec;
+
+    fn setup_with_position(leverage: Decimal) -> (Engine, AccountId) {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let trader = engine.create_account();
+        let maker = engine.create_account();
+        engine.deposit(trader, Quote::new(dec!(20000))).unwrap();
+        engine.deposit(maker, Quote::new(dec!(100000))).unwrap();
+
+        engine
+            .set_leverage(trader, MarketId(1), Leverage::new(leverage).unwrap())
+            .unwrap();
+
+        engine
+            .place_limit_order(
+                maker,
+                MarketId(1),
+                Side::Short,
+                dec!(1),
+                Price::new_unchecked(dec!(50000)),
+                TimeInForce::GTC,
+            )
+            .unwrap();
+        engine
+            .place_market_order(trader, MarketId(1), Side::Long, dec!(1))
+            .unwrap();
+
+        (engine, trader)
+    }
+
+    #[test]
+    fn selected_leverage_sizes_initial_margin() {
+        let (engine, trader) = setup_with_position(dec!(10));
+
+        let pos = engine.get_account(trader).unwrap().get_position(MarketId(1)).unwrap();
+        assert_eq!(pos.leverage.value(), dec!(10));
+        assert_eq!(pos.collateral.value(), dec!(5000)); // 50k / 10x
+
+        let opened = engine.events().iter().find_map(|e| match &e.payload {
+            EventPayload::PositionOpened(ev) if ev.account_id == trader => Some(ev.leverage),
+            _ => None,
+        });
+        assert_eq!(opened, Some(dec!(10)));
+    }
+
+    #[test]
+    fn leverage_above_market_max_rejected() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        let trader = engine.create_account();
+
+        let result = engine.set_leverage(trader, MarketId(1), Leverage::new(dec!(60)).unwrap());
+        assert!(matches!(result, Err(EngineError::InvalidLeverage { .. })));
+    }
+
+    #[test]
+    fn leverage_change_with_open_position_requires_health() {
+        let (mut engine, trader) = setup_with_position(dec!(25));
+
+        // 2k collateral backs 50k notional; 5x would need 10k IM
+        let result = engine.set_leverage(trader, MarketId(1), Leverage::new(dec!(5)).unwrap());
+        assert!(matches!(
+            result,
+            Err(EngineError::Account(AccountError::InsufficientMargin { .. }))
+        ));
+
+        // raising leverage keeps the same collateral and is always healthy
+        engine
+            .set_leverage(trader, MarketId(1), Leverage::new(dec!(40)).unwrap())
+            .unwrap();
+        let pos = engine.get_account(trader).unwrap().get_position(MarketId(1)).unwrap();
+        assert_eq!(pos.leverage.value(), dec!(40));
+        assert_eq!(pos.collateral.value(), dec!(2000));
+    }
+
+    #[test]
+    fn position_info_carries_mark_and_adl_indicator() {
+        let (mut engine, trader) = setup_with_position(dec!(10));
+
+        // in profit at 52k the long enters the ADL queue
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(52000))).unwrap();
+        let info = engine.position_info(trader, MarketId(1)).unwrap().unwrap();
+        assert_eq!(info.mark_price, dec!(52000));
+        assert_eq!(info.unrealized_pnl, dec!(2000));
+        assert_eq!(info.leverage, dec!(10));
+        assert!(info.liquidation_price.is_some());
+        assert!(info.adl_quantile > 0);
+        assert_eq!(info.adl_quantile, engine.adl_quantile(trader, MarketId(1)));
+
+        let other = engine.create_account();
+        assert!(engine.position_info(other, MarketId(1)).unwrap().is_none());
+    }
+
+    fn last_liquidation_price(engine: &Engine) -> Option<Price> {
+        engine.events().iter().rev().find_map(|e| match &e.payload {
+            EventPayload::PositionUpdated(ev) => Some(ev.liquidation_price),
+            _ => None,
+        })?
+    }
+
+    #[test]
+    fn add_margin_moves_liquidation_price_away() {
+        let (mut engine, trader) = setup_with_position(dec!(10));
+        let before = engine.get_account(trader).unwrap().balance.value();
+
+        engine
+            .add_position_margin(trader, MarketId(1), Quote::new(dec!(2000)))
+            .unwrap();
+
+        let account = engine.get_account(trader).unwrap();
+        assert_eq!(account.balance.value(), before - dec!(2000));
+        assert_eq!(account.get_position(MarketId(1)).unwrap().collateral.value(), dec!(7000));
+
+        // 7k collateral on 1 BTC long @ 50k, 5% MMF: (50000 - 7000) / 0.95
+        let liq = last_liquidation_price(&engine).unwrap();
+        assert!(liq.value() < dec!(45264) && liq.value() > dec!(45263));
+    }
+
+    #[test]
+    fn remove_margin_checked_against_initial_margin() {
+        let (mut engine, trader) = setup_with_position(dec!(10));
+        engine
+            .add_position_margin(trader, MarketId(1), Quote::new(dec!(3000)))
+            .unwrap();
+
+        // 8k collateral, 5k IM at mark: at most 3k can come out
+        let result = engine.remove_position_margin(trader, MarketId(1), Quote::new(dec!(3001)));
+        assert!(matches!(
+            result,
+            Err(EngineError::Account(AccountError::InsufficientMargin { .. }))
+        ));
+
+        engine
+            .remove_position_margin(trader, MarketId(1), Quote::new(dec!(3000)))
+            .unwrap();
+        let pos = engine.get_account(trader).unwrap().get_position(MarketId(1)).unwrap();
+        assert_eq!(pos.collateral.value(), dec!(5000));
+    }
+
+    #[test]
+    fn margin_change_without_position_rejected() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.add_market(MarketConfig::btc_perp());
+        let trader = engine.create_account();
+        engine.deposit(trader, Quote::new(dec!(1000))).unwrap();
+
+        let result = engine.add_position_margin(trader, MarketId(1), Quote::new(dec!(100)));
+        assert!(matches!(
+            result,
+            Err(EngineError::Account(AccountError::PositionNotFound(_)))
+        ));
+    }
+}
@@ -67,6 +67,10 @@ impl Engine {
             premium_index: new_state.premium_index,
         }));
 
+        self.record_premium_sample(market_id, false);
+        self.update_margin_calls(market_id);
+        self.refresh_adl_ranking(market_id)?;
+
         Ok(())
     }
 }
@@ -1,7 +1,7 @@
 // 8.0.2: result types and errors for engine operations.
 
 use crate::order::Fill;
-use crate::types::{AccountId, MarketId, OrderId, Price, Quote, SignedSize};
+use crate::types::{AccountId, Leverage, MarketId, OrderId, Price, Quote, SignedSize, Timestamp};
 use crate::account::AccountError;
 use crate::market::MarketError;
 use rust_decimal::Decimal;
@@ -21,8 +21,10 @@ pub struct FundingResult {
     pub funding_rate: Decimal,
     pub total_long_payments: Quote,
     pub total_short_payments: Quote,
-    pub lp_fee_collected: Quote, // LP pool cut
-    pub accounts_affected: usize,
+    pub lp_fee_collected: Quote, // LP pool cut, paid in as receivers realize
+    pub accounts_affected: usize, // positions the index moved for, each realizes when next touched
+    pub open_interest: Decimal,
+    pub periods_settled: usize, // 0 when nothing had ended since the last call
 }
 
 #[derive(Debug, Clone)]
@@ -34,6 +36,10 @@ pub struct LiquidationResult {
     pub penalty: Quote,
     pub bad_debt: Quote,
     pub realized_pnl: Quote,
+    pub remaining_size: SignedSize, // what a partial liquidation left open
+    pub backstop_size: Decimal,
+    pub adl_size: Decimal, // closed against deleveraged counterparties
+    pub liquidator: Option<AccountId>,
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -62,6 +68,63 @@ pub enum EngineError {
     #[error("Market error: {0}")]
     Market(#[from] MarketError),
 
+    #[error("Invalid amount: {0}")]
+    InvalidAmount(Quote),
+
+    #[error("Leverage {requested} exceeds maximum {maximum}")]
+    InvalidLeverage { requested: Leverage, maximum: Leverage },
+
+    #[error("Cannot change margin mode of {0:?} with open positions")]
+    MarginModeLocked(AccountId),
+
+    #[error("Account {0:?} is a subaccount and cannot own subaccounts")]
+    NestedSubaccount(AccountId),
+
+    #[error("Accounts {from:?} and {to:?} do not share a master account")]
+    TransferOutsideGroup { from: AccountId, to: AccountId },
+
     #[error("Insufficient pool liquidity: provided {provided}, minimum {minimum}")]
     InsufficientPoolLiquidity { provided: Quote, minimum: Quote },
+
+    #[error("Invalid referral code {0:?}")]
+    InvalidReferralCode(String),
+
+    #[error("Referral code {0:?} is already taken")]
+    ReferralCodeTaken(String),
+
+    #[error("Account {0:?} already has a referral code")]
+    ReferralCodeExists(AccountId),
+
+    #[error("Account {0:?} cannot refer itself or its own subaccounts")]
+    SelfReferral(AccountId),
+
+    #[error("Referring {account:?} to {referrer:?} would create a referral cycle")]
+    ReferralCycle { account: AccountId, referrer: AccountId },
+
+    #[error("Position of {account:?} in {market:?} is not liquidatable")]
+    NotLiquidatable { account: AccountId, market: MarketId },
+
+    #[error("Account {0:?} cannot liquidate itself or its own subaccounts")]
+    SelfLiquidation(AccountId),
+
+    #[error("Insufficient shares: requested {requested}, available {available}")]
+    InsufficientShares { requested: Decimal, available: Decimal },
+
+    #[error("Backstop vault has no equity left to buy into")]
+    BackstopInsolvent,
+
+    #[error("Position of {account:?} in {market:?} is being unwound by the liquidation engine")]
+    UnwindInProgress { account: AccountId, market: MarketId },
+
+    #[error("Nothing could take the liquidation of {account:?} in {market:?}")]
+    LiquidationUnfilled { account: AccountId, market: MarketId },
+
+    #[error("Account {0:?} has no unstake request")]
+    NoPendingUnstake(AccountId),
+
+    #[error("Unstake is in cooldown until {available_at:?}")]
+    UnstakeCooldown { available_at: Timestamp },
+
+    #[error("Unstake request lapsed at {expired_at:?}")]
+    UnstakeExpired { expired_at: Timestamp },
 }
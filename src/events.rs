@@ -1,6 +1,9 @@
 // 11.0: every state change produces an event. used for audit trails, state reconstruction,
 // and notifying external systems. the EventPayload enum lists all event types.
 
+use crate::config::FeeOverride;
+use crate::custody::CollateralType;
+use crate::liquidation::UnwindEndReason;
 use crate::types::{AccountId, MarketId, OrderId, Price, Quote, Side, SignedSize, Timestamp};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
@@ -40,16 +43,41 @@ pub enum EventPayload {
     Deposit(DepositEvent),
     Withdrawal(WithdrawalEvent),
     FundingSettled(FundingSettledEvent),
+    CollateralDeposit(CollateralDepositEvent),
+    CollateralWithdrawal(CollateralWithdrawalEvent),
+    CollateralConverted(CollateralConvertedEvent),
+    SubaccountCreated(SubaccountCreatedEvent),
+    InternalTransfer(InternalTransferEvent),
+    FeeOverrideUpdated(FeeOverrideUpdatedEvent),
+    ReferralCodeCreated(ReferralCodeCreatedEvent),
+    ReferrerSet(ReferrerSetEvent),
+    ReferralReward(ReferralRewardEvent),
+    BackstopDeposit(BackstopDepositEvent),
+    BackstopWithdrawal(BackstopWithdrawalEvent),
+    InsuranceStaked(InsuranceStakedEvent),
+    UnstakeRequested(UnstakeRequestedEvent),
+    InsuranceUnstaked(InsuranceUnstakedEvent),
 
     // Risk events
     Liquidation(LiquidationEvent),
     MarginCall(MarginCallEvent),
+    MarginCallCleared(MarginCallClearedEvent),
+    LiquidationUnwindStarted(LiquidationUnwindStartedEvent),
+    LiquidationUnwindSlice(LiquidationUnwindSliceEvent),
+    LiquidationUnwindEnded(LiquidationUnwindEndedEvent),
     BadDebt(BadDebtEvent),
+    NegativeQuoteBalance(NegativeQuoteBalanceEvent),
+    BackstopTakeover(BackstopTakeoverEvent),
+    AutoDeleverage(AutoDeleverageEvent),
+    LossHaircut(LossHaircutEvent),
+    AccountDebt(AccountDebtEvent),
+    DebtWrittenOff(DebtWrittenOffEvent),
 
     // Position events
     PositionOpened(PositionOpenedEvent),
     PositionClosed(PositionClosedEvent),
     PositionUpdated(PositionUpdatedEvent),
+    LeverageUpdated(LeverageUpdatedEvent),
 
     // Market data events
     OiUpdated(OiUpdatedEvent),
@@ -146,6 +174,8 @@ pub struct LiquidationEvent {
     pub liquidation_price: Price,
     pub penalty: Quote,
     pub liquidator_account: Option<AccountId>,
+    pub remaining_size: SignedSize, // zero once the position is fully closed
+    pub backstop_size: Decimal,     // part the book couldn't take, left to the backstop
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -157,6 +187,14 @@ pub struct MarginCallEvent {
     pub current_equity: Quote,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginCallClearedEvent {
+    pub account_id: AccountId,
+    pub market_id: MarketId,
+    pub margin_ratio: Decimal,
+    pub current_equity: Quote,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BadDebtEvent {
     pub market_id: MarketId,
@@ -166,6 +204,64 @@ pub struct BadDebtEvent {
     pub socialized_loss: Quote,
 }
 
+// a liquidation too large for the book, taken over to be closed in slices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiquidationUnwindStartedEvent {
+    pub market_id: MarketId,
+    pub account_id: AccountId,
+    pub size: SignedSize,
+    pub notional: Quote,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiquidationUnwindSliceEvent {
+    pub market_id: MarketId,
+    pub account_id: AccountId,
+    pub slice: u32,
+    pub size: Decimal,     // closed this slice
+    pub filled: Decimal,   // of which through the book, the rest at mark
+    pub remaining: SignedSize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiquidationUnwindEndedEvent {
+    pub market_id: MarketId,
+    pub account_id: AccountId,
+    pub slices: u32,
+    pub closed: Decimal,
+    pub reason: UnwindEndReason,
+}
+
+// a profitable position closed against a bankrupt one at its bankruptcy price
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoDeleverageEvent {
+    pub market_id: MarketId,
+    pub account_id: AccountId,       // the deleveraged account
+    pub bankrupt_account: AccountId,
+    pub size: SignedSize,            // closed from the deleveraged position
+    pub price: Price,
+    pub realized_pnl: Quote,
+}
+
+// profit taken from an opposite-side account to cover a bankrupt one's debt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LossHaircutEvent {
+    pub market_id: MarketId,
+    pub account_id: AccountId,
+    pub bankrupt_account: AccountId,
+    pub unrealized: Quote,
+    pub realized: Quote,
+}
+
+// the vault stepped into what the book left of a liquidated position
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackstopTakeoverEvent {
+    pub market_id: MarketId,
+    pub account_id: AccountId, // the liquidated account
+    pub size: SignedSize,      // as held by the liquidated account
+    pub price: Price,          // bankruptcy price
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PositionOpenedEvent {
     pub market_id: MarketId,
@@ -205,6 +301,17 @@ pub struct PositionUpdatedEvent {
     pub old_entry_price: Price,
     pub new_entry_price: Price,
     pub realized_pnl: Quote,
+    pub collateral: Quote,
+    pub liquidation_price: Option<Price>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeverageUpdatedEvent {
+    pub market_id: MarketId,
+    pub account_id: AccountId,
+    pub old_leverage: Decimal,
+    pub new_leverage: Decimal,
+    pub has_open_position: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -222,11 +329,160 @@ pub struct FundingFeeCollectedEvent {
     pub funding_rate: Decimal,
 }
 
+// non quote collateral, amounts in asset units
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollateralDepositEvent {
+    pub account_id: AccountId,
+    pub asset: CollateralType,
+    pub amount: Decimal,
+    pub new_amount: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollateralWithdrawalEvent {
+    pub account_id: AccountId,
+    pub asset: CollateralType,
+    pub amount: Decimal,
+    pub new_amount: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubaccountCreatedEvent {
+    pub master: AccountId,
+    pub account_id: AccountId,
+    pub subaccount_number: u32,
+}
+
+// quote moved between accounts under the same master
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InternalTransferEvent {
+    pub from: AccountId,
+    pub to: AccountId,
+    pub amount: Quote,
+}
+
+// admin set or cleared a VIP fee schedule. None = back on the volume tiers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeeOverrideUpdatedEvent {
+    pub account_id: AccountId,
+    pub fee_override: Option<FeeOverride>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferralCodeCreatedEvent {
+    pub account_id: AccountId,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferrerSetEvent {
+    pub account_id: AccountId,
+    pub referrer: AccountId,
+}
+
+// rebate paid from the treasury out of a referee's fee. level 1 = direct referrer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferralRewardEvent {
+    pub referrer: AccountId,
+    pub referee: AccountId,
+    pub level: u8,
+    pub fee: Quote,
+    pub amount: Quote,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackstopDepositEvent {
+    pub account_id: AccountId,
+    pub amount: Quote,
+    pub shares: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackstopWithdrawalEvent {
+    pub account_id: AccountId,
+    pub amount: Quote,
+    pub shares: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsuranceStakedEvent {
+    pub account_id: AccountId,
+    pub amount: Quote,
+    pub shares: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnstakeRequestedEvent {
+    pub account_id: AccountId,
+    pub shares: Decimal,
+    pub available_at: Timestamp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsuranceUnstakedEvent {
+    pub account_id: AccountId,
+    pub amount: Quote,
+    pub shares: Decimal,
+}
+
+// collateral sold at oracle price to cover the quote balance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollateralConvertedEvent {
+    pub account_id: AccountId,
+    pub asset: CollateralType,
+    pub amount: Decimal,
+    pub price: Price,
+    pub proceeds: Quote,
+}
+
+// quote balance still negative after conversion. collateral needs liquidating
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NegativeQuoteBalanceEvent {
+    pub account_id: AccountId,
+    pub balance: Quote,
+    pub collateral_value: Quote, // haircut value of remaining non quote collateral
+}
+
+// quote shortfall and how much of it position collateral absorbed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountDebtEvent {
+    pub account_id: AccountId,
+    pub shortfall: Quote,
+    pub from_position_collateral: Quote,
+    pub remaining: Quote,
+}
+
+// debt left on an account with nothing more to seize
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebtWrittenOffEvent {
+    pub account_id: AccountId,
+    pub debt_amount: Quote,
+    pub covered_by_insurance: Quote,
+    pub socialized_loss: Quote,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WithdrawalRejectedEvent {
     pub account_id: AccountId,
     pub amount: Quote,
-    pub reason: String,
+    pub reason: WithdrawalRejectReason,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WithdrawalRejectReason {
+    InsufficientBalance {
+        balance: Quote,
+    },
+    // equity - initial_margin - funding_buffer = withdrawable
+    InsufficientFreeCollateral {
+        equity: Quote,
+        initial_margin: Quote,
+        funding_buffer: Quote,
+        withdrawable: Quote,
+    },
+    NoMarkPrice {
+        market_id: MarketId,
+    },
 }
 
 pub trait EventEmitter {
@@ -320,6 +576,8 @@ mod tests {
             liquidation_price: Price::new_unchecked(dec!(47500)),
             penalty: Quote::new(dec!(475)),
             liquidator_account: Some(AccountId(99)),
+            remaining_size: SignedSize::zero(),
+            backstop_size: dec!(0),
         };
 
         assert!(liq.liquidated_size.is_short()); // Liquidation is opposite direction
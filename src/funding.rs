@@ -1,20 +1,30 @@
 // 5.0: funding rates. every 8hrs longs pay shorts or vice versa to keep perp price near spot.
-// 5.0 has the params/state structs. 5.1 has the rate calculation logic.
+// 5.0 has the params/state structs. 5.1 has the rate calculation logic, 5.7 the history.
 
 use crate::types::{Price, Quote, SignedSize, Timestamp};
+use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FundingParams {
     pub max_rate: Decimal,
     pub interest_rate: Decimal,
     pub period_hours: Decimal,
+    // periods start at anchor + k * period_hours. 0 lines 8h periods up with 00/08/16 UTC
+    pub schedule_anchor_ms: i64,
     pub dampening_factor: Decimal,
     // fraction of gross funding routed to the LP pool (0.10 = 10%).
     // payers pay full amount, receivers get (1 - this), remainder goes to pool.
     pub lp_fee_fraction: Decimal,
+    // quote size walked through the book for the impact bid/ask
+    pub impact_notional: Decimal,
+    // minimum gap between premium samples
+    pub premium_sample_interval_ms: i64,
+    // settlements kept in FundingState.history, oldest dropped first
+    pub max_history: usize,
 }
 
 impl Default for FundingParams {
@@ -23,18 +33,80 @@ impl Default for FundingParams {
             max_rate: dec!(0.01),
             interest_rate: dec!(0.0001),
             period_hours: dec!(8),
+            schedule_anchor_ms: 0,
             dampening_factor: dec!(0.5),
             lp_fee_fraction: dec!(0.10),
+            impact_notional: dec!(10000),
+            premium_sample_interval_ms: 60_000,
+            max_history: 1000,
         }
     }
 }
 
+impl FundingParams {
+    pub fn period_ms(&self) -> i64 {
+        (self.period_hours * dec!(3600000)).to_i64().unwrap_or(8 * 3600000)
+    }
+
+    // 5.9: first period boundary strictly after `t`
+    pub fn next_boundary(&self, t: Timestamp) -> Timestamp {
+        let period_ms = self.period_ms();
+        let periods = (t.as_millis() - self.schedule_anchor_ms).div_euclid(period_ms) + 1;
+        Timestamp::from_millis(self.schedule_anchor_ms + periods * period_ms)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FundingState {
     pub current_rate: Decimal,
-    pub cumulative_funding: Decimal,
-    pub last_update: Timestamp,
-    pub twap_premium: Decimal,
+    pub cumulative_funding: Decimal, // quote paid per unit of long since launch
+    pub last_update: Timestamp, // end of the last settled period
+    pub twap_premium: Decimal, // premium the last settlement was priced on
+    // 5.6: running time weighted premium for the open period. each sample holds until the next
+    pub window_start: Timestamp,
+    pub premium_accumulator: Decimal, // sum of premium x ms held
+    pub premium_window_ms: i64,
+    pub last_premium: Decimal,
+    pub last_mark: Option<Price>,
+    pub last_index: Option<Price>,
+    pub last_sample: Option<Timestamp>,
+    // periods that ended but haven't been settled yet, oldest first
+    pub closed_windows: VecDeque<FundingWindow>,
+    pub history: VecDeque<FundingRecord>,
+}
+
+// 5.9: a finished period's samples, what its settlement is priced on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FundingWindow {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub premium_twap: Decimal,
+    pub mark_price: Price, // as last sampled before the boundary
+    pub index_price: Price,
+}
+
+// 5.7: one settlement, as it was priced and what it moved
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FundingRecord {
+    pub timestamp: Timestamp, // the period boundary
+    pub rate: Decimal, // prorated when the period was only partly sampled
+    pub premium: Decimal,
+    pub mark_price: Price,
+    pub index_price: Price,
+    pub open_interest_long: Decimal,
+    pub open_interest_short: Decimal,
+    pub total_paid: Quote, // gross from the paying side
+    pub lp_fee: Quote,
+}
+
+// 5.8: what the next settlement pays if the window's premium holds until then
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PredictedFunding {
+    pub rate: Decimal,
+    pub annualized_rate: Decimal,
+    pub premium_twap: Decimal,
+    pub next_funding: Timestamp,
+    pub time_to_next_ms: i64,
 }
 
 impl FundingState {
@@ -44,7 +116,107 @@ impl FundingState {
             cumulative_funding: Decimal::ZERO,
             last_update: timestamp,
             twap_premium: Decimal::ZERO,
+            window_start: timestamp,
+            premium_accumulator: Decimal::ZERO,
+            premium_window_ms: 0,
+            last_premium: Decimal::ZERO,
+            last_mark: None,
+            last_index: None,
+            last_sample: None,
+            closed_windows: VecDeque::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn record_settlement(&mut self, record: FundingRecord, max_history: usize) {
+        self.history.push_back(record);
+        while self.history.len() > max_history {
+            self.history.pop_front();
+        }
+    }
+
+    // most recent first
+    pub fn recent_history(&self, limit: usize) -> Vec<FundingRecord> {
+        self.history.iter().rev().take(limit).cloned().collect()
+    }
+
+    pub fn predict(&self, now: Timestamp, params: &FundingParams) -> PredictedFunding {
+        let mut state = self.clone();
+        state.roll_windows(now, params);
+        let premium_twap = state.premium_twap(now);
+        let rate = calculate_funding_rate(premium_twap, params);
+        let next_funding = params.next_boundary(now);
+        PredictedFunding {
+            rate,
+            annualized_rate: annualized_funding_rate(rate, params.period_hours),
+            premium_twap,
+            next_funding,
+            time_to_next_ms: next_funding.as_millis() - now.as_millis(),
+        }
+    }
+
+    // close out the previous sample's hold and start holding this one
+    pub fn record_premium(&mut self, now: Timestamp, premium: Decimal, mark: Price, index: Price, params: &FundingParams) {
+        self.roll_windows(now, params);
+        match self.last_sample {
+            Some(last) => self.hold_last(now.as_millis() - last.as_millis()),
+            None => self.window_start = now, // nothing to price before the first sample
+        }
+        self.last_premium = premium;
+        self.last_mark = Some(mark);
+        self.last_index = Some(index);
+        self.last_sample = Some(now);
+    }
+
+    // close every period that ended by `now`, the last sample held up to each boundary
+    pub fn roll_windows(&mut self, now: Timestamp, params: &FundingParams) {
+        let (Some(mut last), Some(mark_price), Some(index_price)) = (self.last_sample, self.last_mark, self.last_index)
+        else {
+            return;
+        };
+        loop {
+            let end = params.next_boundary(self.window_start);
+            if end > now {
+                break;
+            }
+            self.hold_last(end.as_millis() - last.as_millis());
+            let premium_twap = if self.premium_window_ms == 0 {
+                self.last_premium
+            } else {
+                self.premium_accumulator / Decimal::from(self.premium_window_ms)
+            };
+            self.closed_windows.push_back(FundingWindow {
+                start: self.window_start,
+                end,
+                premium_twap,
+                mark_price,
+                index_price,
+            });
+            self.premium_accumulator = Decimal::ZERO;
+            self.premium_window_ms = 0;
+            self.window_start = end;
+            last = end;
+        }
+        self.last_sample = Some(last);
+    }
+
+    fn hold_last(&mut self, held_ms: i64) {
+        let held_ms = held_ms.max(0);
+        self.premium_accumulator += self.last_premium * Decimal::from(held_ms);
+        self.premium_window_ms += held_ms;
+    }
+
+    // time weighted premium of the open period so far, the latest sample held up to `now`
+    pub fn premium_twap(&self, now: Timestamp) -> Decimal {
+        let Some(last) = self.last_sample else {
+            return Decimal::ZERO;
+        };
+        let held_ms = (now.as_millis() - last.as_millis()).max(0);
+        let window_ms = self.premium_window_ms + held_ms;
+        if window_ms == 0 {
+            return self.last_premium;
         }
+        (self.premium_accumulator + self.last_premium * Decimal::from(held_ms)) / Decimal::from(window_ms)
     }
 }
 
@@ -53,6 +225,19 @@ pub fn calculate_premium_index(mark_price: Price, index_price: Price) -> Decimal
     (mark_price.value() - index_price.value()) / index_price.value()
 }
 
+// 5.1.1: premium from what a trade of impact size would actually get. only counts when the
+// impact bid is above index or the impact ask below it, a missing side contributes nothing
+pub fn calculate_impact_premium(
+    impact_bid: Option<Price>,
+    impact_ask: Option<Price>,
+    index_price: Price,
+) -> Decimal {
+    let index = index_price.value();
+    let bid_excess = impact_bid.map_or(Decimal::ZERO, |bid| (bid.value() - index).max(Decimal::ZERO));
+    let ask_shortfall = impact_ask.map_or(Decimal::ZERO, |ask| (index - ask.value()).max(Decimal::ZERO));
+    (bid_excess - ask_shortfall) / index
+}
+
 // 5.2: dampens and clamps the rate to prevent wild swings
 pub fn calculate_funding_rate(premium_index: Decimal, params: &FundingParams) -> Decimal {
     let dampened_premium = premium_index * params.dampening_factor;
@@ -110,6 +295,7 @@ pub fn update_funding_state(
         cumulative_funding: new_cumulative,
         last_update: current_time,
         twap_premium: new_twap,
+        ..state.clone()
     }
 }
 
@@ -121,8 +307,12 @@ pub fn period_to_annual_rate(period_rate: Decimal, periods_per_year: u32) -> Dec
     period_rate * Decimal::from(periods_per_year)
 }
 
-pub fn annualized_funding_rate(period_rate: Decimal) -> Decimal {
-    period_rate * dec!(1095)
+// 365 * 24 / period_hours periods a year, 1095 at the default 8h
+pub fn annualized_funding_rate(period_rate: Decimal, period_hours: Decimal) -> Decimal {
+    if period_hours <= Decimal::ZERO {
+        return Decimal::ZERO;
+    }
+    period_rate * dec!(8760) / period_hours
 }
 
 #[cfg(test)]
@@ -235,10 +425,13 @@ mod tests {
     fn annualized_rate() {
         let period_rate = dec!(0.001); // 0.1% per 8h
 
-        let annual = annualized_funding_rate(period_rate);
+        let annual = annualized_funding_rate(period_rate, dec!(8));
 
         // 0.001 * 3 * 365 = 1.095 = 109.5% APR
         assert_eq!(annual, dec!(1.095));
+
+        // hourly periods pay 24 times a day
+        assert_eq!(annualized_funding_rate(period_rate, dec!(1)), dec!(8.76));
     }
 
     #[test]
@@ -258,4 +451,75 @@ mod tests {
         // Cumulative should have increased
         assert!(new_state.cumulative_funding > Decimal::ZERO);
     }
+
+    #[test]
+    fn impact_premium_only_counts_crossed_sides() {
+        let index = Price::new_unchecked(dec!(50000));
+        let bid = |p| Some(Price::new_unchecked(p));
+
+        // book straddles index: no premium
+        assert_eq!(calculate_impact_premium(bid(dec!(49990)), bid(dec!(50010)), index), Decimal::ZERO);
+        assert_eq!(calculate_impact_premium(bid(dec!(50100)), bid(dec!(50200)), index), dec!(0.002));
+        assert_eq!(calculate_impact_premium(None, bid(dec!(49900)), index), dec!(-0.002));
+    }
+
+    #[test]
+    fn premium_windows_close_on_period_boundaries() {
+        let params = test_params();
+        let hour = 3_600_000;
+        let price = Price::new_unchecked(dec!(50000));
+        let mut state = FundingState::new(Timestamp::from_millis(0));
+        state.record_premium(Timestamp::from_millis(0), dec!(0.001), price, price, &params);
+        state.record_premium(Timestamp::from_millis(6 * hour), dec!(0.004), price, price, &params);
+        assert_eq!(state.premium_twap(Timestamp::from_millis(8 * hour)), dec!(0.00175));
+
+        // no samples for two periods: each closes holding the last one
+        state.roll_windows(Timestamp::from_millis(25 * hour), &params);
+        let windows: Vec<_> = state.closed_windows.iter().map(|w| (w.end.as_millis() / hour, w.premium_twap)).collect();
+        assert_eq!(windows, vec![(8, dec!(0.00175)), (16, dec!(0.004)), (24, dec!(0.004))]);
+        assert_eq!(state.premium_twap(Timestamp::from_millis(25 * hour)), dec!(0.004));
+    }
+
+    #[test]
+    fn schedule_boundaries_follow_anchor() {
+        let mut params = test_params();
+        let hour = 3_600_000;
+        assert_eq!(params.next_boundary(Timestamp::from_millis(0)).as_millis(), 8 * hour);
+        assert_eq!(params.next_boundary(Timestamp::from_millis(8 * hour - 1)).as_millis(), 8 * hour);
+
+        params.schedule_anchor_ms = hour;
+        assert_eq!(params.next_boundary(Timestamp::from_millis(0)).as_millis(), hour);
+        assert_eq!(params.next_boundary(Timestamp::from_millis(hour)).as_millis(), 9 * hour);
+    }
+
+    #[test]
+    fn prediction_uses_running_twap_and_history_is_capped() {
+        let params = test_params();
+        let mut state = FundingState::new(Timestamp::from_millis(0));
+        let price = Price::new_unchecked(dec!(50000));
+        state.record_premium(Timestamp::from_millis(0), dec!(0.002), price, price, &params);
+
+        let predicted = state.predict(Timestamp::from_millis(3_600_000), &params);
+        assert_eq!(predicted.rate, dec!(0.0011));
+        assert_eq!(predicted.annualized_rate, dec!(1.2045));
+        assert_eq!(predicted.time_to_next_ms, 7 * 3_600_000);
+
+        for i in 0..3 {
+            let record = FundingRecord {
+                timestamp: Timestamp::from_millis(i),
+                rate: Decimal::from(i),
+                premium: Decimal::ZERO,
+                mark_price: Price::new_unchecked(dec!(50000)),
+                index_price: Price::new_unchecked(dec!(50000)),
+                open_interest_long: Decimal::ZERO,
+                open_interest_short: Decimal::ZERO,
+                total_paid: Quote::zero(),
+                lp_fee: Quote::zero(),
+            };
+            state.record_settlement(record, 2);
+        }
+        let recent = state.recent_history(10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].rate, dec!(2));
+    }
 }
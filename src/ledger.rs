@@ -0,0 +1,221 @@
+// 14.0: double-entry ledger. every movement of quote funds between accounts, the treasury,
+// the insurance fund and LP pools is posted as one entry with a source and a destination,
+// so entries always sum to zero and each holder's ledger balance can be checked against state.
+// 14.1: settlement clearing. realized PnL and funding flow through a per-market clearing
+// account. its balance is what traders with open positions are owed by (or owe to) each other.
+
+use crate::types::{AccountId, MarketId, Quote, Timestamp};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LedgerAccount {
+    External,               // outside the exchange: deposits in, withdrawals out
+    Trader(AccountId),      // balance plus isolated position collateral
+    Treasury,               // net trading fees
+    InsuranceFund,          // global, takes market funds' overflow
+    MarketInsurance(MarketId),
+    LpPool(MarketId),       // funding fees accrued to the pool
+    Settlement(MarketId),   // PnL and funding clearing between traders
+    SocializedLoss,         // debt no one could cover
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    Deposit,
+    Withdrawal,
+    Transfer,
+    CollateralConversion,
+    TradingFee,
+    MakerRebate,
+    ReferralReward,
+    RealizedPnl,
+    Funding,
+    FundingLpFee,
+    LiquidationPenalty,
+    LiquidatorReward,
+    InsuranceCover,
+    DebtSocialized,
+    ProfitHaircut,
+    InsuranceDeposit,
+    InsuranceOverflow,
+    InsuranceStake,
+    InsuranceUnstake,
+    PoolDeposit,
+    BackstopDeposit,
+    BackstopWithdrawal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub id: u64,
+    pub timestamp: Timestamp,
+    pub from: LedgerAccount,
+    pub to: LedgerAccount,
+    pub amount: Quote, // always positive
+    pub kind: EntryKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    entries: Vec<LedgerEntry>,
+    balances: HashMap<LedgerAccount, Decimal>,
+    next_id: u64,
+    max_entries: usize, // history is trimmed like the event log, balances are not
+}
+
+impl Ledger {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            balances: HashMap::new(),
+            next_id: 1,
+            max_entries,
+        }
+    }
+
+    // 14.2: move `amount` from one holder to another. a negative amount flows the other way,
+    // zero posts nothing
+    pub fn post(
+        &mut self,
+        from: LedgerAccount,
+        to: LedgerAccount,
+        amount: Quote,
+        kind: EntryKind,
+        timestamp: Timestamp,
+    ) {
+        let (from, to, amount) = if amount.value().is_sign_negative() {
+            (to, from, Quote::new(-amount.value()))
+        } else {
+            (from, to, amount)
+        };
+        if amount.value().is_zero() || from == to {
+            return;
+        }
+
+        *self.balances.entry(from).or_insert(Decimal::ZERO) -= amount.value();
+        *self.balances.entry(to).or_insert(Decimal::ZERO) += amount.value();
+
+        self.entries.push(LedgerEntry {
+            id: self.next_id,
+            timestamp,
+            from,
+            to,
+            amount,
+            kind,
+        });
+        self.next_id += 1;
+
+        if self.entries.len() > self.max_entries {
+            let drain_count = self.entries.len() - self.max_entries;
+            self.entries.drain(0..drain_count);
+        }
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> Quote {
+        Quote::new(self.balances.get(&account).copied().unwrap_or(Decimal::ZERO))
+    }
+
+    pub fn balances(&self) -> impl Iterator<Item = (LedgerAccount, Quote)> + '_ {
+        self.balances.iter().map(|(account, balance)| (*account, Quote::new(*balance)))
+    }
+
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    // every entry debits and credits the same amount, so this only fails on a bug in post
+    pub fn is_balanced(&self) -> bool {
+        self.balances.values().sum::<Decimal>().is_zero()
+    }
+}
+
+// ledger balance that disagrees with what the holder actually has
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerMismatch {
+    pub account: LedgerAccount,
+    pub ledger: Quote,
+    pub actual: Quote,
+}
+
+// 14.3: where every unit deposited sits right now
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConservationReport {
+    pub net_deposits: Quote,    // deposited - withdrawn
+    pub trader_funds: Quote,    // balances + position collateral
+    pub treasury: Quote,
+    pub insurance_fund: Quote,
+    pub lp_pool_fees: Quote,
+    pub settlement: Quote,      // open PnL and funding owed between traders
+    pub socialized_loss: Quote, // negative: debt written off with no one to cover it
+    pub mismatches: Vec<LedgerMismatch>,
+}
+
+impl ConservationReport {
+    pub fn total_held(&self) -> Quote {
+        Quote::new(
+            self.trader_funds.value()
+                + self.treasury.value()
+                + self.insurance_fund.value()
+                + self.lp_pool_fees.value()
+                + self.settlement.value()
+                + self.socialized_loss.value(),
+        )
+    }
+
+    // nothing moved outside the ledger and nothing was created or destroyed
+    pub fn is_conserved(&self) -> bool {
+        self.mismatches.is_empty() && self.total_held() == self.net_deposits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn post_moves_funds_and_stays_balanced() {
+        let mut ledger = Ledger::new(100);
+        let trader = LedgerAccount::Trader(AccountId(1));
+        let now = Timestamp::from_millis(0);
+
+        ledger.post(LedgerAccount::External, trader, Quote::new(dec!(1000)), EntryKind::Deposit, now);
+        ledger.post(trader, LedgerAccount::Treasury, Quote::new(dec!(5)), EntryKind::TradingFee, now);
+        // negative amount flows the other way: a realized loss goes to settlement
+        ledger.post(
+            LedgerAccount::Settlement(MarketId(1)),
+            trader,
+            Quote::new(dec!(-100)),
+            EntryKind::RealizedPnl,
+            now,
+        );
+        ledger.post(trader, LedgerAccount::Treasury, Quote::zero(), EntryKind::TradingFee, now);
+
+        assert_eq!(ledger.entries().len(), 3);
+        assert_eq!(ledger.entries()[2].from, trader);
+        assert_eq!(ledger.balance(trader).value(), dec!(895));
+        assert_eq!(ledger.balance(LedgerAccount::External).value(), dec!(-1000));
+        assert_eq!(ledger.balance(LedgerAccount::Settlement(MarketId(1))).value(), dec!(100));
+        assert!(ledger.is_balanced());
+    }
+
+    #[test]
+    fn history_trimmed_balances_kept() {
+        let mut ledger = Ledger::new(2);
+        let trader = LedgerAccount::Trader(AccountId(1));
+        for _ in 0..5 {
+            ledger.post(
+                LedgerAccount::External,
+                trader,
+                Quote::new(dec!(10)),
+                EntryKind::Deposit,
+                Timestamp::from_millis(0),
+            );
+        }
+        assert_eq!(ledger.entries().len(), 2);
+        assert_eq!(ledger.entries()[1].id, 5);
+        assert_eq!(ledger.balance(trader).value(), dec!(50));
+    }
+}
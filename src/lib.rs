@@ -7,6 +7,7 @@
 //   2.x  order.rs: CLOB order book and matching engine
 //   2.1x conditional.rs: stop loss, take profit, trailing stops, OCO
 //   3.x  margin.rs: IM/MM calculation, leverage tiers
+//   3.5  portfolio.rs: scenario-based portfolio margin
 //   4.x  position.rs: position struct, PnL, increase/reduce/flip
 //   5.x  funding.rs: 8-hour funding cycle, premium index
 //   6.x  liquidation.rs: liquidation detection, penalty, insurance
@@ -22,18 +23,25 @@
 //   11.x events.rs: state transition events for audit
 //   12.x market.rs: market config + runtime state
 //   13.x mark_price.rs: blended mark price derivation
+//   14.x ledger.rs: double-entry fund ledger, treasury, conservation check
+//   15.x referral.rs: referral codes, tiered rebates and referee discounts
+//   16.x backstop.rs: backstop vault shares and unwind schedule
 
 // core trading modules
 pub mod account;
+pub mod backstop;
 pub mod engine;
 pub mod events;
 pub mod funding;
+pub mod ledger;
 pub mod liquidation;
 pub mod margin;
 pub mod mark_price;
 pub mod market;
 pub mod order;
+pub mod portfolio;
 pub mod position;
+pub mod referral;
 pub mod types;
 
 // risk and safety modules
@@ -52,20 +60,24 @@ pub mod settlement;
 // re exports for convenience
 pub use account::*;
 pub use adl::*;
+pub use backstop::*;
 pub use conditional::*;
 pub use engine::*;
 pub use events::*;
 pub use funding::*;
+pub use ledger::*;
 pub use liquidation::*;
 pub use margin::*;
 pub use mark_price::*;
 pub use market::*;
 pub use order::*;
+pub use portfolio::*;
 pub use position::*;
+pub use referral::*;
 pub use risk::*;
 pub use types::*;
 pub use api::{EngineCommand, EngineQuery, ApiResponse, ApiError, ErrorCode};
-pub use config::{IntegrationConfig, MarketConfig as IntegrationMarketConfig, FeeConfig, Environment};
+pub use config::{IntegrationConfig, MarketConfig as IntegrationMarketConfig, FeeConfig, FeeOverride, FeeTier, Environment};
 pub use custody::{CustodyManager, DepositRequest, WithdrawalRequest, CollateralType};
 pub use liquidity::{LiquidityProvider, SharedPool, LiquidityRouter, LiquidityQuote};
 pub use price_feed::{PriceUpdate, PriceAggregator, TwapCalculator};
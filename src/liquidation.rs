@@ -1,17 +1,32 @@
 // 6.0 \u2014 liquidation. when equity < MM, position gets force-closed.\n// 6.1 has insurance fund and bad debt handling below.
 
-use crate::types::{Leverage, Price, Quote, Side, SignedSize};
-use crate::margin::MarginRequirement;
-use rust_decimal::prelude::Signed;
+use crate::types::{AccountId, Leverage, MarketId, Price, Quote, Side, SignedSize, Timestamp};
+use crate::margin::{MarginParams, MarginRequirement};
+use crate::position::Position;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LiquidationParams {
     pub penalty_rate: Decimal,
     pub liquidator_share: Decimal,
-    pub max_liquidation_size: Quote,
+    pub max_liquidation_size: Quote, // notional closed per step
+    // margin a partial liquidation restores the rest of the position to
+    pub restore_to: LiquidationTarget,
+    // equity / MM needed to clear a margin call. above the 1.2 at-risk band (6.4) so a
+    // price hovering at the edge doesn't raise a fresh call on every tick
+    pub margin_call_clear_ratio: Decimal,
+    // what happens to bad debt the insurance fund can't cover
+    pub bad_debt_mode: BadDebtMode,
+    // 6.1: the market's own insurance fund keeps this much, penalties above it go global
+    pub insurance_target: Quote,
+    // 6.6: positions above this notional are unwound a slice per interval
+    pub unwind_threshold: Quote,
+    pub unwind_interval_ms: i64,
+    pub unwind_depth_fraction: Decimal, // of the other side's depth a slice may take
+    pub unwind_depth_band: Decimal,     // depth counted this far from mark, 0.01 = 1%
 }
 
 impl Default for LiquidationParams {
@@ -20,10 +35,41 @@ impl Default for LiquidationParams {
             penalty_rate: dec!(0.01),
             liquidator_share: dec!(0.5),
             max_liquidation_size: Quote::new(dec!(1_000_000)),
+            restore_to: LiquidationTarget::Initial,
+            margin_call_clear_ratio: dec!(1.5),
+            bad_debt_mode: BadDebtMode::AutoDeleverage,
+            insurance_target: Quote::new(dec!(1_000_000)),
+            unwind_threshold: Quote::new(dec!(5_000_000)),
+            unwind_interval_ms: 30_000,
+            unwind_depth_fraction: dec!(0.25),
+            unwind_depth_band: dec!(0.01),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LiquidationTarget {
+    Maintenance,
+    Initial, // leaves headroom so the next tick doesn't liquidate again
+}
+
+impl LiquidationTarget {
+    pub fn requirement(&self, margin_req: &MarginRequirement) -> Quote {
+        match self {
+            LiquidationTarget::Maintenance => margin_req.maintenance,
+            LiquidationTarget::Initial => margin_req.initial,
+        }
+    }
+}
+
+// 6.2: AutoDeleverage force-closes the most profitable opposite positions. Socialize haircuts
+// every opposite account's profit pro rata and leaves the positions open
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BadDebtMode {
+    AutoDeleverage,
+    Socialize,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum LiquidationStatus {
     Safe {
@@ -102,6 +148,16 @@ pub fn liquidation_price_from_margin(
     }
 }
 
+// liquidation price of a live position from its own collateral and leverage.
+// None for portfolio-margined positions, which hold no collateral of their own
+pub fn position_liquidation_price(position: &Position, params: &MarginParams) -> Option<Price> {
+    if position.collateral.value().is_zero() {
+        return None;
+    }
+    let mmf = position.leverage.initial_margin_fraction() * params.maintenance_margin_ratio;
+    liquidation_price_from_margin(position.size, position.entry_price, position.collateral, mmf)
+}
+
 // 6.4: checks if position is safe, at risk, liquidatable, or bankrupt
 pub fn evaluate_liquidation(
     equity: Quote,
@@ -179,35 +235,153 @@ pub fn calculate_liquidation_penalty(
     }
 }
 
+impl LiquidationPenalty {
+    // never more than the equity left to pay it. a bankrupt position pays no penalty, so
+    // neither the liquidator nor the fund is paid out of bad debt
+    pub fn capped(&self, available: Quote, params: &LiquidationParams) -> Self {
+        if available.value() >= self.total.value() {
+            return self.clone();
+        }
+        let total = Quote::new(available.value().max(Decimal::ZERO));
+        let liquidator_reward = Quote::new(total.value() * params.liquidator_share);
+        Self {
+            total,
+            liquidator_reward,
+            insurance_contribution: total.sub(liquidator_reward),
+        }
+    }
+}
+
+// 6.5: units to close so the rest meets `target_fraction` of its notional. the closed part's
+// equity stays behind as collateral and pays its penalty:
+//   equity - q * price * penalty_rate >= (size - q) * price * target_fraction
+// capped at max_liquidation_size notional per step. the whole position if that can't work
 pub fn calculate_liquidation_amount(
     position_size: SignedSize,
-    position_value: Quote,
+    mark_price: Price,
     equity: Quote,
-    maintenance_margin: Quote,
-    initial_margin: Quote,
-    max_liquidation: Quote,
-) -> SignedSize {
-    let target_margin_ratio = initial_margin.value() / position_value.value();
-    let current_margin_ratio = equity.value() / position_value.value();
+    target_fraction: Decimal,
+    params: &LiquidationParams,
+) -> Decimal {
+    let size = position_size.abs();
+    let price = mark_price.value();
+    let required = size * price * target_fraction;
+    if equity.value() >= required {
+        return Decimal::ZERO;
+    }
+    if equity.value() <= Decimal::ZERO || target_fraction <= params.penalty_rate {
+        return size;
+    }
+
+    let needed = (required - equity.value()) / (price * (target_fraction - params.penalty_rate));
+    let step_cap = params.max_liquidation_size.value() / price;
+    needed.min(step_cap).min(size)
+}
+
+// 6.6: a liquidation too big to hit the book at once. the engine holds the position and
+// works it out a slice per interval, re-checking margin before each one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiquidationUnwind {
+    pub initial_size: SignedSize,
+    pub closed: Decimal,
+    pub slices: u32,
+    pub started_at: Timestamp,
+    pub last_slice: Option<Timestamp>,
+}
+
+impl LiquidationUnwind {
+    pub fn new(initial_size: SignedSize, timestamp: Timestamp) -> Self {
+        Self {
+            initial_size,
+            closed: Decimal::ZERO,
+            slices: 0,
+            started_at: timestamp,
+            last_slice: None,
+        }
+    }
+
+    pub fn slice_due(&self, now: Timestamp, params: &LiquidationParams) -> bool {
+        self.last_slice
+            .is_none_or(|last| now.as_millis() - last.as_millis() >= params.unwind_interval_ms)
+    }
 
-    if current_margin_ratio >= target_margin_ratio {
-        return SignedSize::zero();
+    pub fn record_slice(&mut self, size: Decimal, now: Timestamp) {
+        self.closed += size;
+        self.slices += 1;
+        self.last_slice = Some(now);
     }
 
-    if equity.value() < maintenance_margin.value() {
-        let max_size = max_liquidation.value() / (position_value.value() / position_size.abs());
-        let liq_size = position_size.abs().min(max_size);
-        return SignedSize::new(-position_size.value().signum() * liq_size);
+    // nothing to slice into this time, the next try waits out the interval
+    pub fn defer(&mut self, now: Timestamp) {
+        self.last_slice = Some(now);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnwindEndReason {
+    Closed,   // the last slice went through
+    Restored, // back above maintenance, the rest stays with the account
+    Bankrupt, // closed in one go through the usual backstop
+}
+
+// flows kept per fund, oldest dropped first
+pub const INSURANCE_HISTORY: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InsuranceFlowKind {
+    Deposit,  // funded from outside
+    Penalty,  // liquidation penalty share
+    Overflow, // above a market fund's target, market -> global
+    Payout,   // bad debt covered
+    Stake,
+    Unstake,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsuranceParams {
+    pub unstake_cooldown_ms: i64,       // between an unstake request and the withdrawal
+    pub unstake_window_ms: i64,         // after the cooldown to withdraw before the request lapses
+    pub staker_penalty_share: Decimal,  // of penalties' insurance cut, to the global fund while staked
+}
+
+impl Default for InsuranceParams {
+    fn default() -> Self {
+        Self {
+            unstake_cooldown_ms: 7 * 24 * 60 * 60 * 1000,
+            unstake_window_ms: 24 * 60 * 60 * 1000,
+            staker_penalty_share: dec!(0.2),
+        }
     }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnstakeRequest {
+    pub shares: Decimal,
+    pub available_at: Timestamp,
+    pub expires_at: Timestamp,
+}
 
-    SignedSize::zero()
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsuranceFlow {
+    pub timestamp: Timestamp,
+    pub kind: InsuranceFlowKind,
+    pub market_id: Option<MarketId>, // where the debt or overflow came from, for the global fund
+    pub amount: Quote,               // negative when leaving the fund
+    pub balance: Quote,              // after the flow
 }
 
+// 6.1: one per market plus a global fund. the global fund collects what market funds hold
+// above target and only pays out once the market's own fund is empty
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InsuranceFund {
     pub balance: Quote,
     pub total_deposits: Quote,
     pub total_payouts: Quote,
+    pub history: VecDeque<InsuranceFlow>,
+    // 6.1.1: shares of the balance. whatever stakers don't hold is the operator's
+    total_shares: Decimal,
+    stakes: HashMap<AccountId, Decimal>,
+    unstake_requests: HashMap<AccountId, UnstakeRequest>,
 }
 
 impl InsuranceFund {
@@ -216,7 +390,38 @@ impl InsuranceFund {
             balance: initial_balance,
             total_deposits: initial_balance,
             total_payouts: Quote::zero(),
+            history: VecDeque::new(),
+            total_shares: Decimal::ZERO,
+            stakes: HashMap::new(),
+            unstake_requests: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, kind: InsuranceFlowKind, market_id: Option<MarketId>, amount: Quote, timestamp: Timestamp) {
+        if amount.value().is_zero() {
+            return;
         }
+        self.history.push_back(InsuranceFlow {
+            timestamp,
+            kind,
+            market_id,
+            amount,
+            balance: self.balance,
+        });
+        while self.history.len() > INSURANCE_HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn recent_history(&self, limit: usize) -> Vec<InsuranceFlow> {
+        self.history.iter().rev().take(limit).cloned().collect()
+    }
+
+    // takes whatever sits above `target` out of the fund
+    pub fn take_overflow(&mut self, target: Quote) -> Quote {
+        let overflow = Quote::new((self.balance.value() - target.value()).max(Decimal::ZERO));
+        self.balance = self.balance.sub(overflow);
+        overflow
     }
 
     pub fn deposit(&mut self, amount: Quote) {
@@ -238,6 +443,97 @@ impl InsuranceFund {
     pub fn can_cover(&self, amount: Quote) -> bool {
         self.balance.value() >= amount.value()
     }
+
+    pub fn shares_of(&self, staker: AccountId) -> Decimal {
+        self.stakes.get(&staker).copied().unwrap_or(Decimal::ZERO)
+    }
+
+    pub fn staked_shares(&self) -> Decimal {
+        self.stakes.values().sum()
+    }
+
+    pub fn share_value(&self, shares: Decimal) -> Quote {
+        if self.total_shares.is_zero() {
+            return Quote::zero();
+        }
+        Quote::new(shares * self.balance.value() / self.total_shares)
+    }
+
+    pub fn pending_unstake(&self, staker: AccountId) -> Option<&UnstakeRequest> {
+        self.unstake_requests.get(&staker)
+    }
+
+    // 6.1.1: operator capital, shares nobody can redeem so stakers don't buy into it for free
+    pub fn fund(&mut self, amount: Quote) {
+        let shares = self.shares_for(amount);
+        self.total_shares += shares;
+        self.deposit(amount);
+    }
+
+    // shares for a stake at the current share value
+    pub fn stake(&mut self, staker: AccountId, amount: Quote) -> Decimal {
+        let shares = self.shares_for(amount);
+        *self.stakes.entry(staker).or_insert(Decimal::ZERO) += shares;
+        self.total_shares += shares;
+        self.deposit(amount);
+        shares
+    }
+
+    // 6.1.1: the stakers' cut of penalties, minted to them pro rata at the current share value
+    // so none of it accrues to the operator's shares
+    pub fn credit_stakers(&mut self, amount: Quote) {
+        let staked = self.staked_shares();
+        let shares = self.shares_for(amount);
+        if staked > Decimal::ZERO && !self.stakes.is_empty() {
+            let mut minted = Decimal::ZERO;
+            for held in self.stakes.values_mut() {
+                let cut = shares * *held / staked;
+                *held += cut;
+                minted += cut;
+            }
+            self.total_shares += minted;
+        }
+        self.deposit(amount);
+    }
+
+    pub fn request_unstake(&mut self, staker: AccountId, shares: Decimal, available_at: Timestamp, expires_at: Timestamp) {
+        self.unstake_requests.insert(staker, UnstakeRequest { shares, available_at, expires_at });
+    }
+
+    pub fn cancel_unstake(&mut self, staker: AccountId) -> Option<UnstakeRequest> {
+        self.unstake_requests.remove(&staker)
+    }
+
+    // pays out the staker's requested shares at the current share value
+    pub fn redeem(&mut self, staker: AccountId) -> Option<(Decimal, Quote)> {
+        let request = self.unstake_requests.remove(&staker)?;
+        let amount = self.share_value(request.shares);
+        let held = self.stakes.entry(staker).or_insert(Decimal::ZERO);
+        *held -= request.shares;
+        if held.is_zero() {
+            self.stakes.remove(&staker);
+        }
+        self.total_shares -= request.shares;
+        self.balance = self.balance.sub(amount);
+        Some((request.shares, amount))
+    }
+
+    fn shares_for(&mut self, amount: Quote) -> Decimal {
+        // balance from before anyone held shares is the operator's. a fund paid out to zero
+        // leaves every share worthless, so ownership starts over
+        if self.balance.value().is_zero() {
+            self.total_shares = Decimal::ZERO;
+            self.stakes.clear();
+            self.unstake_requests.clear();
+        }
+        if self.total_shares.is_zero() {
+            self.total_shares = self.balance.value();
+        }
+        if self.total_shares.is_zero() {
+            return amount.value();
+        }
+        amount.value() * self.total_shares / self.balance.value()
+    }
 }
 
 #[cfg(test)]
@@ -359,6 +655,22 @@ mod tests {
         assert_eq!(penalty.insurance_contribution.value(), dec!(250));
     }
 
+    #[test]
+    fn liquidation_amount_restores_target() {
+        let params = LiquidationParams::default();
+        let size = SignedSize::new(dec!(-2));
+        let price = Price::new_unchecked(dec!(50000));
+
+        // 100k notional, 10% target = 10k. 8k equity: close q with 8k - 500q >= (2 - q) * 5000
+        let q = calculate_liquidation_amount(size, price, Quote::new(dec!(8000)), dec!(0.1), &params);
+        assert_eq!(q, dec!(0.4444444444444444444444444444));
+        assert_eq!(calculate_liquidation_amount(size, price, Quote::new(dec!(10000)), dec!(0.1), &params), dec!(0));
+        assert_eq!(calculate_liquidation_amount(size, price, Quote::new(dec!(-1)), dec!(0.1), &params), dec!(2));
+
+        let capped = LiquidationParams { max_liquidation_size: Quote::new(dec!(10000)), ..params };
+        assert_eq!(calculate_liquidation_amount(size, price, Quote::new(dec!(8000)), dec!(0.1), &capped), dec!(0.2));
+    }
+
     #[test]
     fn insurance_fund_operations() {
         let mut fund = InsuranceFund::new(Quote::new(dec!(100000)));
@@ -375,4 +687,61 @@ mod tests {
         assert_eq!(partial.value(), dec!(105000)); // Only what's available
         assert_eq!(fund.balance.value(), dec!(0));
     }
+
+    #[test]
+    fn stakers_share_gains_and_losses_with_the_operator() {
+        let mut fund = InsuranceFund::new(Quote::new(dec!(1000)));
+        let alice = AccountId(1);
+
+        // the operator's 1000 isn't alice's to claim
+        assert_eq!(fund.stake(alice, Quote::new(dec!(1000))), dec!(1000));
+        assert_eq!(fund.share_value(fund.shares_of(alice)).value(), dec!(1000));
+
+        fund.deposit(Quote::new(dec!(500)));
+        assert_eq!(fund.share_value(dec!(1000)).value(), dec!(1250));
+        fund.cover_bad_debt(Quote::new(dec!(1500)));
+        assert_eq!(fund.share_value(dec!(1000)).value(), dec!(500));
+
+        fund.request_unstake(alice, dec!(400), Timestamp::from_millis(0), Timestamp::from_millis(0));
+        assert_eq!(fund.redeem(alice).unwrap().1.value(), dec!(200));
+        assert!(fund.redeem(alice).is_none());
+        assert_eq!(fund.shares_of(alice), dec!(600));
+        assert_eq!(fund.balance.value(), dec!(800));
+
+        // paid out to nothing: old shares are gone and a new stake starts at par
+        fund.cover_bad_debt(Quote::new(dec!(800)));
+        assert_eq!(fund.stake(AccountId(2), Quote::new(dec!(100))), dec!(100));
+        assert_eq!(fund.shares_of(alice), dec!(0));
+    }
+
+    #[test]
+    fn unwind_slices_are_rate_limited() {
+        let params = LiquidationParams::default();
+        let mut unwind = LiquidationUnwind::new(SignedSize::new(dec!(-200)), Timestamp::from_millis(0));
+
+        assert!(unwind.slice_due(Timestamp::from_millis(0), &params));
+        unwind.record_slice(dec!(20), Timestamp::from_millis(0));
+        assert!(!unwind.slice_due(Timestamp::from_millis(29_999), &params));
+        assert!(unwind.slice_due(Timestamp::from_millis(30_000), &params));
+        assert_eq!((unwind.closed, unwind.slices), (dec!(20), 1));
+    }
+
+    #[test]
+    fn insurance_overflow_and_history() {
+        let mut fund = InsuranceFund::new(Quote::zero());
+        let now = Timestamp::from_millis(0);
+
+        fund.deposit(Quote::new(dec!(1500)));
+        fund.record(InsuranceFlowKind::Penalty, None, Quote::new(dec!(1500)), now);
+        let overflow = fund.take_overflow(Quote::new(dec!(1000)));
+        fund.record(InsuranceFlowKind::Overflow, None, Quote::new(-overflow.value()), now);
+        assert_eq!(overflow.value(), dec!(500));
+        assert_eq!(fund.balance.value(), dec!(1000));
+        assert_eq!(fund.take_overflow(Quote::new(dec!(1000))).value(), dec!(0));
+
+        let recent = fund.recent_history(10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].kind, InsuranceFlowKind::Overflow);
+        assert_eq!(recent[0].balance.value(), dec!(1000));
+    }
 }
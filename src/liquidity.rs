@@ -387,8 +387,10 @@ mod tests {
 
     #[test]
     fn test_pool_inactive() {
-        let mut config = PoolConfig::default();
-        config.active = false;
+        let config = PoolConfig {
+            active: false,
+            ..Default::default()
+        };
         let pool = SharedPool::new(config);
 
         let result = pool.get_quote(Side::Long, dec(1), dec(50000));
@@ -231,6 +231,9 @@ fn scenario_5_funding_settlement() {
 
     engine.advance_time(8 * 60 * 60 * 1000);
     let result = engine.settle_funding(MarketId(1)).unwrap();
+    // funding realizes when a position is touched, settle both to show it
+    engine.settle_position_funding(long_trader, MarketId(1)).unwrap();
+    engine.settle_position_funding(short_trader, MarketId(1)).unwrap();
 
     let long_after = engine.get_account(long_trader).unwrap().balance;
     let short_after = engine.get_account(short_trader).unwrap().balance;
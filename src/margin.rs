@@ -132,6 +132,39 @@ pub fn free_margin(account_equity: Quote, margin_used: Quote) -> Quote {
     Quote::new(account_equity.value() - margin_used.value())
 }
 
+// resting orders on one side of the book: total size and total size x price
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RestingSide {
+    pub size: Decimal,
+    pub notional: Decimal,
+}
+
+/** 3.6: margin held against resting orders. worst case of every bid or every ask filling,
+counted only for the size that would grow the position past its current size */
+pub fn calculate_open_order_margin(
+    position_size: SignedSize,
+    bids: RestingSide,
+    asks: RestingSide,
+    leverage: Leverage,
+    params: &MarginParams,
+) -> Quote {
+    let current = position_size.abs();
+    let side_margin = |side: RestingSide, signed: Decimal| {
+        if side.size.is_zero() {
+            return Decimal::ZERO;
+        }
+        let added = ((position_size.value() + signed).abs() - current).max(Decimal::ZERO);
+        let avg_price = Price::new_unchecked(side.notional / side.size);
+        calculate_margin_requirement(SignedSize::new(added), avg_price, leverage, params)
+            .initial
+            .value()
+    };
+
+    let bid_margin = side_margin(bids, bids.size);
+    let ask_margin = side_margin(asks, -asks.size);
+    Quote::new(bid_margin.max(ask_margin))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -235,4 +268,24 @@ mod tests {
         let free = free_margin(equity, margin_used);
         assert_eq!(free.value(), dec!(5000));
     }
+
+    #[test]
+    fn open_order_margin_nets_against_position() {
+        let params = test_params();
+        let lev = Leverage::new(dec!(10)).unwrap();
+        let bids = RestingSide { size: dec!(2), notional: dec!(100000) };
+        let asks = RestingSide { size: dec!(1), notional: dec!(51000) };
+
+        // flat: worst side is 2 BTC of bids at 50k
+        let flat = calculate_open_order_margin(SignedSize::new(Decimal::ZERO), bids, asks, lev, &params);
+        assert_eq!(flat.value(), dec!(10000));
+
+        // short 1: first BTC of bids only closes it, asks add 1 at 51k
+        let short = calculate_open_order_margin(SignedSize::new(dec!(-1)), bids, asks, lev, &params);
+        assert_eq!(short.value(), dec!(5100));
+
+        // short 2: bids flatten it, so only the asks count
+        let hedged = calculate_open_order_margin(SignedSize::new(dec!(-2)), bids, RestingSide::default(), lev, &params);
+        assert_eq!(hedged.value(), dec!(0));
+    }
 }
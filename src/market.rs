@@ -1,28 +1,25 @@
 // 12.0: market config and runtime state. each market has its own order book, funding, and risk params.
 // 12.0 has the config struct. 12.1 has the mutable MarketState below.
 
+use crate::adl::AdlRanking;
 use crate::funding::{FundingParams, FundingState};
-use crate::liquidation::LiquidationParams;
+use crate::liquidation::{InsuranceFund, LiquidationParams};
 use crate::margin::MarginParams;
 use crate::mark_price::MarkPriceParams;
 use crate::order::OrderBook;
-use crate::types::{MarketId, Price, Timestamp};
+use crate::types::{AccountId, MarketId, Price, Quote, Timestamp};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum MarketStatus {
+    #[default]
     Active,
     Paused,
     Closed,
 }
 
-impl Default for MarketStatus {
-    fn default() -> Self {
-        Self::Active
-    }
-}
-
 // 12.0: static market config. immutable after creation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MarketConfig {
@@ -101,9 +98,12 @@ pub struct MarketState {
     pub smoothed_premium: Decimal,
     pub open_interest_long: Decimal,
     pub open_interest_short: Decimal,
+    pub position_holders: HashSet<AccountId>, // accounts with an open position, so per-market scans skip the rest
     pub pool_funding_fees: Decimal, // LP pool accrual
     pub last_trade_price: Option<Price>,
     pub volume_24h: Decimal,
+    pub adl_ranking: AdlRanking, // refreshed with the mark
+    pub insurance_fund: InsuranceFund, // fed by this market's liquidations, see 6.1
     pub last_updated: Timestamp,
 }
 
@@ -122,9 +122,12 @@ impl MarketState {
             smoothed_premium: Decimal::ZERO,
             open_interest_long: Decimal::ZERO,
             open_interest_short: Decimal::ZERO,
+            position_holders: HashSet::new(),
             pool_funding_fees: Decimal::ZERO,
             last_trade_price: None,
             volume_24h: Decimal::ZERO,
+            adl_ranking: AdlRanking::new(timestamp),
+            insurance_fund: InsuranceFund::new(Quote::zero()),
             last_updated: timestamp,
         }
     }
@@ -138,6 +141,15 @@ impl MarketState {
         self.mark_price.or(self.index_price)
     }
 
+    // keep the holder index in step with the account's position after every change to it
+    pub fn track_holder(&mut self, account_id: AccountId, has_position: bool) {
+        if has_position {
+            self.position_holders.insert(account_id);
+        } else {
+            self.position_holders.remove(&account_id);
+        }
+    }
+
     pub fn update_open_interest(&mut self, long_delta: Decimal, short_delta: Decimal) {
         self.open_interest_long += long_delta;
         self.open_interest_short += short_delta;
@@ -8,20 +8,15 @@ use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
 // GTC stays on book, IOC fills or cancels remainder, FOK all-or-nothing, PostOnly rejects if it would take.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum TimeInForce {
+    #[default]
     GTC,
     IOC,
     FOK,
     PostOnly,
 }
 
-impl Default for TimeInForce {
-    fn default() -> Self {
-        Self::GTC
-    }
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OrderType {
     Limit,
@@ -45,6 +40,7 @@ pub struct Order {
 }
 
 impl Order {
+    #[allow(clippy::too_many_arguments)]
     pub fn new_limit(
         id: OrderId,
         account_id: AccountId,
@@ -204,6 +200,46 @@ impl OrderBook {
         }
     }
 
+    // 2.1: average price a `notional` sized order on `side` would fill at: buys walk the asks,
+    // sells walk the bids. None if that side can't absorb it
+    pub fn impact_price(&self, side: Side, notional: Decimal) -> Option<Price> {
+        if notional <= Decimal::ZERO {
+            return None;
+        }
+        match side {
+            Side::Long => walk_for_notional(self.asks.values(), notional),
+            Side::Short => walk_for_notional(self.bids.values().rev(), notional),
+        }
+    }
+
+    // furthest price a taker on `side` reaches filling `size`, the last level if the book runs
+    // out first. None if that side is empty
+    pub fn sweep_price(&self, side: Side, size: Decimal) -> Option<Price> {
+        match side {
+            Side::Long => walk_for_size(self.asks.values(), size),
+            Side::Short => walk_for_size(self.bids.values().rev(), size),
+        }
+    }
+
+    // size a taker on `side` could fill at `limit` or better
+    pub fn depth_to_price(&self, side: Side, limit: Price) -> Decimal {
+        match side {
+            Side::Long => self
+                .asks
+                .iter()
+                .take_while(|(k, _)| k.price.value() <= limit.value())
+                .map(|(_, o)| o.remaining_size)
+                .sum(),
+            Side::Short => self
+                .bids
+                .iter()
+                .rev()
+                .take_while(|(k, _)| k.price.value() >= limit.value())
+                .map(|(_, o)| o.remaining_size)
+                .sum(),
+        }
+    }
+
     // adds a limit order to the book
     pub fn insert(&mut self, order: Order) {
         let price = order.price.expect("limit order must have price");
@@ -331,6 +367,14 @@ impl OrderBook {
         }
     }
 
+    /// Resting orders belonging to one account, both sides
+    pub fn orders_for_account(&self, account_id: AccountId) -> impl Iterator<Item = &Order> {
+        self.bids
+            .values()
+            .chain(self.asks.values())
+            .filter(move |o| o.account_id == account_id)
+    }
+
     /// Total number of orders in the book
     pub fn order_count(&self) -> usize {
         self.bids.len() + self.asks.len()
@@ -342,6 +386,36 @@ impl OrderBook {
     }
 }
 
+// price of the last order `size` reaches in priority order
+fn walk_for_size<'a>(orders: impl Iterator<Item = &'a Order>, size: Decimal) -> Option<Price> {
+    let mut remaining = size;
+    let mut last = None;
+    for order in orders {
+        if remaining <= Decimal::ZERO {
+            break;
+        }
+        last = order.price;
+        remaining -= order.remaining_size;
+    }
+    last
+}
+
+// fill `notional` of quote from orders in priority order, average price if there's enough
+fn walk_for_notional<'a>(orders: impl Iterator<Item = &'a Order>, notional: Decimal) -> Option<Price> {
+    let mut remaining = notional;
+    let mut size = Decimal::ZERO;
+    for order in orders {
+        let price = order.price?.value();
+        let take = remaining.min(order.remaining_size * price);
+        size += take / price;
+        remaining -= take;
+        if remaining.is_zero() {
+            return Some(Price::new_unchecked(notional / size));
+        }
+    }
+    None
+}
+
 /// Result of matching an incoming order against the book
 #[derive(Debug, Clone)]
 pub struct MatchResult {
@@ -389,10 +463,10 @@ pub fn match_order(book: &mut OrderBook, mut order: Order) -> MatchResult {
         // Check if price matches
         let can_match = if is_buy {
             // Buy order matches if bid price >= ask price
-            limit_price.map_or(true, |p| p.value() >= opposing_key.price.value())
+            limit_price.is_none_or(|p| p.value() >= opposing_key.price.value())
         } else {
             // Sell order matches if ask price <= bid price
-            limit_price.map_or(true, |p| p.value() <= opposing_key.price.value())
+            limit_price.is_none_or(|p| p.value() <= opposing_key.price.value())
         };
 
         if !can_match {
@@ -485,6 +559,38 @@ mod tests {
         assert!(book.mid_price().is_none());
     }
 
+    #[test]
+    fn impact_price_walks_levels() {
+        let mut book = OrderBook::new(MarketId(1));
+        book.insert(create_ask(1, dec!(100), dec!(10), 1));
+        book.insert(create_ask(2, dec!(110), dec!(10), 2));
+        book.insert(create_bid(3, dec!(90), dec!(5), 3));
+
+        // 1000 at 100, then 1100 at 110: 2100 buys 20
+        assert_eq!(book.impact_price(Side::Long, dec!(2100)).unwrap().value(), dec!(105));
+        assert_eq!(book.impact_price(Side::Long, dec!(500)).unwrap().value(), dec!(100));
+        assert_eq!(book.impact_price(Side::Short, dec!(450)).unwrap().value(), dec!(90));
+        assert!(book.impact_price(Side::Short, dec!(451)).is_none());
+
+        assert_eq!(book.depth_to_price(Side::Long, Price::new_unchecked(dec!(105))), dec!(10));
+        assert_eq!(book.depth_to_price(Side::Long, Price::new_unchecked(dec!(110))), dec!(20));
+        assert_eq!(book.depth_to_price(Side::Short, Price::new_unchecked(dec!(91))), dec!(0));
+    }
+
+    #[test]
+    fn sweep_price_is_the_last_level_reached() {
+        let mut book = OrderBook::new(MarketId(1));
+        assert!(book.sweep_price(Side::Long, dec!(1)).is_none());
+        book.insert(create_ask(1, dec!(100), dec!(10), 1));
+        book.insert(create_ask(2, dec!(110), dec!(10), 2));
+
+        assert_eq!(book.sweep_price(Side::Long, dec!(10)).unwrap().value(), dec!(100));
+        assert_eq!(book.sweep_price(Side::Long, dec!(10.5)).unwrap().value(), dec!(110));
+        // more than the book holds stops at its last level
+        assert_eq!(book.sweep_price(Side::Long, dec!(50)).unwrap().value(), dec!(110));
+        assert!(book.sweep_price(Side::Short, dec!(1)).is_none());
+    }
+
     #[test]
     fn insert_and_retrieve() {
         let mut book = OrderBook::new(MarketId(1));
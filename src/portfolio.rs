@@ -0,0 +1,248 @@
+// 3.5: portfolio margin. opt-in alternative to per-position leverage tiers for hedged books.
+// each market gets a risk array (loss under each price shock on a grid). the requirement is the
+// sum of each market's worst loss, less credits for offsetting positions in correlated markets.
+
+use crate::margin::{calculate_margin_requirement, MarginParams};
+use crate::types::{Leverage, MarketId, Price, Quote, SignedSize};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioMarginParams {
+    // shock grid as fractions of the scan range. -1 = full move down, 1 = full move up
+    pub price_shocks: Vec<Decimal>,
+    // scan range used for markets without an override (0.08 = 8% move)
+    pub default_scan_range: Decimal,
+    pub market_scan_ranges: HashMap<MarketId, Decimal>,
+    pub correlation_offsets: Vec<CorrelationOffset>,
+    // MM = requirement * this, same convention as MarginParams
+    pub maintenance_margin_ratio: Decimal,
+    // requirement never drops below this fraction of the summed tiered IM
+    pub min_margin_fraction: Decimal,
+}
+
+impl Default for PortfolioMarginParams {
+    fn default() -> Self {
+        Self {
+            price_shocks: vec![
+                dec!(-1),
+                dec!(-0.66),
+                dec!(-0.33),
+                dec!(0),
+                dec!(0.33),
+                dec!(0.66),
+                dec!(1),
+            ],
+            default_scan_range: dec!(0.08),
+            market_scan_ranges: HashMap::new(),
+            correlation_offsets: Vec::new(),
+            maintenance_margin_ratio: dec!(0.5),
+            min_margin_fraction: dec!(0.1),
+        }
+    }
+}
+
+impl PortfolioMarginParams {
+    pub fn scan_range(&self, market_id: MarketId) -> Decimal {
+        self.market_scan_ranges
+            .get(&market_id)
+            .copied()
+            .unwrap_or(self.default_scan_range)
+    }
+}
+
+// fraction of the matched risk of two positively correlated markets that is credited back
+// when the book is long one and short the other. 0.8 = 80% of the hedged leg offsets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorrelationOffset {
+    pub market_a: MarketId,
+    pub market_b: MarketId,
+    pub offset: Decimal,
+}
+
+// one market's exposure as seen by the portfolio calc
+#[derive(Debug, Clone)]
+pub struct PortfolioLeg {
+    pub market_id: MarketId,
+    pub size: SignedSize,
+    pub mark_price: Price,
+    pub tiered_initial: Quote, // IM this leg would need under leverage tiers
+}
+
+impl PortfolioLeg {
+    pub fn new(
+        market_id: MarketId,
+        size: SignedSize,
+        mark_price: Price,
+        leverage: Leverage,
+        margin_params: &MarginParams,
+    ) -> Self {
+        let tiered = calculate_margin_requirement(size, mark_price, leverage, margin_params);
+        Self {
+            market_id,
+            size,
+            mark_price,
+            tiered_initial: tiered.initial,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PortfolioMarginRequirement {
+    pub initial: Quote,
+    pub maintenance: Quote,
+    pub scan_risk: Quote,     // sum of each market's worst loss
+    pub offset_credit: Quote, // taken off scan_risk for hedges
+    pub floor: Quote,         // min_margin_fraction of tiered IM
+}
+
+// 3.5.1: loss of a leg under each shock on the grid. positive = loss
+pub fn risk_array(leg: &PortfolioLeg, scan_range: Decimal, price_shocks: &[Decimal]) -> Vec<Quote> {
+    price_shocks
+        .iter()
+        .map(|shock| {
+            let price_move = leg.mark_price.value() * scan_range * shock;
+            Quote::new(-leg.size.value() * price_move)
+        })
+        .collect()
+}
+
+// 3.5.2: portfolio requirement across all legs
+pub fn calculate_portfolio_margin(
+    legs: &[PortfolioLeg],
+    params: &PortfolioMarginParams,
+) -> PortfolioMarginRequirement {
+    // worst loss per market and the direction of the move that causes it
+    let mut worst: HashMap<MarketId, (Decimal, Decimal)> = HashMap::new();
+    let mut tiered_initial = Decimal::ZERO;
+
+    for leg in legs {
+        tiered_initial += leg.tiered_initial.value();
+        let losses = risk_array(leg, params.scan_range(leg.market_id), &params.price_shocks);
+
+        let mut worst_loss = Decimal::ZERO;
+        let mut worst_shock = Decimal::ZERO;
+        for (loss, shock) in losses.iter().zip(&params.price_shocks) {
+            if loss.value() > worst_loss {
+                worst_loss = loss.value();
+                worst_shock = *shock;
+            }
+        }
+        worst.insert(leg.market_id, (worst_loss, worst_shock));
+    }
+
+    let scan_risk: Decimal = worst.values().map(|(loss, _)| *loss).sum();
+
+    // credits consume the risk they offset so a leg is never counted twice
+    let mut remaining: HashMap<MarketId, Decimal> =
+        worst.iter().map(|(id, (loss, _))| (*id, *loss)).collect();
+    let mut offset_credit = Decimal::ZERO;
+
+    for corr in &params.correlation_offsets {
+        let (Some((_, shock_a)), Some((_, shock_b))) = (worst.get(&corr.market_a), worst.get(&corr.market_b))
+        else {
+            continue;
+        };
+        // only hedges offset: worst case for one leg must be the other's best case
+        if shock_a.is_zero() || shock_b.is_zero() || shock_a.is_sign_negative() == shock_b.is_sign_negative() {
+            continue;
+        }
+
+        let matched = remaining[&corr.market_a].min(remaining[&corr.market_b]);
+        let credit = matched * corr.offset;
+        *remaining.get_mut(&corr.market_a).unwrap() -= credit;
+        *remaining.get_mut(&corr.market_b).unwrap() -= credit;
+        offset_credit += credit * Decimal::TWO;
+    }
+
+    let floor = tiered_initial * params.min_margin_fraction;
+    let initial = (scan_risk - offset_credit).max(floor);
+
+    PortfolioMarginRequirement {
+        initial: Quote::new(initial),
+        maintenance: Quote::new(initial * params.maintenance_margin_ratio),
+        scan_risk: Quote::new(scan_risk),
+        offset_credit: Quote::new(offset_credit),
+        floor: Quote::new(floor),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn leg(market: u32, size: Decimal, price: Decimal) -> PortfolioLeg {
+        PortfolioLeg::new(
+            MarketId(market),
+            SignedSize::new(size),
+            Price::new_unchecked(price),
+            Leverage::new(dec!(10)).unwrap(),
+            &MarginParams::default(),
+        )
+    }
+
+    #[test]
+    fn risk_array_long_loses_on_down_shocks() {
+        let params = PortfolioMarginParams::default();
+        let losses = risk_array(&leg(1, dec!(1), dec!(50000)), dec!(0.08), &params.price_shocks);
+
+        // full down move: 1 * 50000 * 8% = 4000 loss
+        assert_eq!(losses[0].value(), dec!(4000));
+        assert_eq!(losses[3].value(), dec!(0));
+        assert_eq!(losses[6].value(), dec!(-4000));
+    }
+
+    #[test]
+    fn single_leg_requirement_is_worst_loss() {
+        let params = PortfolioMarginParams::default();
+        let req = calculate_portfolio_margin(&[leg(1, dec!(-2), dec!(50000))], &params);
+
+        assert_eq!(req.scan_risk.value(), dec!(8000));
+        assert_eq!(req.offset_credit.value(), dec!(0));
+        assert_eq!(req.initial.value(), dec!(8000));
+        assert_eq!(req.maintenance.value(), dec!(4000));
+    }
+
+    #[test]
+    fn correlated_hedge_gets_offset_credit() {
+        let mut params = PortfolioMarginParams::default();
+        params.correlation_offsets.push(CorrelationOffset {
+            market_a: MarketId(1),
+            market_b: MarketId(2),
+            offset: dec!(0.8),
+        });
+
+        // long 1 BTC, short 16 ETH. both 50k notional
+        let legs = [leg(1, dec!(1), dec!(50000)), leg(2, dec!(-16), dec!(3125))];
+        let req = calculate_portfolio_margin(&legs, &params);
+
+        assert_eq!(req.scan_risk.value(), dec!(8000));
+        assert_eq!(req.offset_credit.value(), dec!(6400));
+        assert_eq!(req.initial.value(), dec!(1600));
+
+        // same direction on both markets is not a hedge
+        let legs = [leg(1, dec!(1), dec!(50000)), leg(2, dec!(16), dec!(3125))];
+        let req = calculate_portfolio_margin(&legs, &params);
+        assert_eq!(req.offset_credit.value(), dec!(0));
+    }
+
+    #[test]
+    fn requirement_floored_at_fraction_of_tiered_margin() {
+        let mut params = PortfolioMarginParams::default();
+        params.correlation_offsets.push(CorrelationOffset {
+            market_a: MarketId(1),
+            market_b: MarketId(2),
+            offset: Decimal::ONE,
+        });
+
+        let legs = [leg(1, dec!(1), dec!(50000)), leg(2, dec!(-16), dec!(3125))];
+        let req = calculate_portfolio_margin(&legs, &params);
+
+        // fully offset, floor is 10% of 2 * 5000 tiered IM
+        assert_eq!(req.floor.value(), dec!(1000));
+        assert_eq!(req.initial.value(), dec!(1000));
+    }
+}
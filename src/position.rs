@@ -169,13 +169,13 @@ pub fn reduce_position(
     let close_size_for_pnl = SignedSize::new(position.size.value().signum() * reduce_amount);
     let realized = calculate_realized_pnl(close_size_for_pnl, position.entry_price, fill_price);
 
-    // Calculate funding for the reduced portion
-    let reduce_fraction = reduce_amount / position_abs_size;
+    // Calculate funding for the reduced portion. multiply before dividing so an exact share
+    // stays exact, a rounded fraction leaves dust that the ledger can't balance
     let position_funding = position.pending_funding(current_funding_index);
-    let funding_for_reduced = Quote::new(position_funding.value() * reduce_fraction);
+    let funding_for_reduced = Quote::new(position_funding.value() * reduce_amount / position_abs_size);
 
     // Collateral returned proportionally
-    let collateral_returned = Quote::new(position.collateral.value() * reduce_fraction);
+    let collateral_returned = Quote::new(position.collateral.value() * reduce_amount / position_abs_size);
 
     // Remaining position size: reduce the absolute size, keeping the sign
     let remaining_abs = position_abs_size - reduce_amount;
@@ -189,9 +189,8 @@ pub fn reduce_position(
         // Fully closed
         return PositionUpdate {
             new_position: None,
-            realized_pnl: Quote::new(
-                realized.value() + position.realized_pnl.value() - funding_for_reduced.value(),
-            ),
+            // earlier partial closes were already paid out, only this slice settles
+            realized_pnl: Quote::new(realized.value() - funding_for_reduced.value()),
             collateral_returned: position.collateral,
             collateral_required: Quote::zero(),
         };
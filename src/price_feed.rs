@@ -193,7 +193,7 @@ impl PriceAggregator {
             return Decimal::ZERO;
         }
 
-        if len % 2 == 0 {
+        if len.is_multiple_of(2) {
             (sorted[len / 2 - 1] + sorted[len / 2]) / Decimal::new(2, 0)
         } else {
             sorted[len / 2]
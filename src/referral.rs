@@ -0,0 +1,201 @@
+// 15.0: referral program. accounts register a code, new accounts sign up under it.
+// the referrer earns a rebate out of the referee's fees, the referee gets a discount, both
+// set by the tier the referrer's total referred volume has reached. 15.1 pays one level up
+// the chain too: the referrer's own referrer takes a smaller upstream share.
+
+use crate::types::{AccountId, Quote, Timestamp};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferralConfig {
+    // rates once referred volume reaches each threshold. below the first, the referrer earns
+    // FeeConfig.referral_fee_pct and the referee gets no discount
+    pub tiers: Vec<ReferralTier>,
+    // share of the referee's fee paid to the referrer's referrer
+    pub upstream_rebate_pct: Decimal,
+    pub min_code_len: usize,
+    pub max_code_len: usize,
+    // payouts kept per referrer, oldest dropped first
+    pub max_history: usize,
+}
+
+impl Default for ReferralConfig {
+    fn default() -> Self {
+        Self {
+            tiers: vec![
+                ReferralTier {
+                    min_referred_volume: dec!(1_000_000),
+                    referrer_rebate_pct: dec!(0.15),
+                    referee_discount_pct: dec!(0.05),
+                },
+                ReferralTier {
+                    min_referred_volume: dec!(10_000_000),
+                    referrer_rebate_pct: dec!(0.20),
+                    referee_discount_pct: dec!(0.10),
+                },
+            ],
+            upstream_rebate_pct: dec!(0.02),
+            min_code_len: 3,
+            max_code_len: 16,
+            max_history: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferralTier {
+    pub min_referred_volume: Decimal,
+    pub referrer_rebate_pct: Decimal, // of the referee's fee, 0.15 = 15%
+    pub referee_discount_pct: Decimal, // off the referee's fee
+}
+
+impl ReferralConfig {
+    // 15.2: (referrer rebate, referee discount) at this referred volume
+    pub fn rates_for(&self, referred_volume: Decimal, base_rebate_pct: Decimal) -> (Decimal, Decimal) {
+        self.tiers
+            .iter()
+            .filter(|tier| referred_volume >= tier.min_referred_volume)
+            .max_by_key(|tier| tier.min_referred_volume)
+            .map_or((base_rebate_pct, Decimal::ZERO), |tier| {
+                (tier.referrer_rebate_pct, tier.referee_discount_pct)
+            })
+    }
+
+    // letters, digits, '-' and '_' only
+    pub fn is_valid_code(&self, code: &str) -> bool {
+        (self.min_code_len..=self.max_code_len).contains(&code.len())
+            && code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+}
+
+// one rebate paid out of a referee's fee
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferralPayout {
+    pub timestamp: Timestamp,
+    pub referee: AccountId,
+    pub level: u8, // 1 = direct referrer, 2 = upstream
+    pub fee: Quote,
+    pub amount: Quote,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferrerStats {
+    pub code: Option<String>,
+    pub referees: Vec<AccountId>,
+    pub referred_volume: Decimal, // lifetime notional traded by direct referees
+    pub total_earned: Quote,
+    pub history: Vec<ReferralPayout>,
+}
+
+impl Default for ReferrerStats {
+    fn default() -> Self {
+        Self {
+            code: None,
+            referees: Vec::new(),
+            referred_volume: Decimal::ZERO,
+            total_earned: Quote::zero(),
+            history: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReferralProgram {
+    codes: HashMap<String, AccountId>, // keyed lowercase
+    stats: HashMap<AccountId, ReferrerStats>,
+}
+
+impl ReferralProgram {
+    pub fn owner_of(&self, code: &str) -> Option<AccountId> {
+        self.codes.get(&code.to_ascii_lowercase()).copied()
+    }
+
+    pub fn stats(&self, referrer: AccountId) -> Option<&ReferrerStats> {
+        self.stats.get(&referrer)
+    }
+
+    pub fn referred_volume(&self, referrer: AccountId) -> Decimal {
+        self.stats.get(&referrer).map_or(Decimal::ZERO, |s| s.referred_volume)
+    }
+
+    // caller checks the code is valid and the owner has none yet
+    pub fn register_code(&mut self, owner: AccountId, code: &str) {
+        self.codes.insert(code.to_ascii_lowercase(), owner);
+        self.stats.entry(owner).or_default().code = Some(code.to_string());
+    }
+
+    pub fn add_referee(&mut self, referrer: AccountId, referee: AccountId) {
+        let stats = self.stats.entry(referrer).or_default();
+        if !stats.referees.contains(&referee) {
+            stats.referees.push(referee);
+        }
+    }
+
+    pub fn remove_referee(&mut self, referrer: AccountId, referee: AccountId) {
+        if let Some(stats) = self.stats.get_mut(&referrer) {
+            stats.referees.retain(|id| *id != referee);
+        }
+    }
+
+    pub fn record_volume(&mut self, referrer: AccountId, notional: Decimal) {
+        self.stats.entry(referrer).or_default().referred_volume += notional;
+    }
+
+    pub fn record_payout(&mut self, referrer: AccountId, payout: ReferralPayout, max_history: usize) {
+        let stats = self.stats.entry(referrer).or_default();
+        stats.total_earned = stats.total_earned.add(payout.amount);
+        stats.history.push(payout);
+        if stats.history.len() > max_history {
+            let drain_count = stats.history.len() - max_history;
+            stats.history.drain(0..drain_count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_follow_referred_volume() {
+        let config = ReferralConfig::default();
+        assert_eq!(config.rates_for(dec!(999_999), dec!(0.10)), (dec!(0.10), dec!(0)));
+        assert_eq!(config.rates_for(dec!(1_000_000), dec!(0.10)), (dec!(0.15), dec!(0.05)));
+        assert_eq!(config.rates_for(dec!(50_000_000), dec!(0.10)), (dec!(0.20), dec!(0.10)));
+    }
+
+    #[test]
+    fn codes_are_case_insensitive_and_validated() {
+        let config = ReferralConfig::default();
+        assert!(config.is_valid_code("alice_01"));
+        assert!(!config.is_valid_code("ab"));
+        assert!(!config.is_valid_code("no spaces"));
+
+        let mut program = ReferralProgram::default();
+        program.register_code(AccountId(7), "Alice");
+        assert_eq!(program.owner_of("ALICE"), Some(AccountId(7)));
+        assert_eq!(program.stats(AccountId(7)).unwrap().code.as_deref(), Some("Alice"));
+    }
+
+    #[test]
+    fn payout_history_is_capped() {
+        let mut program = ReferralProgram::default();
+        for i in 0..5 {
+            let payout = ReferralPayout {
+                timestamp: Timestamp::from_millis(i),
+                referee: AccountId(2),
+                level: 1,
+                fee: Quote::new(dec!(10)),
+                amount: Quote::new(dec!(1)),
+            };
+            program.record_payout(AccountId(1), payout, 3);
+        }
+        let stats = program.stats(AccountId(1)).unwrap();
+        assert_eq!(stats.history.len(), 3);
+        assert_eq!(stats.history[0].timestamp, Timestamp::from_millis(2));
+        assert_eq!(stats.total_earned.value(), dec!(5));
+    }
+}
@@ -233,6 +233,70 @@ proptest! {
             result.accounts_affected
         );
     }
+
+    /// Fund conservation. Every quote unit deposited is held by an account, the treasury,
+    /// the insurance fund, an LP pool or settlement clearing, with fees, rebates, referral
+    /// cuts, funding and liquidations all running.
+    #[test]
+    fn funds_conserved_through_fees_funding_and_liquidations(
+        trade_sizes in proptest::collection::vec(1i64..200i64, 2..12),
+        price_path in proptest::collection::vec(-8000i64..8000i64, 1..6),
+        maker_fee_bps in -2i32..3i32,
+    ) {
+        let mut config = EngineConfig::default();
+        config.fees.maker_fee_bps = maker_fee_bps;
+
+        let mut engine = Engine::new(config);
+        engine.add_market(MarketConfig::btc_perp());
+        engine.fund_insurance(Quote::new(dec!(10_000)));
+        engine.update_index_price(MarketId(1), Price::new_unchecked(dec!(50000))).unwrap();
+
+        let maker = engine.create_account();
+        let referrer = engine.create_account();
+        engine.deposit(maker, Quote::new(dec!(10_000_000))).unwrap();
+
+        let mut traders = Vec::new();
+        for (i, &size_raw) in trade_sizes.iter().enumerate() {
+            let trader = engine.create_account();
+            engine.deposit(trader, Quote::new(Decimal::from(1000 + 500 * i as i64))).unwrap();
+            engine.set_referrer(trader, referrer).unwrap();
+
+            let size = Decimal::new(size_raw, 3);
+            let (maker_side, taker_side) = if i % 2 == 0 {
+                (Side::Short, Side::Long)
+            } else {
+                (Side::Long, Side::Short)
+            };
+            let _ = engine.place_limit_order(
+                maker,
+                MarketId(1),
+                maker_side,
+                size,
+                Price::new_unchecked(dec!(50000)),
+                TimeInForce::GTC,
+            );
+            let _ = engine.place_market_order(trader, MarketId(1), taker_side, size);
+            traders.push(trader);
+        }
+
+        for delta in price_path {
+            let price = Price::new_unchecked(dec!(50000) + Decimal::from(delta));
+            engine.update_index_price(MarketId(1), price).unwrap();
+            engine.advance_time(8 * 60 * 60 * 1000);
+            engine.settle_funding(MarketId(1)).unwrap();
+            engine.check_liquidations(MarketId(1)).unwrap();
+        }
+
+        let report = engine.conservation_report();
+        prop_assert!(report.mismatches.is_empty(), "ledger drifted from state: {:?}", report.mismatches);
+        prop_assert!(
+            report.is_conserved(),
+            "funds not conserved: deposits={}, held={}",
+            report.net_deposits,
+            report.total_held()
+        );
+        prop_assert!(engine.ledger().is_balanced());
+    }
 }
 
 /// Non-proptest solvency tests.
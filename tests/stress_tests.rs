@@ -586,6 +586,7 @@ mod funding_tests {
             engine.advance_time(8 * 60 * 60 * 1000);
             engine.settle_funding(MarketId(1)).unwrap();
         }
+        engine.settle_position_funding(long_trader, MarketId(1)).unwrap();
 
         let final_balance = engine.get_account(long_trader).unwrap().balance.value();
 